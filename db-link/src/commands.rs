@@ -1,7 +1,11 @@
 use crate::mem_utils::as_u8_slice;
+use crate::region::RefreshKind;
+
+#[cfg(feature = "std")]
+use thiserror::Error;
 
 pub const SYNC_BYTE: u8 = 0xA1;
-pub const VERSION: u8 = 0x01;
+pub const VERSION: u8 = 0x04;
 pub const HEADER_SIZE: usize = core::mem::size_of::<Header>();
 pub const MAX_PAYLOAD_SIZE: usize = 0xFF;
 pub const MAX_PACKET_SIZE: usize = MAX_PAYLOAD_SIZE + HEADER_SIZE;
@@ -15,6 +19,72 @@ pub enum Command {
     GetParamList,
     Response,
     Error,
+    CreditRequest,
+    Credit,
+    ListPages,
+    ShowPage,
+    SetPlaylist,
+    GetCurrentPage,
+    UpdateRegion,
+    Refresh,
+    CreditAdvertise,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0 => Command::Echo,
+            1 => Command::GetParam,
+            2 => Command::SetParam,
+            3 => Command::GetParamList,
+            4 => Command::Response,
+            5 => Command::Error,
+            6 => Command::CreditRequest,
+            7 => Command::Credit,
+            8 => Command::ListPages,
+            9 => Command::ShowPage,
+            10 => Command::SetPlaylist,
+            11 => Command::GetCurrentPage,
+            12 => Command::UpdateRegion,
+            13 => Command::Refresh,
+            14 => Command::CreditAdvertise,
+            _ => return Err(value),
+        })
+    }
+}
+
+/// Logical stream a packet belongs to, lets control traffic overtake bulk transfers
+/// Declared in priority order, highest first
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum Channel {
+    #[default]
+    Control,
+    Event,
+    Log,
+    Bulk,
+}
+
+pub const CHANNEL_COUNT: usize = 4;
+
+impl Channel {
+    /// All channels, highest priority first
+    pub const ALL: [Channel; CHANNEL_COUNT] = [
+        Channel::Control,
+        Channel::Event,
+        Channel::Log,
+        Channel::Bulk,
+    ];
+}
+
+impl TryFrom<u8> for Channel {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Channel::ALL.get(value as usize).copied().ok_or(value)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -22,6 +92,7 @@ pub enum Command {
 pub struct Header {
     pub sync: u8, //should be SYNC_BYTE
     pub version: u8,
+    pub channel: Channel,
     pub command: Command,
     pub payload_length: u8, // this gives us max payload of 255, which should be easy for
                             // constrained devices to accommodate
@@ -34,7 +105,12 @@ pub struct ResponsePayload {
     pub msg: [u8; MAX_PAYLOAD_SIZE - 1],
 }
 
+/// Max length of the detail string carried by an error, first payload byte is the code
+pub const MAX_ERROR_DETAIL_SIZE: usize = MAX_PAYLOAD_SIZE - 1;
+
 pub type PayloadBuf = heapless::Vec<u8, MAX_PAYLOAD_SIZE>;
+pub type ErrorDetail = heapless::String<MAX_ERROR_DETAIL_SIZE>;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Packet {
     Echo(PayloadBuf), //whole payload is the message, dont need to do anything crazy here
@@ -42,14 +118,149 @@ pub enum Packet {
     GetParam(PayloadBuf),
     SetParam(PayloadBuf),
     Response(PayloadBuf),
-    Error(PayloadBuf),
+    Error(ErrorPayload),
+    /// Asks the receiver to re-advertise its free buffer space, see `flow`
+    CreditRequest,
+    /// Bytes the sender may send on top of what it already has
+    Credit(u16),
+    /// Answered with a page list Response, see `pages`
+    ListPages,
+    /// Switches to a page by id and stops any playlist
+    ShowPage(u8),
+    /// Rotation of pages with per page durations, empty stops the rotation
+    SetPlaylist(PayloadBuf),
+    /// Answered with a one byte Response holding the page id
+    GetCurrentPage,
+    /// Pixels for part of the screen, drawn on the next refresh, see `region`
+    UpdateRegion(PayloadBuf),
+    /// Pushes drawn regions to the panel, the device may upgrade a partial refresh to a full one
+    Refresh(RefreshKind),
+    /// Answer to `CreditRequest`, the receiver's free space replaces whatever credit the sender had
+    CreditAdvertise(u16),
+}
+
+/// Machine readable reason carried in the first byte of an Error payload
+/// Codes the receiver doesn't know about decode to `ErrorCode::Unknown`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "std", derive(Error))]
+#[repr(u8)]
+pub enum ErrorCode {
+    #[cfg_attr(feature = "std", error("unknown error"))]
+    Unknown,
+    #[cfg_attr(feature = "std", error("unknown command"))]
+    UnknownCommand,
+    #[cfg_attr(feature = "std", error("unknown param"))]
+    UnknownParam,
+    #[cfg_attr(feature = "std", error("invalid value"))]
+    InvalidValue,
+    #[cfg_attr(feature = "std", error("device busy"))]
+    Busy,
+    #[cfg_attr(feature = "std", error("out of memory"))]
+    OutOfMemory,
+    #[cfg_attr(feature = "std", error("not supported"))]
+    NotSupported,
+    #[cfg_attr(feature = "std", error("checksum failed"))]
+    ChecksumFailed,
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ErrorCode::UnknownCommand,
+            2 => ErrorCode::UnknownParam,
+            3 => ErrorCode::InvalidValue,
+            4 => ErrorCode::Busy,
+            5 => ErrorCode::OutOfMemory,
+            6 => ErrorCode::NotSupported,
+            7 => ErrorCode::ChecksumFailed,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+/// Payload of an Error packet, encoded as the code byte followed by an optional utf8 detail
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "std", derive(Error))]
+#[cfg_attr(
+    feature = "std",
+    error("{code}{}", .detail.as_ref().map(|d| std::format!(": {d}")).unwrap_or_default())
+)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    pub detail: Option<ErrorDetail>,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode) -> Self {
+        Self { code, detail: None }
+    }
+
+    /// Attach a detail message, truncated to fit in a single payload
+    /// An empty detail is the same as none, that's how it decodes
+    pub fn with_detail(code: ErrorCode, detail: &str) -> Self {
+        let mut end = detail.len().min(MAX_ERROR_DETAIL_SIZE);
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            return Self::new(code);
+        }
+        let mut buf = ErrorDetail::new();
+        //can't fail, we just made sure it fits
+        _ = buf.push_str(&detail[..end]);
+        Self {
+            code,
+            detail: Some(buf),
+        }
+    }
+
+    pub fn encode(&self) -> PayloadBuf {
+        let mut buf = PayloadBuf::new();
+        //first byte always fits, detail is capped at MAX_ERROR_DETAIL_SIZE
+        _ = buf.push(self.code as u8);
+        if let Some(detail) = &self.detail {
+            _ = buf.extend_from_slice(detail.as_bytes());
+        }
+        buf
+    }
+
+    /// Decodes an Error payload, an empty payload is treated as an unknown error
+    /// and invalid utf8 in the detail is dropped
+    pub fn decode(payload: &[u8]) -> Self {
+        let Some((code, detail)) = payload.split_first() else {
+            return Self::new(ErrorCode::Unknown);
+        };
+        let detail = core::str::from_utf8(detail)
+            .ok()
+            .filter(|d| !d.is_empty())
+            .and_then(|d| ErrorDetail::try_from(d).ok());
+        Self {
+            code: (*code).into(),
+            detail,
+        }
+    }
+}
+
+impl From<ErrorCode> for ErrorPayload {
+    fn from(value: ErrorCode) -> Self {
+        Self::new(value)
+    }
+}
+
+/// A packet along with the channel it travels on
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub channel: Channel,
+    pub packet: Packet,
 }
 
 impl Header {
+    /// Header on the default (control) channel
     pub fn new(command: Command, payload_length: u8) -> Header {
         Header {
             sync: SYNC_BYTE,
             version: VERSION,
+            channel: Channel::Control,
             command,
             payload_length,
         }
@@ -65,10 +276,38 @@ impl Header {
                 Header::new(Command::Response, response.len() as u8),
                 Some(response),
             ),
-            Packet::Error(response) => (
-                Header::new(Command::Error, response.len() as u8),
-                Some(response),
+            Packet::Error(error) => {
+                let buf = error.encode();
+                (Header::new(Command::Error, buf.len() as u8), Some(buf))
+            }
+            Packet::CreditRequest => (Header::new(Command::CreditRequest, 0), None),
+            Packet::Credit(credit) => {
+                //2 bytes always fit in a payload
+                let buf = PayloadBuf::from_slice(&credit.to_le_bytes()).unwrap();
+                (Header::new(Command::Credit, buf.len() as u8), Some(buf))
+            }
+            Packet::ListPages => (Header::new(Command::ListPages, 0), None),
+            Packet::ShowPage(page) => {
+                let buf = PayloadBuf::from_slice(&[page]).unwrap();
+                (Header::new(Command::ShowPage, 1), Some(buf))
+            }
+            Packet::SetPlaylist(buf) => (
+                Header::new(Command::SetPlaylist, buf.len() as u8),
+                Some(buf),
+            ),
+            Packet::GetCurrentPage => (Header::new(Command::GetCurrentPage, 0), None),
+            Packet::UpdateRegion(buf) => (
+                Header::new(Command::UpdateRegion, buf.len() as u8),
+                Some(buf),
             ),
+            Packet::Refresh(kind) => {
+                let buf = PayloadBuf::from_slice(&[kind as u8]).unwrap();
+                (Header::new(Command::Refresh, 1), Some(buf))
+            }
+            Packet::CreditAdvertise(credit) => {
+                let buf = PayloadBuf::from_slice(&credit.to_le_bytes()).unwrap();
+                (Header::new(Command::CreditAdvertise, 2), Some(buf))
+            }
         }
     }
 
@@ -80,9 +319,64 @@ impl Header {
 // 1 byte for BW , 250 x 250 pixels, 115200, 4 secs a frame, 15 fps
 // 1 bit for BW, 250 x 250 pixels, 115200, .5 sec a frame, 110 fps, 36 fps for 24bit color
 impl Packet {
+    /// Channel a packet goes out on when not sent with an explicit `Frame`
+    /// Screen data goes on Bulk so it can't hold up control requests, refreshes go with it
+    /// so they never overtake the regions they push to the panel
+    pub fn channel(&self) -> Channel {
+        match self {
+            Packet::UpdateRegion(_) | Packet::Refresh(_) => Channel::Bulk,
+            _ => Channel::Control,
+        }
+    }
+
+    /// Bytes the packet takes up on the wire, header included
+    pub fn size(&self) -> usize {
+        let payload = match self {
+            Packet::Echo(buf)
+            | Packet::GetParam(buf)
+            | Packet::SetParam(buf)
+            | Packet::Response(buf)
+            | Packet::SetPlaylist(buf)
+            | Packet::UpdateRegion(buf) => buf.len(),
+            Packet::Error(error) => 1 + error.detail.as_ref().map_or(0, |d| d.len()),
+            Packet::GetParamList
+            | Packet::CreditRequest
+            | Packet::ListPages
+            | Packet::GetCurrentPage => 0,
+            Packet::ShowPage(_) | Packet::Refresh(_) => 1,
+            Packet::Credit(_) | Packet::CreditAdvertise(_) => 2,
+        };
+        HEADER_SIZE + payload
+    }
+
+    pub fn serialize(self) -> heapless::Vec<u8, MAX_PACKET_SIZE> {
+        Frame::from(self).serialize()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn serialize_vec(self) -> std::vec::Vec<u8> {
+        Frame::from(self).serialize_vec()
+    }
+}
+
+impl From<Packet> for Frame {
+    fn from(packet: Packet) -> Self {
+        Self {
+            channel: packet.channel(),
+            packet,
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(channel: Channel, packet: Packet) -> Self {
+        Self { channel, packet }
+    }
+
     pub fn serialize(self) -> heapless::Vec<u8, MAX_PACKET_SIZE> {
         let mut vec = heapless::Vec::<u8, MAX_PACKET_SIZE>::new();
-        let (header, payload) = Header::from_packet(self);
+        let (mut header, payload) = Header::from_packet(self.packet);
+        header.channel = self.channel;
         let header_buf = unsafe { as_u8_slice(&header) };
         for b in header_buf {
             //unwrap should be fine here since we're controlling all the sizes
@@ -100,7 +394,8 @@ impl Packet {
     pub fn serialize_vec(self) -> std::vec::Vec<u8> {
         use std::vec;
         let mut vec = vec![];
-        let (header, payload) = Header::from_packet(self);
+        let (mut header, payload) = Header::from_packet(self.packet);
+        header.channel = self.channel;
         let header_buf = unsafe { as_u8_slice(&header) };
         vec.extend_from_slice(header_buf);
         if let Some(payload) = payload {
@@ -116,9 +411,160 @@ mod test {
     #[test]
     pub fn test_serialize() {
         let packet = Packet::Echo(PayloadBuf::from_slice(b"Hi").unwrap());
-        let expected = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'H', b'i'];
-        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let expected = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'H',
+            b'i',
+        ];
+
+        assert_eq!(expected, packet.serialize());
+    }
+
+    #[test]
+    pub fn test_serialize_error() {
+        let packet = Packet::Error(ErrorPayload::with_detail(ErrorCode::UnknownParam, "FOO"));
+        let expected = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Error as u8,
+            4,
+            ErrorCode::UnknownParam as u8,
+            b'F',
+            b'O',
+            b'O',
+        ];
 
         assert_eq!(expected, packet.serialize());
     }
+
+    #[test]
+    pub fn test_serialize_frame() {
+        let frame = Frame::new(
+            Channel::Bulk,
+            Packet::Echo(PayloadBuf::from_slice(b"Hi").unwrap()),
+        );
+        let expected = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Bulk as u8,
+            Command::Echo as u8,
+            2,
+            b'H',
+            b'i',
+        ];
+        assert_eq!(expected, frame.clone().serialize());
+        assert_eq!(&expected[..], &frame.serialize_vec()[..]);
+    }
+
+    #[test]
+    pub fn test_default_channel() {
+        let region = Packet::UpdateRegion(PayloadBuf::new());
+        assert_eq!(Frame::from(region).channel, Channel::Bulk);
+        assert_eq!(Packet::Refresh(RefreshKind::Full).channel(), Channel::Bulk);
+        assert_eq!(Packet::GetParamList.channel(), Channel::Control);
+        assert_eq!(
+            Packet::Error(ErrorCode::Busy.into()).channel(),
+            Channel::Control
+        );
+    }
+
+    #[test]
+    pub fn test_byte_conversions() {
+        assert_eq!(Channel::try_from(3), Ok(Channel::Bulk));
+        assert_eq!(Channel::try_from(4), Err(4));
+        assert_eq!(
+            Command::try_from(Command::Credit as u8),
+            Ok(Command::Credit)
+        );
+        assert_eq!(
+            Command::try_from(Command::GetCurrentPage as u8),
+            Ok(Command::GetCurrentPage)
+        );
+        assert_eq!(Command::try_from(0xF0), Err(0xF0));
+    }
+
+    #[test]
+    pub fn test_serialize_credit() {
+        let expected = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Credit as u8,
+            2,
+            0x00,
+            0x10,
+        ];
+        assert_eq!(expected, Packet::Credit(4096).serialize());
+    }
+
+    #[test]
+    pub fn test_packet_size() {
+        let packets = [
+            Packet::Echo(PayloadBuf::from_slice(b"Hi").unwrap()),
+            Packet::Error(ErrorPayload::with_detail(ErrorCode::Busy, "drawing")),
+            Packet::Error(ErrorCode::Busy.into()),
+            Packet::CreditRequest,
+            Packet::CreditAdvertise(4096),
+            Packet::ShowPage(3),
+        ];
+        for packet in packets {
+            assert_eq!(
+                packet.size(),
+                packet.clone().serialize().len(),
+                "{packet:?}"
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_error_roundtrip() {
+        let error = ErrorPayload::with_detail(ErrorCode::Busy, "drawing");
+        assert_eq!(ErrorPayload::decode(&error.encode()), error);
+
+        let error = ErrorPayload::with_detail(ErrorCode::Busy, "");
+        assert_eq!(error, ErrorPayload::new(ErrorCode::Busy));
+        assert_eq!(ErrorPayload::decode(&error.encode()), error);
+
+        let error = ErrorPayload::new(ErrorCode::ChecksumFailed);
+        assert_eq!(
+            error.encode().as_slice(),
+            &[ErrorCode::ChecksumFailed as u8]
+        );
+        assert_eq!(ErrorPayload::decode(&error.encode()), error);
+    }
+
+    #[test]
+    pub fn test_decode_unknown_error_code() {
+        let error = ErrorPayload::decode(&[0xEE, b'?']);
+        assert_eq!(error.code, ErrorCode::Unknown);
+        assert_eq!(error.detail.as_deref(), Some("?"));
+        assert_eq!(ErrorPayload::decode(&[]).code, ErrorCode::Unknown);
+    }
+
+    #[test]
+    pub fn test_error_detail_truncated() {
+        let long = [b'a'; MAX_PAYLOAD_SIZE + 10];
+        let error = ErrorPayload::with_detail(
+            ErrorCode::InvalidValue,
+            core::str::from_utf8(&long).unwrap(),
+        );
+        assert_eq!(error.encode().len(), MAX_PAYLOAD_SIZE);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    pub fn test_error_display() {
+        use std::string::ToString;
+        let error = ErrorPayload::with_detail(ErrorCode::UnknownParam, "FOO");
+        assert_eq!(error.to_string(), "unknown param: FOO");
+        assert_eq!(
+            ErrorPayload::new(ErrorCode::NotSupported).to_string(),
+            "not supported"
+        );
+    }
 }
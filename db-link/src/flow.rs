@@ -0,0 +1,366 @@
+//! Credit based flow control
+//!
+//! The receiving side advertises how many bytes it can buffer, the sender never has more than
+//! that in flight and the receiver hands credit back as it consumes data.
+//! Credit is counted in serialized frames, `CreditRequest`, `Credit` and `CreditAdvertise`
+//! packets themselves are exempt so a sender with no credit can always resync. Bytes between
+//! frames are noise the sender never paid for and aren't handed back either.
+//!
+//! Resync: the sender drops its credit, sends `CreditRequest` and waits for `CreditAdvertise`.
+//! The receiver answers with its current free space. `Credit` batches that cross the request
+//! are for bytes the advertised space already accounts for, so the sender ignores them until
+//! the advertisement arrives. This is only exact when the sender has nothing else in flight,
+//! i.e. on connect.
+
+use crate::commands::Packet;
+
+#[cfg(feature = "std")]
+use thiserror::Error;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Error))]
+pub enum Error {
+    #[cfg_attr(feature = "std", error("not enough credit to send {0} bytes"))]
+    NoCredit(usize),
+}
+
+/// Returns true for packets that don't count against the credit window
+pub fn is_exempt(packet: &Packet) -> bool {
+    matches!(
+        packet,
+        Packet::CreditRequest | Packet::Credit(_) | Packet::CreditAdvertise(_)
+    )
+}
+
+/// Sending side of the credit window, lives on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreditSender {
+    credit: usize,
+    unlimited: bool,
+    /// waiting on a `CreditAdvertise`, grants until then are stale
+    resyncing: bool,
+}
+
+impl CreditSender {
+    /// Starts out with no credit, the receiver has to advertise first
+    pub const fn new() -> Self {
+        Self {
+            credit: 0,
+            unlimited: false,
+            resyncing: true,
+        }
+    }
+
+    /// Sender that never blocks, for peers that don't implement flow control
+    pub const fn unlimited() -> Self {
+        Self {
+            credit: usize::MAX,
+            unlimited: true,
+            resyncing: false,
+        }
+    }
+
+    /// Bytes that can currently be sent
+    pub fn credit(&self) -> usize {
+        self.credit
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.unlimited
+    }
+
+    /// True until the answer to a `CreditRequest` arrives
+    pub fn is_resyncing(&self) -> bool {
+        self.resyncing
+    }
+
+    /// Drop all credit and ignore grants until the next advertisement, used before sending a
+    /// `CreditRequest`
+    pub fn reset(&mut self) {
+        if !self.unlimited {
+            self.credit = 0;
+            self.resyncing = true;
+        }
+    }
+
+    pub fn grant(&mut self, credit: u16) {
+        if !self.unlimited {
+            self.credit = self.credit.saturating_add(credit as usize);
+        }
+    }
+
+    pub fn can_send(&self, len: usize) -> bool {
+        len <= self.credit
+    }
+
+    /// Take `len` bytes of credit, fails without taking anything if there isn't enough
+    pub fn consume(&mut self, len: usize) -> Result<(), Error> {
+        if self.unlimited {
+            Ok(())
+        } else if self.can_send(len) {
+            self.credit -= len;
+            Ok(())
+        } else {
+            Err(Error::NoCredit(len))
+        }
+    }
+
+    /// Applies a received `Credit` or `CreditAdvertise` packet, returns false for any other
+    /// packet
+    pub fn handle(&mut self, packet: &Packet) -> bool {
+        match packet {
+            Packet::Credit(credit) => {
+                if !self.resyncing {
+                    self.grant(*credit);
+                }
+                true
+            }
+            Packet::CreditAdvertise(credit) => {
+                if !self.unlimited {
+                    self.credit = *credit as usize;
+                    self.resyncing = false;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for CreditSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receiving side of the credit window, lives on the device
+/// Tracks consumed bytes and hands them back once `threshold` bytes have built up,
+/// batching keeps credit packets from eating the bandwidth they are meant to protect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreditReceiver {
+    capacity: u16,
+    threshold: u16,
+    pending: u16,
+}
+
+impl CreditReceiver {
+    pub const fn new(capacity: u16, threshold: u16) -> Self {
+        Self {
+            capacity,
+            threshold,
+            pending: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> u16 {
+        self.capacity
+    }
+
+    /// Credit consumed but not yet handed back
+    pub fn pending(&self) -> u16 {
+        self.pending
+    }
+
+    /// Answer to a `CreditRequest`, `free` is the space left in the receive buffer
+    pub fn advertise(&mut self, free: usize) -> Packet {
+        self.pending = 0;
+        Packet::CreditAdvertise(free.min(self.capacity as usize) as u16)
+    }
+
+    /// Record a parsed frame taken out of the receive buffer, exempt packets don't count
+    /// returns a `Credit` packet to send once enough has built up
+    pub fn received(&mut self, packet: &Packet) -> Option<Packet> {
+        if is_exempt(packet) {
+            None
+        } else {
+            self.consumed(packet.size())
+        }
+    }
+
+    /// Record `len` bytes taken out of the receive buffer
+    /// returns a `Credit` packet to send once enough has built up
+    pub fn consumed(&mut self, len: usize) -> Option<Packet> {
+        let len = len.min(self.capacity as usize) as u16;
+        self.pending = self.pending.saturating_add(len).min(self.capacity);
+        if self.pending >= self.threshold {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Hand back everything pending, call when the receive buffer runs dry so the
+    /// sender isn't left waiting on credit below the threshold
+    pub fn flush(&mut self) -> Option<Packet> {
+        if self.pending == 0 {
+            None
+        } else {
+            let credit = self.pending;
+            self.pending = 0;
+            Some(Packet::Credit(credit))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sender_starts_empty() {
+        let mut sender = CreditSender::new();
+        assert_eq!(sender.credit(), 0);
+        assert_eq!(sender.consume(1), Err(Error::NoCredit(1)));
+        sender.grant(10);
+        assert!(sender.can_send(10));
+        assert_eq!(sender.consume(6), Ok(()));
+        assert_eq!(sender.consume(6), Err(Error::NoCredit(6)));
+        assert_eq!(sender.credit(), 4);
+    }
+
+    #[test]
+    fn test_sender_handle() {
+        let mut sender = CreditSender::new();
+        //stale, nothing was advertised yet
+        assert!(sender.handle(&Packet::Credit(5)));
+        assert_eq!(sender.credit(), 0);
+        assert!(sender.handle(&Packet::CreditAdvertise(20)));
+        assert!(!sender.is_resyncing());
+        assert!(sender.handle(&Packet::Credit(5)));
+        assert!(!sender.handle(&Packet::CreditRequest));
+        assert_eq!(sender.credit(), 25);
+        sender.reset();
+        assert_eq!(sender.credit(), 0);
+        assert!(sender.is_resyncing());
+        sender.handle(&Packet::CreditAdvertise(8));
+        assert_eq!(sender.credit(), 8);
+    }
+
+    #[test]
+    fn test_unlimited_sender() {
+        let mut sender = CreditSender::unlimited();
+        assert_eq!(sender.consume(usize::MAX), Ok(()));
+        sender.reset();
+        assert!(sender.can_send(1 << 20));
+    }
+
+    #[test]
+    fn test_receiver_batches_credit() {
+        let mut receiver = CreditReceiver::new(100, 10);
+        assert_eq!(receiver.advertise(80), Packet::CreditAdvertise(80));
+        assert_eq!(receiver.consumed(4), None);
+        assert_eq!(receiver.consumed(4), None);
+        assert_eq!(receiver.consumed(4), Some(Packet::Credit(12)));
+        assert_eq!(receiver.pending(), 0);
+        assert_eq!(receiver.consumed(3), None);
+        assert_eq!(receiver.flush(), Some(Packet::Credit(3)));
+        assert_eq!(receiver.flush(), None);
+    }
+
+    #[test]
+    fn test_receiver_advertise_caps_and_resets() {
+        let mut receiver = CreditReceiver::new(100, 50);
+        receiver.consumed(20);
+        assert_eq!(receiver.advertise(1000), Packet::CreditAdvertise(100));
+        assert_eq!(receiver.pending(), 0);
+    }
+
+    /// Runs a sender and receiver against a bounded buffer and checks it never overflows
+    #[test]
+    fn test_window_never_overruns() {
+        const CAPACITY: usize = 64;
+        let mut sender = CreditSender::new();
+        let mut receiver = CreditReceiver::new(CAPACITY as u16, 16);
+        let mut buffered = 0usize;
+        let mut sent = 0usize;
+
+        sender.handle(&receiver.advertise(CAPACITY - buffered));
+        for step in 0..1000 {
+            let len = 1 + step % 23;
+            if sender.consume(len).is_ok() {
+                buffered += len;
+                sent += len;
+                assert!(buffered <= CAPACITY);
+            }
+            //device drains a few bytes at a time
+            let drained = buffered.min(1 + step % 7);
+            buffered -= drained;
+            if let Some(credit) = receiver.consumed(drained) {
+                sender.handle(&credit);
+            }
+            if buffered == 0 {
+                if let Some(credit) = receiver.flush() {
+                    sender.handle(&credit);
+                }
+            }
+        }
+        assert!(sent > CAPACITY);
+    }
+
+    #[test]
+    fn test_received_skips_exempt() {
+        let mut receiver = CreditReceiver::new(100, 10);
+        assert_eq!(receiver.received(&Packet::CreditRequest), None);
+        assert_eq!(receiver.received(&Packet::Credit(4)), None);
+        assert_eq!(receiver.pending(), 0);
+        assert_eq!(receiver.received(&Packet::GetParamList), None);
+        assert_eq!(receiver.pending(), 5);
+    }
+
+    /// Three echoes are queued in front of a `CreditRequest`. The second crosses the threshold
+    /// so a batch `Credit` goes out ahead of the advertisement, the third leaves credit pending
+    /// that the request's own bytes would push over the threshold if they counted. The sender
+    /// must end up with exactly the advertised space
+    #[test]
+    fn test_threshold_crossed_during_resync() {
+        use crate::commands::{Frame, PayloadBuf};
+        use crate::parser::Parser;
+
+        const CAPACITY: usize = 64;
+        let mut sender = CreditSender::new();
+        let mut receiver = CreditReceiver::new(CAPACITY as u16, 16);
+        let mut parser = Parser::new();
+        let mut queue: heapless::Deque<u8, CAPACITY> = heapless::Deque::new();
+        sender.handle(&receiver.advertise(CAPACITY));
+
+        let echo = Packet::Echo(PayloadBuf::from_slice(&[0; 10]).unwrap());
+        for _ in 0..3 {
+            assert_eq!(sender.consume(echo.size()), Ok(()));
+            for b in echo.clone().serialize() {
+                queue.push_back(b).unwrap();
+            }
+        }
+        //the host gives up waiting and resyncs with all three echoes still queued
+        sender.reset();
+        for b in Packet::CreditRequest.serialize() {
+            queue.push_back(b).unwrap();
+        }
+
+        let mut replies = heapless::Vec::<Packet, 8>::new();
+        while let Some(byte) = queue.pop_front() {
+            match parser.parse_frame(&[byte]) {
+                Ok(Frame {
+                    packet: Packet::CreditRequest,
+                    ..
+                }) => {
+                    let free = CAPACITY - queue.len();
+                    replies.push(receiver.advertise(free)).unwrap();
+                }
+                Ok(frame) => {
+                    if let Some(credit) = receiver.received(&frame.packet) {
+                        replies.push(credit).unwrap();
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+        assert_eq!(
+            replies,
+            [Packet::Credit(30), Packet::CreditAdvertise(CAPACITY as u16)]
+        );
+        for reply in &replies {
+            sender.handle(reply);
+        }
+        assert_eq!(sender.credit(), CAPACITY);
+    }
+}
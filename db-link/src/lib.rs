@@ -4,5 +4,10 @@
 extern crate std;
 
 pub mod commands;
+pub mod flow;
 pub mod mem_utils;
+pub mod mux;
+pub mod pages;
+pub mod params;
 pub mod parser;
+pub mod region;
@@ -1,3 +1,7 @@
+/// Views a value as its raw bytes
+///
+/// # Safety
+/// `T` must not contain padding bytes, reading those is undefined behaviour
 pub unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::core::slice::from_raw_parts((p as *const T) as *const u8, ::core::mem::size_of::<T>())
 }
@@ -0,0 +1,103 @@
+//! Per channel send queues
+//!
+//! Frames are queued by channel and handed out highest priority first, so a quick control
+//! request only ever waits behind the packet currently on the wire, not a whole bulk transfer.
+//! Scheduling is strict priority, bulk data only moves when nothing else is waiting.
+
+use heapless::Deque;
+
+use crate::commands::{Channel, Frame, CHANNEL_COUNT};
+
+pub struct Mux<const DEPTH: usize> {
+    queues: [Deque<Frame, DEPTH>; CHANNEL_COUNT],
+}
+
+impl<const DEPTH: usize> Mux<DEPTH> {
+    pub const fn new() -> Self {
+        Self {
+            queues: [Deque::new(), Deque::new(), Deque::new(), Deque::new()],
+        }
+    }
+
+    /// Queue a frame on its channel, hands it back if that channel is full
+    //same shape as heapless, there's no allocator to box with
+    #[allow(clippy::result_large_err)]
+    pub fn push(&mut self, frame: Frame) -> Result<(), Frame> {
+        self.queues[frame.channel as usize].push_back(frame)
+    }
+
+    /// Next frame to send, from the highest priority channel with anything waiting
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    /// Frames waiting on a channel
+    pub fn len(&self, channel: Channel) -> usize {
+        self.queues[channel as usize].len()
+    }
+
+    pub fn is_full(&self, channel: Channel) -> bool {
+        self.queues[channel as usize].is_full()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Drop everything queued on a channel, e.g. when a bulk transfer is aborted
+    pub fn clear(&mut self, channel: Channel) {
+        self.queues[channel as usize].clear();
+    }
+}
+
+impl<const DEPTH: usize> Default for Mux<DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Packet, PayloadBuf};
+
+    fn frame(channel: Channel, msg: &[u8]) -> Frame {
+        Frame::new(channel, Packet::Echo(PayloadBuf::from_slice(msg).unwrap()))
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut mux = Mux::<4>::new();
+        mux.push(frame(Channel::Bulk, b"b1")).unwrap();
+        mux.push(frame(Channel::Bulk, b"b2")).unwrap();
+        mux.push(frame(Channel::Log, b"l1")).unwrap();
+        mux.push(frame(Channel::Control, b"c1")).unwrap();
+        mux.push(frame(Channel::Event, b"e1")).unwrap();
+
+        assert_eq!(mux.pop(), Some(frame(Channel::Control, b"c1")));
+        assert_eq!(mux.pop(), Some(frame(Channel::Event, b"e1")));
+        assert_eq!(mux.pop(), Some(frame(Channel::Log, b"l1")));
+        //control arriving mid transfer jumps the remaining bulk frames
+        assert_eq!(mux.pop(), Some(frame(Channel::Bulk, b"b1")));
+        mux.push(frame(Channel::Control, b"c2")).unwrap();
+        assert_eq!(mux.pop(), Some(frame(Channel::Control, b"c2")));
+        assert_eq!(mux.pop(), Some(frame(Channel::Bulk, b"b2")));
+        assert_eq!(mux.pop(), None);
+        assert!(mux.is_empty());
+    }
+
+    #[test]
+    fn test_full_channel_does_not_block_others() {
+        let mut mux = Mux::<1>::new();
+        mux.push(frame(Channel::Bulk, b"b1")).unwrap();
+        assert!(mux.is_full(Channel::Bulk));
+        assert_eq!(
+            mux.push(frame(Channel::Bulk, b"b2")),
+            Err(frame(Channel::Bulk, b"b2"))
+        );
+        mux.push(frame(Channel::Control, b"c1")).unwrap();
+        assert_eq!(mux.len(Channel::Control), 1);
+        mux.clear(Channel::Bulk);
+        assert_eq!(mux.len(Channel::Bulk), 0);
+    }
+}
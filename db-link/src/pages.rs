@@ -0,0 +1,506 @@
+//! Typed payloads for the page commands
+//!
+//! ListPages response: entries of page id, name length (1 byte), name
+//! ShowPage: page id (1 byte)
+//! SetPlaylist: entries of page id, duration in seconds (u16 LE)
+//! GetCurrentPage response: page id (1 byte)
+//!
+//! `PageRegistry` keeps the device side state and answers all four commands
+
+use core::time::Duration;
+
+use crate::commands::{ErrorCode, ErrorPayload, Packet, PayloadBuf, MAX_PAYLOAD_SIZE};
+
+#[cfg(feature = "std")]
+use thiserror::Error;
+
+pub type PageId = u8;
+
+pub const MAX_PAGE_NAME_SIZE: usize = 32;
+pub const PLAYLIST_ENTRY_SIZE: usize = 3;
+pub const MAX_PLAYLIST_LEN: usize = MAX_PAYLOAD_SIZE / PLAYLIST_ENTRY_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Error))]
+pub enum Error {
+    #[cfg_attr(
+        feature = "std",
+        error("page name must be 1 to {MAX_PAGE_NAME_SIZE} bytes")
+    )]
+    InvalidName,
+    #[cfg_attr(
+        feature = "std",
+        error("playlist longer than {MAX_PLAYLIST_LEN} entries")
+    )]
+    PlaylistTooLong,
+    #[cfg_attr(feature = "std", error("playlist durations must be non zero"))]
+    InvalidDuration,
+    #[cfg_attr(feature = "std", error("malformed page payload"))]
+    InvalidPayload,
+    #[cfg_attr(feature = "std", error("no room for more pages"))]
+    TooManyPages,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PageInfo<'a> {
+    pub id: PageId,
+    pub name: &'a str,
+}
+
+/// Builds a ListPages response, pages that don't fit are left off
+#[derive(Debug, Default)]
+pub struct PageListBuilder {
+    buf: PayloadBuf,
+}
+
+impl PageListBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false once the list is full
+    pub fn push(&mut self, id: PageId, name: &str) -> Result<bool, Error> {
+        if name.is_empty() || name.len() > MAX_PAGE_NAME_SIZE {
+            return Err(Error::InvalidName);
+        }
+        if self.buf.len() + 2 + name.len() > self.buf.capacity() {
+            return Ok(false);
+        }
+        _ = self.buf.push(id);
+        _ = self.buf.push(name.len() as u8);
+        _ = self.buf.extend_from_slice(name.as_bytes());
+        Ok(true)
+    }
+
+    pub fn finish(self) -> Packet {
+        Packet::Response(self.buf)
+    }
+}
+
+/// Pages in a ListPages response payload, stops at the first malformed entry
+pub fn page_list(payload: &[u8]) -> impl Iterator<Item = PageInfo<'_>> {
+    let mut rest = payload;
+    core::iter::from_fn(move || {
+        let [id, len, tail @ ..] = rest else {
+            return None;
+        };
+        let len = *len as usize;
+        if tail.len() < len {
+            return None;
+        }
+        let (name, tail) = tail.split_at(len);
+        rest = tail;
+        let name = core::str::from_utf8(name).ok()?;
+        Some(PageInfo { id: *id, name })
+    })
+}
+
+/// Page id of a GetCurrentPage response payload
+pub fn decode_current(payload: &[u8]) -> Result<PageId, Error> {
+    match payload {
+        [id] => Ok(*id),
+        _ => Err(Error::InvalidPayload),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PlaylistEntry {
+    pub page: PageId,
+    pub duration_secs: u16,
+}
+
+impl PlaylistEntry {
+    pub fn new(page: PageId, duration_secs: u16) -> Self {
+        Self {
+            page,
+            duration_secs,
+        }
+    }
+}
+
+/// Entries of a SetPlaylist payload
+pub fn decode_playlist(payload: &[u8]) -> Result<impl Iterator<Item = PlaylistEntry> + '_, Error> {
+    if !payload.len().is_multiple_of(PLAYLIST_ENTRY_SIZE) {
+        return Err(Error::InvalidPayload);
+    }
+    let entries = payload
+        .chunks_exact(PLAYLIST_ENTRY_SIZE)
+        .map(|entry| PlaylistEntry::new(entry[0], u16::from_le_bytes([entry[1], entry[2]])));
+    if entries.clone().any(|entry| entry.duration_secs == 0) {
+        return Err(Error::InvalidDuration);
+    }
+    Ok(entries)
+}
+
+impl Packet {
+    pub fn set_playlist(entries: &[PlaylistEntry]) -> Result<Packet, Error> {
+        if entries.len() > MAX_PLAYLIST_LEN {
+            return Err(Error::PlaylistTooLong);
+        }
+        let mut buf = PayloadBuf::new();
+        for entry in entries {
+            if entry.duration_secs == 0 {
+                return Err(Error::InvalidDuration);
+            }
+            //length is checked above, this always fits
+            _ = buf.push(entry.page);
+            _ = buf.extend_from_slice(&entry.duration_secs.to_le_bytes());
+        }
+        Ok(Packet::SetPlaylist(buf))
+    }
+
+    pub fn current_page(id: PageId) -> Packet {
+        Packet::Response(PayloadBuf::from_slice(&[id]).unwrap())
+    }
+}
+
+pub type PageName = heapless::String<MAX_PAGE_NAME_SIZE>;
+
+#[derive(Debug, Clone)]
+struct Page {
+    id: PageId,
+    name: PageName,
+}
+
+/// Tracks which pages exist and which one is on screen, and serves the page commands
+/// Holds up to `N` pages. Times are `now` since any fixed point, such as boot
+#[derive(Debug)]
+pub struct PageRegistry<const N: usize> {
+    pages: heapless::Vec<Page, N>,
+    current: Option<PageId>,
+    playlist: heapless::Vec<PlaylistEntry, MAX_PLAYLIST_LEN>,
+    position: usize,
+    shown_at: Duration,
+}
+
+impl<const N: usize> PageRegistry<N> {
+    pub const fn new() -> Self {
+        Self {
+            pages: heapless::Vec::new(),
+            current: None,
+            playlist: heapless::Vec::new(),
+            position: 0,
+            shown_at: Duration::ZERO,
+        }
+    }
+
+    /// Adds a page and returns its id, the first page registered is shown
+    pub fn register(&mut self, name: &str) -> Result<PageId, Error> {
+        let id = PageId::try_from(self.pages.len()).map_err(|_| Error::TooManyPages)?;
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        let name = PageName::try_from(name).map_err(|_| Error::InvalidName)?;
+        self.pages
+            .push(Page { id, name })
+            .map_err(|_| Error::TooManyPages)?;
+        self.current.get_or_insert(id);
+        Ok(id)
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = PageInfo<'_>> {
+        self.pages.iter().map(|page| PageInfo {
+            id: page.id,
+            name: page.name.as_str(),
+        })
+    }
+
+    pub fn name(&self, id: PageId) -> Option<&str> {
+        self.pages
+            .iter()
+            .find(|page| page.id == id)
+            .map(|page| page.name.as_str())
+    }
+
+    pub fn current(&self) -> Option<PageId> {
+        self.current
+    }
+
+    pub fn playlist(&self) -> &[PlaylistEntry] {
+        &self.playlist
+    }
+
+    /// Shows a page right away and stops the playlist, returns false for unknown ids
+    pub fn show(&mut self, id: PageId, now: Duration) -> bool {
+        if self.name(id).is_none() {
+            return false;
+        }
+        self.playlist.clear();
+        self.current = Some(id);
+        self.shown_at = now;
+        true
+    }
+
+    /// Starts rotating through `entries` from the first one, an empty list stops the rotation
+    /// Returns false and leaves the registry untouched if any entry is an unknown page or
+    /// there are more than `MAX_PLAYLIST_LEN`
+    pub fn set_playlist(&mut self, entries: &[PlaylistEntry], now: Duration) -> bool {
+        if entries.iter().any(|entry| self.name(entry.page).is_none()) {
+            return false;
+        }
+        let Ok(playlist) = heapless::Vec::from_slice(entries) else {
+            return false;
+        };
+        self.playlist = playlist;
+        self.position = 0;
+        if let Some(first) = self.playlist.first() {
+            self.current = Some(first.page);
+            self.shown_at = now;
+        }
+        true
+    }
+
+    /// Advances the playlist, returns the new page when the screen needs redrawing
+    pub fn tick(&mut self, now: Duration) -> Option<PageId> {
+        let entry = self.playlist.get(self.position)?;
+        let duration = Duration::from_secs(entry.duration_secs as u64);
+        if now.saturating_sub(self.shown_at) < duration {
+            return None;
+        }
+        self.position = (self.position + 1) % self.playlist.len();
+        let next = self.playlist[self.position].page;
+        self.shown_at = now;
+        if self.current == Some(next) {
+            return None;
+        }
+        self.current = Some(next);
+        Some(next)
+    }
+
+    /// Answers a page command, returns None for packets that aren't page commands
+    pub fn handle(&mut self, packet: &Packet, now: Duration) -> Option<Packet> {
+        Some(match packet {
+            Packet::ListPages => {
+                let mut list = PageListBuilder::new();
+                for page in self.pages() {
+                    //names are checked on register, pages that don't fit are left off
+                    if list.push(page.id, page.name) != Ok(true) {
+                        break;
+                    }
+                }
+                list.finish()
+            }
+            Packet::ShowPage(id) => {
+                if self.show(*id, now) {
+                    Packet::Response(PayloadBuf::new())
+                } else {
+                    unknown_page()
+                }
+            }
+            Packet::SetPlaylist(payload) => {
+                let Ok(entries) = decode_playlist(payload) else {
+                    return Some(Packet::Error(ErrorPayload::new(ErrorCode::InvalidValue)));
+                };
+                //a payload never holds more than MAX_PLAYLIST_LEN entries
+                let entries: heapless::Vec<_, MAX_PLAYLIST_LEN> = entries.collect();
+                if self.set_playlist(&entries, now) {
+                    Packet::Response(PayloadBuf::new())
+                } else {
+                    unknown_page()
+                }
+            }
+            Packet::GetCurrentPage => match self.current {
+                Some(id) => Packet::current_page(id),
+                None => Packet::Error(ErrorPayload::with_detail(
+                    ErrorCode::NotSupported,
+                    "no pages",
+                )),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl<const N: usize> Default for PageRegistry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unknown_page() -> Packet {
+    Packet::Error(ErrorPayload::with_detail(
+        ErrorCode::InvalidValue,
+        "unknown page",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> PageRegistry<4> {
+        let mut registry = PageRegistry::new();
+        registry.register("weather").unwrap();
+        registry.register("clock").unwrap();
+        registry.register("stats").unwrap();
+        registry
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_page_list() {
+        let mut list = PageListBuilder::new();
+        assert_eq!(list.push(0, "weather"), Ok(true));
+        assert_eq!(list.push(4, "clock"), Ok(true));
+        assert_eq!(list.push(5, ""), Err(Error::InvalidName));
+        let Packet::Response(payload) = list.finish() else {
+            panic!("expected Response");
+        };
+        let mut pages = page_list(&payload);
+        assert_eq!(
+            pages.next(),
+            Some(PageInfo {
+                id: 0,
+                name: "weather"
+            })
+        );
+        assert_eq!(
+            pages.next(),
+            Some(PageInfo {
+                id: 4,
+                name: "clock"
+            })
+        );
+        assert_eq!(pages.next(), None);
+    }
+
+    #[test]
+    fn test_page_list_truncated() {
+        assert_eq!(page_list(&[1, 5, b'a', b'b']).next(), None);
+    }
+
+    #[test]
+    fn test_playlist_roundtrip() {
+        let entries = [PlaylistEntry::new(0, 60), PlaylistEntry::new(2, 600)];
+        let Packet::SetPlaylist(payload) = Packet::set_playlist(&entries).unwrap() else {
+            panic!("expected SetPlaylist");
+        };
+        assert_eq!(&payload[..], &[0, 60, 0, 2, 0x58, 0x02]);
+        let decoded: heapless::Vec<_, 2> = decode_playlist(&payload).unwrap().collect();
+        assert_eq!(&decoded[..], &entries);
+    }
+
+    #[test]
+    fn test_playlist_invalid() {
+        assert_eq!(
+            Packet::set_playlist(&[PlaylistEntry::new(0, 0)]),
+            Err(Error::InvalidDuration)
+        );
+        assert_eq!(
+            Packet::set_playlist(&[PlaylistEntry::new(0, 1); MAX_PLAYLIST_LEN + 1]),
+            Err(Error::PlaylistTooLong)
+        );
+        assert!(decode_playlist(&[0, 1]).is_err());
+        assert!(decode_playlist(&[0, 0, 0]).is_err());
+        assert_eq!(decode_playlist(&[]).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_current_page() {
+        let Packet::Response(payload) = Packet::current_page(7) else {
+            panic!("expected Response");
+        };
+        assert_eq!(decode_current(&payload), Ok(7));
+        assert_eq!(decode_current(&[]), Err(Error::InvalidPayload));
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = registry();
+        assert_eq!(registry.current(), Some(0));
+        assert_eq!(registry.register(""), Err(Error::InvalidName));
+        assert_eq!(
+            registry.register(core::str::from_utf8(&[b'a'; MAX_PAGE_NAME_SIZE + 1]).unwrap()),
+            Err(Error::InvalidName)
+        );
+        assert_eq!(registry.register("news"), Ok(3));
+        assert_eq!(registry.register("full"), Err(Error::TooManyPages));
+    }
+
+    #[test]
+    fn test_register_runs_out_of_ids() {
+        let mut registry = PageRegistry::<300>::new();
+        for id in 0..=PageId::MAX {
+            assert_eq!(registry.register("page"), Ok(id));
+        }
+        assert_eq!(registry.register("page"), Err(Error::TooManyPages));
+    }
+
+    #[test]
+    fn test_list_pages() {
+        let mut registry = registry();
+        let Some(Packet::Response(payload)) = registry.handle(&Packet::ListPages, secs(0)) else {
+            panic!("expected Response");
+        };
+        let pages: heapless::Vec<_, 4> = page_list(&payload).collect();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(
+            pages[1],
+            PageInfo {
+                id: 1,
+                name: "clock"
+            }
+        );
+    }
+
+    #[test]
+    fn test_show_page() {
+        let mut registry = registry();
+        assert_eq!(
+            registry.handle(&Packet::ShowPage(2), secs(0)),
+            Some(Packet::Response(PayloadBuf::new()))
+        );
+        let Some(Packet::Response(payload)) = registry.handle(&Packet::GetCurrentPage, secs(0))
+        else {
+            panic!("expected Response");
+        };
+        assert_eq!(decode_current(&payload), Ok(2));
+        assert_eq!(
+            registry.handle(&Packet::ShowPage(9), secs(0)),
+            Some(unknown_page())
+        );
+        assert_eq!(registry.handle(&Packet::GetParamList, secs(0)), None);
+        assert!(matches!(
+            PageRegistry::<1>::new().handle(&Packet::GetCurrentPage, secs(0)),
+            Some(Packet::Error(_))
+        ));
+    }
+
+    #[test]
+    fn test_playlist_rotation() {
+        let mut registry = registry();
+        let start = secs(100);
+        let playlist =
+            Packet::set_playlist(&[PlaylistEntry::new(1, 10), PlaylistEntry::new(2, 5)]).unwrap();
+        assert_eq!(
+            registry.handle(&playlist, start),
+            Some(Packet::Response(PayloadBuf::new()))
+        );
+        assert_eq!(registry.current(), Some(1));
+        assert_eq!(registry.tick(start + secs(9)), None);
+        assert_eq!(registry.tick(start + secs(10)), Some(2));
+        assert_eq!(registry.tick(start + secs(14)), None);
+        assert_eq!(registry.tick(start + secs(15)), Some(1));
+
+        //showing a page by hand stops the rotation
+        assert!(registry.show(0, start + secs(16)));
+        assert_eq!(registry.tick(start + secs(100)), None);
+        assert_eq!(registry.current(), Some(0));
+    }
+
+    #[test]
+    fn test_playlist_unknown_page() {
+        let mut registry = registry();
+        let playlist = Packet::set_playlist(&[PlaylistEntry::new(7, 10)]).unwrap();
+        assert_eq!(registry.handle(&playlist, secs(0)), Some(unknown_page()));
+        assert!(registry.playlist().is_empty());
+        let bad = Packet::SetPlaylist(PayloadBuf::from_slice(&[0, 1]).unwrap());
+        assert!(matches!(
+            registry.handle(&bad, secs(0)),
+            Some(Packet::Error(error)) if error.code == ErrorCode::InvalidValue
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+//! Typed payloads for the param commands
+//!
+//! GetParam: the key
+//! SetParam: key length (1 byte), key, value
+//! GetParamList response: keys separated by `PARAM_LIST_SEPARATOR`
+
+use crate::commands::{Packet, PayloadBuf, MAX_PAYLOAD_SIZE};
+
+#[cfg(feature = "std")]
+use thiserror::Error;
+
+pub const MAX_KEY_SIZE: usize = 32;
+/// Largest value that fits in a SetParam next to a max size key
+pub const MAX_VALUE_SIZE: usize = MAX_PAYLOAD_SIZE - 1 - MAX_KEY_SIZE;
+pub const PARAM_LIST_SEPARATOR: u8 = 0;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Error))]
+pub enum Error {
+    #[cfg_attr(
+        feature = "std",
+        error("key must be 1 to {MAX_KEY_SIZE} bytes of utf8")
+    )]
+    InvalidKey,
+    #[cfg_attr(feature = "std", error("value larger than {MAX_VALUE_SIZE} bytes"))]
+    ValueTooLong,
+    #[cfg_attr(feature = "std", error("malformed param payload"))]
+    InvalidPayload,
+}
+
+fn check_key(key: &[u8]) -> Result<&str, Error> {
+    if key.is_empty() || key.len() > MAX_KEY_SIZE || key.contains(&PARAM_LIST_SEPARATOR) {
+        return Err(Error::InvalidKey);
+    }
+    core::str::from_utf8(key).map_err(|_| Error::InvalidKey)
+}
+
+/// Key of a GetParam payload
+pub fn decode_get(payload: &[u8]) -> Result<&str, Error> {
+    check_key(payload)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SetParam<'a> {
+    pub key: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> SetParam<'a> {
+    pub fn new(key: &'a str, value: &'a [u8]) -> Result<Self, Error> {
+        check_key(key.as_bytes())?;
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLong);
+        }
+        Ok(Self { key, value })
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Result<Self, Error> {
+        let Some((key_len, rest)) = payload.split_first() else {
+            return Err(Error::InvalidPayload);
+        };
+        let key_len = *key_len as usize;
+        if rest.len() < key_len {
+            return Err(Error::InvalidPayload);
+        }
+        let (key, value) = rest.split_at(key_len);
+        Ok(Self {
+            key: check_key(key)?,
+            value,
+        })
+    }
+
+    pub fn encode(&self) -> PayloadBuf {
+        let mut buf = PayloadBuf::new();
+        //sizes are checked in new, this always fits
+        _ = buf.push(self.key.len() as u8);
+        _ = buf.extend_from_slice(self.key.as_bytes());
+        _ = buf.extend_from_slice(self.value);
+        buf
+    }
+}
+
+/// Builds a GetParamList response, keys that don't fit are left off
+#[derive(Debug, Default)]
+pub struct ParamListBuilder {
+    buf: PayloadBuf,
+}
+
+impl ParamListBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false once the list is full
+    pub fn push(&mut self, key: &str) -> bool {
+        let sep = if self.buf.is_empty() { 0 } else { 1 };
+        if self.buf.len() + sep + key.len() > self.buf.capacity() {
+            return false;
+        }
+        if sep == 1 {
+            _ = self.buf.push(PARAM_LIST_SEPARATOR);
+        }
+        _ = self.buf.extend_from_slice(key.as_bytes());
+        true
+    }
+
+    pub fn finish(self) -> Packet {
+        Packet::Response(self.buf)
+    }
+}
+
+/// Keys in a GetParamList response payload, invalid utf8 entries are skipped
+pub fn param_list(payload: &[u8]) -> impl Iterator<Item = &str> {
+    payload
+        .split(|b| *b == PARAM_LIST_SEPARATOR)
+        .filter(|key| !key.is_empty())
+        .filter_map(|key| core::str::from_utf8(key).ok())
+}
+
+impl Packet {
+    pub fn get_param(key: &str) -> Result<Packet, Error> {
+        let key = check_key(key.as_bytes())?;
+        //keys are always shorter than a payload
+        Ok(Packet::GetParam(
+            PayloadBuf::from_slice(key.as_bytes()).unwrap(),
+        ))
+    }
+
+    pub fn set_param(key: &str, value: &[u8]) -> Result<Packet, Error> {
+        Ok(Packet::SetParam(SetParam::new(key, value)?.encode()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_param_roundtrip() {
+        let packet = Packet::set_param("BRIGHTNESS", &[10]).unwrap();
+        let Packet::SetParam(payload) = packet else {
+            panic!("expected SetParam");
+        };
+        assert_eq!(payload[0], 10);
+        let param = SetParam::decode(&payload).unwrap();
+        assert_eq!(param.key, "BRIGHTNESS");
+        assert_eq!(param.value, &[10]);
+    }
+
+    #[test]
+    fn test_set_param_limits() {
+        let long_key = [b'k'; MAX_KEY_SIZE + 1];
+        let long_key = core::str::from_utf8(&long_key).unwrap();
+        assert_eq!(SetParam::new(long_key, &[]), Err(Error::InvalidKey));
+        assert_eq!(SetParam::new("", &[]), Err(Error::InvalidKey));
+        assert_eq!(
+            SetParam::new("K", &[0; MAX_VALUE_SIZE + 1]),
+            Err(Error::ValueTooLong)
+        );
+        let max_key = [b'k'; MAX_KEY_SIZE];
+        let max_key = core::str::from_utf8(&max_key).unwrap();
+        let param = SetParam::new(max_key, &[1; MAX_VALUE_SIZE]).unwrap();
+        assert_eq!(param.encode().len(), MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert_eq!(SetParam::decode(&[]), Err(Error::InvalidPayload));
+        assert_eq!(SetParam::decode(&[5, b'a']), Err(Error::InvalidPayload));
+        assert_eq!(SetParam::decode(&[1, 0xFF]), Err(Error::InvalidKey));
+        assert_eq!(decode_get(b"VERSION"), Ok("VERSION"));
+    }
+
+    #[test]
+    fn test_param_list() {
+        let mut list = ParamListBuilder::new();
+        assert!(list.push("VERSION"));
+        assert!(list.push("BRIGHTNESS"));
+        let Packet::Response(payload) = list.finish() else {
+            panic!("expected Response");
+        };
+        let mut keys = param_list(&payload);
+        assert_eq!(keys.next(), Some("VERSION"));
+        assert_eq!(keys.next(), Some("BRIGHTNESS"));
+        assert_eq!(keys.next(), None);
+    }
+
+    #[test]
+    fn test_param_list_full() {
+        let mut list = ParamListBuilder::new();
+        let key = [b'k'; MAX_KEY_SIZE];
+        let key = core::str::from_utf8(&key).unwrap();
+        let mut pushed = 0;
+        while list.push(key) {
+            pushed += 1;
+        }
+        assert_eq!(pushed, (MAX_PAYLOAD_SIZE + 1) / (MAX_KEY_SIZE + 1));
+    }
+}
@@ -1,6 +1,9 @@
 use crate::commands::{
-    Command, Header, Packet, PayloadBuf, HEADER_SIZE, MAX_PACKET_SIZE, SYNC_BYTE, VERSION,
+    Channel, Command, ErrorPayload, Frame, Header, Packet, PayloadBuf, HEADER_SIZE,
+    MAX_PACKET_SIZE, SYNC_BYTE, VERSION,
 };
+use crate::region::RefreshKind;
+use fifo::{Consumer, Fifo};
 
 #[cfg(feature = "std")]
 use thiserror::Error;
@@ -18,13 +21,19 @@ pub enum Error {
     InCompleteHeader,
     #[cfg_attr(feature = "std", error("payload too large"))]
     PayloadTooBig,
+    #[cfg_attr(feature = "std", error("invalid payload for command"))]
+    InvalidPayload,
+    #[cfg_attr(feature = "std", error("invalid channel"))]
+    InvalidChannel,
+    #[cfg_attr(feature = "std", error("invalid command"))]
+    InvalidCommand,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum Status {
     WaitingForSync,
     WaitingForHeader,
-    WaitingForPayload(Command, usize), //command, and payload length
+    WaitingForPayload(Channel, Command, usize), //channel, command, and payload length
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -55,6 +64,11 @@ impl Parser {
     ///     as such packets should be used or the buffer they reference copied before pushing more
     ///     bytes to the parser
     pub fn parse(&mut self, buffer: &[u8]) -> Result<Packet, Error> {
+        self.parse_frame(buffer).map(|frame| frame.packet)
+    }
+
+    /// Same as `parse` but keeps the channel the packet arrived on
+    pub fn parse_frame(&mut self, buffer: &[u8]) -> Result<Frame, Error> {
         for b in buffer {
             //out of space
             if self.buffer_pos >= self.buffer.len() {
@@ -75,60 +89,152 @@ impl Parser {
                 }
                 Status::WaitingForHeader => {
                     if self.buffer_pos == HEADER_SIZE {
-                        let header: &Header = buffer_to_struct(&self.buffer[..HEADER_SIZE]);
-                        if header.version == VERSION {
-                            self.status = Status::WaitingForPayload(
-                                header.command,
-                                header.payload_length as usize,
-                            );
-                        } else {
-                            return Err(Error::InvalidVersion);
+                        let header = match decode_header(&self.buffer[..HEADER_SIZE]) {
+                            Ok(header) => header,
+                            Err(e) => {
+                                self.reset();
+                                return Err(e);
+                            }
+                        };
+                        let payload_len = header.payload_length as usize;
+                        if payload_len == 0 {
+                            //nothing more to wait for
+                            self.reset();
+                            return parse_command(header.command, &[])
+                                .map(|packet| Frame::new(header.channel, packet));
                         }
+                        self.status =
+                            Status::WaitingForPayload(header.channel, header.command, payload_len);
                     }
                 }
-                Status::WaitingForPayload(command, payload_len) => {
+                Status::WaitingForPayload(channel, command, payload_len) => {
                     if self.buffer_pos == HEADER_SIZE + payload_len {
                         self.reset();
-                        return Ok(parse_command(
+                        return parse_command(
                             command,
                             &self.buffer[HEADER_SIZE..HEADER_SIZE + payload_len],
-                        ));
+                        )
+                        .map(|packet| Frame::new(channel, packet));
                     }
                 }
             }
         }
 
+        self.pending()
+    }
+
+    /// Reads from `fifo` until a packet is complete, bytes after it are left in the fifo
+    /// Running out of bytes mid packet returns the same errors as `parse`, the partial packet
+    /// is kept and finished by the next call. While waiting for sync everything before the
+    /// next sync byte is dropped in one go instead of a byte at a time
+    pub fn parse_fifo(&mut self, fifo: &mut Fifo<u8>) -> Result<Packet, Error> {
+        self.parse_next(|status| {
+            if let Status::WaitingForSync = status {
+                fifo.skip_until(SYNC_BYTE);
+            }
+            fifo.read()
+        })
+        .map(|frame| frame.packet)
+    }
+
+    /// Same as `parse_fifo` for the consumer half of a split fifo
+    pub fn parse_queue(&mut self, consumer: &mut Consumer<u8>) -> Result<Packet, Error> {
+        self.parse_queue_frame(consumer).map(|frame| frame.packet)
+    }
+
+    /// Same as `parse_queue` but keeps the channel the packet arrived on
+    pub fn parse_queue_frame(&mut self, consumer: &mut Consumer<u8>) -> Result<Frame, Error> {
+        self.parse_next(|status| {
+            if let Status::WaitingForSync = status {
+                consumer.skip_until(SYNC_BYTE);
+            }
+            consumer.read()
+        })
+    }
+
+    /// `next` is handed the current status so it can skip ahead while waiting for sync
+    fn parse_next(&mut self, mut next: impl FnMut(Status) -> Option<u8>) -> Result<Frame, Error> {
+        while let Some(byte) = next(self.status) {
+            match self.parse_frame(&[byte]) {
+                Err(Error::NoSyncByte | Error::InCompleteHeader | Error::InCompletePayload) => {}
+                result => return result,
+            }
+        }
+        self.pending()
+    }
+
+    /// Error for the packet still being waited on
+    fn pending(&self) -> Result<Frame, Error> {
         match self.status {
             Status::WaitingForSync => Err(Error::NoSyncByte),
             Status::WaitingForHeader => Err(Error::InCompleteHeader),
-            Status::WaitingForPayload(_, _) => Err(Error::InCompletePayload),
+            Status::WaitingForPayload(_, _, _) => Err(Error::InCompletePayload),
         }
     }
 }
 
-fn parse_command<'a>(command: Command, payload: &'a [u8]) -> Packet {
-    //FIXME: Is panic the best move here ?
-    let vec = PayloadBuf::from_slice(payload).unwrap();
-    match command {
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_command(command: Command, payload: &[u8]) -> Result<Packet, Error> {
+    let vec = PayloadBuf::from_slice(payload).map_err(|_| Error::PayloadTooBig)?;
+    Ok(match command {
         Command::Echo => Packet::Echo(vec),
         Command::GetParamList => Packet::GetParamList,
         Command::SetParam => Packet::SetParam(vec),
         Command::GetParam => Packet::GetParam(vec),
         Command::Response => Packet::Response(vec),
-        Command::Error => Packet::Error(vec),
-    }
+        Command::Error => Packet::Error(ErrorPayload::decode(payload)),
+        Command::CreditRequest => Packet::CreditRequest,
+        Command::Credit => {
+            let credit = payload.try_into().map_err(|_| Error::InvalidPayload)?;
+            Packet::Credit(u16::from_le_bytes(credit))
+        }
+        Command::CreditAdvertise => {
+            let credit = payload.try_into().map_err(|_| Error::InvalidPayload)?;
+            Packet::CreditAdvertise(u16::from_le_bytes(credit))
+        }
+        Command::ListPages => Packet::ListPages,
+        Command::ShowPage => match payload {
+            [page] => Packet::ShowPage(*page),
+            _ => return Err(Error::InvalidPayload),
+        },
+        Command::SetPlaylist => Packet::SetPlaylist(vec),
+        Command::GetCurrentPage => Packet::GetCurrentPage,
+        Command::UpdateRegion => Packet::UpdateRegion(vec),
+        Command::Refresh => match payload {
+            [kind] => {
+                Packet::Refresh(RefreshKind::try_from(*kind).map_err(|_| Error::InvalidPayload)?)
+            }
+            _ => return Err(Error::InvalidPayload),
+        },
+    })
 }
 
-fn buffer_to_struct<'a, S>(buffer: &'a [u8]) -> &'a S {
-    let (head, body, _tail) = unsafe { buffer.align_to::<S>() };
-    assert!(head.is_empty(), "Error Casting buf to struct");
-    &body[0]
+/// Checks every field before building the header, the raw bytes may not be a valid enum
+fn decode_header(buffer: &[u8]) -> Result<Header, Error> {
+    let [sync, version, channel, command, payload_length] = buffer else {
+        return Err(Error::InCompleteHeader);
+    };
+    if *version != VERSION {
+        return Err(Error::InvalidVersion);
+    }
+    Ok(Header {
+        sync: *sync,
+        version: *version,
+        channel: Channel::try_from(*channel).map_err(|_| Error::InvalidChannel)?,
+        command: Command::try_from(*command).map_err(|_| Error::InvalidCommand)?,
+        payload_length: *payload_length,
+    })
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::commands::Command;
+    use crate::commands::{Channel, Command, ErrorCode};
     use crate::commands::{SYNC_BYTE, VERSION};
 
     use super::*;
@@ -138,6 +244,7 @@ mod tests {
         let buffer = [
             SYNC_BYTE,
             VERSION,
+            Channel::Control as u8,
             Command::Echo as u8,
             5,
             b'h',
@@ -154,12 +261,29 @@ mod tests {
 
     #[test]
     pub fn test_multi_parse() {
-        let buffer = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'h', b'i'];
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
         let mut parser = Parser::new();
         let output = parser.parse(&buffer).unwrap();
         let want = Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap());
         assert_eq!(output, want);
-        let buffer2 = [SYNC_BYTE, VERSION, Command::Echo as u8, 3, b'b', b'y', b'e'];
+        let buffer2 = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            3,
+            b'b',
+            b'y',
+            b'e',
+        ];
         let output2 = parser.parse(&buffer2).unwrap();
         let want = Packet::Echo(PayloadBuf::from_slice(b"bye").unwrap());
         assert_eq!(output2, want);
@@ -172,6 +296,7 @@ mod tests {
             b'h',
             SYNC_BYTE,
             VERSION,
+            Channel::Control as u8,
             Command::Echo as u8,
             5,
             b'h',
@@ -186,6 +311,144 @@ mod tests {
         assert_eq!(output, want);
     }
 
+    #[test]
+    pub fn test_parse_error() {
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Error as u8,
+            3,
+            ErrorCode::UnknownParam as u8,
+            b'F',
+            b'O',
+        ];
+        let mut parser = Parser::new();
+        let output = parser.parse(&buffer).unwrap();
+        let want = Packet::Error(ErrorPayload::with_detail(ErrorCode::UnknownParam, "FO"));
+        assert_eq!(output, want);
+    }
+
+    #[test]
+    pub fn test_parse_credit() {
+        let mut parser = Parser::new();
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Credit as u8,
+            2,
+            0x00,
+            0x01,
+        ];
+        assert_eq!(parser.parse(&buffer), Ok(Packet::Credit(256)));
+
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Credit as u8,
+            1,
+            0x00,
+        ];
+        assert_eq!(parser.parse(&buffer), Err(Error::InvalidPayload));
+        let advertise = Packet::CreditAdvertise(80);
+        assert_eq!(parser.parse(&advertise.clone().serialize()), Ok(advertise));
+    }
+
+    #[test]
+    pub fn test_parse_show_page() {
+        let mut parser = Parser::new();
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::ShowPage as u8,
+            1,
+            3,
+        ];
+        assert_eq!(parser.parse(&buffer), Ok(Packet::ShowPage(3)));
+        assert_eq!(
+            parser.parse(&Packet::GetCurrentPage.serialize()),
+            Ok(Packet::GetCurrentPage)
+        );
+    }
+
+    #[test]
+    pub fn test_parse_refresh() {
+        let mut parser = Parser::new();
+        let buffer = Packet::Refresh(RefreshKind::Full).serialize();
+        assert_eq!(
+            parser.parse(&buffer),
+            Ok(Packet::Refresh(RefreshKind::Full))
+        );
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Refresh as u8,
+            1,
+            7,
+        ];
+        assert_eq!(parser.parse(&buffer), Err(Error::InvalidPayload));
+    }
+
+    #[test]
+    pub fn test_parse_empty_payload() {
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::GetParamList as u8,
+            0,
+        ];
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse(&buffer), Ok(Packet::GetParamList));
+        assert_eq!(parser.parse(&buffer), Ok(Packet::GetParamList));
+    }
+
+    #[test]
+    pub fn test_parse_frame_channel() {
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Bulk as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
+        let mut parser = Parser::new();
+        let output = parser.parse_frame(&buffer).unwrap();
+        assert_eq!(output.channel, Channel::Bulk);
+        assert_eq!(
+            output.packet,
+            Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap())
+        );
+    }
+
+    #[test]
+    pub fn test_invalid_channel_and_command() {
+        let mut parser = Parser::new();
+        let buffer = [SYNC_BYTE, VERSION, 9, Command::Echo as u8, 0];
+        assert_eq!(parser.parse(&buffer), Err(Error::InvalidChannel));
+        let buffer = [SYNC_BYTE, VERSION, Channel::Control as u8, 0xEE, 0];
+        assert_eq!(parser.parse(&buffer), Err(Error::InvalidCommand));
+        //parser recovers after a bad header
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            1,
+            b'a',
+        ];
+        assert_eq!(
+            parser.parse(&buffer),
+            Ok(Packet::Echo(PayloadBuf::from_slice(b"a").unwrap()))
+        );
+    }
+
     #[test]
     pub fn test_partial_header() {
         let buffer = [SYNC_BYTE, VERSION];
@@ -193,18 +456,24 @@ mod tests {
         if let Err(output) = parser.parse(&buffer) {
             assert_eq!(output, Error::InCompleteHeader);
         } else {
-            assert!(false, "got ok but expected error")
+            panic!("got ok but expected error")
         }
     }
 
     #[test]
     pub fn test_wrong_version() {
-        let buffer = [SYNC_BYTE, VERSION + 1, Command::Echo as u8, 0];
+        let buffer = [
+            SYNC_BYTE,
+            VERSION + 1,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            0,
+        ];
         let mut parser = Parser::new();
         if let Err(output) = parser.parse(&buffer) {
             assert_eq!(output, Error::InvalidVersion);
         } else {
-            assert!(false, "got ok but expected error")
+            panic!("got ok but expected error")
         }
     }
 
@@ -215,103 +484,221 @@ mod tests {
         if let Err(output) = parser.parse(&buffer) {
             assert_eq!(output, Error::NoSyncByte);
         } else {
-            assert!(false, "got ok but expected error")
+            panic!("got ok but expected error")
+        }
+    }
+
+    #[test]
+    pub fn test_full_fifo() {
+        let mut fifo_buf = [0u8; 255];
+        let mut fifo = Fifo::new(&mut fifo_buf);
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
+        fifo.write(&buffer).unwrap();
+        let mut parser = Parser::new();
+        let output = parser.parse_fifo(&mut fifo).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap()));
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    pub fn test_fifo_resync() {
+        let mut fifo_buf = [0u8; 64];
+        let mut fifo = Fifo::new(&mut fifo_buf);
+        fifo.write(&[0x55; 20]).unwrap();
+        //wrong version, the parser drops the header and looks for sync again
+        fifo.write(&[SYNC_BYTE, VERSION + 1, 0, 0, 0, 7, 7, 7])
+            .unwrap();
+        fifo.write(&[
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            1,
+            b'!',
+        ])
+        .unwrap();
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse_fifo(&mut fifo), Err(Error::InvalidVersion));
+        let output = parser.parse_fifo(&mut fifo).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"!").unwrap()));
+        assert!(fifo.is_empty());
+
+        fifo.write(&[1, 2, 3]).unwrap();
+        assert_eq!(parser.parse_fifo(&mut fifo), Err(Error::NoSyncByte));
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    pub fn test_queue_resync() {
+        let mut queue_buf = [0u8; 64];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        producer.write(&[0x55; 20]).unwrap();
+        producer
+            .write(&[SYNC_BYTE, VERSION + 1, 0, 0, 0, 7, 7, 7])
+            .unwrap();
+        producer
+            .write(&[
+                SYNC_BYTE,
+                VERSION,
+                Channel::Control as u8,
+                Command::Echo as u8,
+                1,
+                b'!',
+            ])
+            .unwrap();
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse_queue(&mut consumer),
+            Err(Error::InvalidVersion)
+        );
+        let output = parser.parse_queue(&mut consumer).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"!").unwrap()));
+        assert!(consumer.is_empty());
+
+        producer.write(&[1, 2, 3]).unwrap();
+        assert_eq!(parser.parse_queue(&mut consumer), Err(Error::NoSyncByte));
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    pub fn test_full_queue() {
+        const SIZE: usize = MAX_PACKET_SIZE + 1;
+        let mut queue_buf = [0u8; SIZE];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
+        for b in buffer {
+            producer.push(b).unwrap();
         }
+
+        let mut parser = Parser::new();
+        let output = parser.parse_queue(&mut consumer).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap()));
+    }
+
+    #[test]
+    pub fn test_double_parse_queue() {
+        const SIZE: usize = MAX_PACKET_SIZE + 1;
+        let mut queue_buf = [0u8; SIZE];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
+        producer.write(&buffer).unwrap();
+        let buffer = [
+            SYNC_BYTE,
+            VERSION,
+            Channel::Event as u8,
+            Command::Echo as u8,
+            3,
+            b'b',
+            b'y',
+            b'e',
+        ];
+        producer.write(&buffer).unwrap();
+
+        //only the first packet is consumed
+        let mut parser = Parser::new();
+        let output = parser.parse_queue(&mut consumer).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap()));
+        assert_eq!(consumer.len(), buffer.len());
+
+        let output = parser.parse_queue_frame(&mut consumer).unwrap();
+        assert_eq!(
+            output,
+            Frame::new(
+                Channel::Event,
+                Packet::Echo(PayloadBuf::from_slice(b"bye").unwrap())
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_off_center_queue() {
+        const SIZE: usize = 1024;
+        let mut queue_buf = [0u8; SIZE];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        let buffer = [
+            11,
+            11,
+            SYNC_BYTE,
+            VERSION,
+            Channel::Control as u8,
+            Command::Echo as u8,
+            2,
+            b'h',
+            b'i',
+        ];
+        for b in buffer {
+            producer.push(b).unwrap();
+        }
+
+        let mut parser = Parser::new();
+        let output = parser.parse_queue(&mut consumer).unwrap();
+        assert_eq!(output, Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap()));
     }
 
-    // #[test]
-    // pub fn test_full_fifo() {
-    //     let mut fifo_buf = [0u8; 255];
-    //     let mut scratch = [0u8; 255];
-    //     let mut fifo = Fifo::new(&mut fifo_buf);
-    //     let buffer = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'h', b'i'];
-    //     fifo.write(&buffer).unwrap();
-    //     let parser = Parser::new();
-    //     let output = parser.parse_fifo(&mut scratch, &mut fifo).unwrap();
-    //     assert_eq!(output, Packet::Echo(b"hi"));
-    // }
-    //
-    // #[test]
-    // pub fn test_full_queue() {
-    //     const SIZE: usize = MAX_PACKET_SIZE + 1;
-    //     let mut scratch = [0u8; MAX_PACKET_SIZE];
-    //     let mut queue: Queue<u8, SIZE> = Queue::new();
-    //     let (mut producer, mut consumer) = queue.split();
-    //     let buffer = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'h', b'i'];
-    //     for b in buffer {
-    //         producer.enqueue(b).unwrap();
-    //     }
-    //
-    //     let mut parser = Parser::new();
-    //     let output = parser.parse_queue(&mut scratch, &mut consumer).unwrap();
-    //     assert_eq!(output, Packet::Echo(b"hi"));
-    // }
-    //
-    // #[test]
-    // pub fn test_double_parse_queue() {
-    //     const SIZE: usize = MAX_PACKET_SIZE + 1;
-    //     let mut scratch = [0u8; MAX_PACKET_SIZE];
-    //     let mut queue: Queue<u8, SIZE> = Queue::new();
-    //     let (mut producer, mut consumer) = queue.split();
-    //     let buffer = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'h', b'i'];
-    //     for b in buffer {
-    //         producer.enqueue(b).unwrap();
-    //     }
-    //
-    //     let mut parser = Parser::new();
-    //     let output = parser.parse_queue(&mut scratch, &mut consumer).unwrap();
-    //     assert_eq!(output, Packet::Echo(b"hi"));
-    //
-    //     let buffer = [SYNC_BYTE, VERSION, Command::Echo as u8, 2, b'b', b'y', b'e'];
-    //     for b in buffer {
-    //         producer.enqueue(b).unwrap();
-    //     }
-    //
-    //     let output = parser.parse_queue(&mut scratch, &mut consumer).unwrap();
-    //     assert_eq!(output, Packet::Echo(b"hi"));
-    // }
-    //
-    // #[test]
-    // pub fn test_off_center_queue() {
-    //     const SIZE: usize = 1024;
-    //     let mut scratch = [0u8; MAX_PACKET_SIZE];
-    //     let mut queue: Queue<u8, SIZE> = Queue::new();
-    //     let (mut producer, mut consumer) = queue.split();
-    //     let buffer = [
-    //         11,
-    //         11,
-    //         SYNC_BYTE,
-    //         VERSION,
-    //         Command::Echo as u8,
-    //         2,
-    //         b'h',
-    //         b'i',
-    //     ];
-    //     for b in buffer {
-    //         producer.enqueue(b).unwrap();
-    //     }
-    //
-    //     let mut parser = Parser::new();
-    //     let output = parser.parse_queue(&mut scratch, &mut consumer).unwrap();
-    //     assert_eq!(output, Packet::Echo(b"hi"));
-    // }
-    //
-    // #[test]
-    // pub fn test_no_sync_queue() {
-    //     const SIZE: usize = 1024;
-    //     let mut scratch = [0u8; MAX_PACKET_SIZE];
-    //     let mut queue: Queue<u8, SIZE> = Queue::new();
-    //     let (mut producer, mut consumer) = queue.split();
-    //     let buffer = [VERSION, Command::Echo as u8, 2, b'h', b'i'];
-    //     for b in buffer {
-    //         producer.enqueue(b).unwrap();
-    //     }
-    //
-    //     let mut parser = Parser::new();
-    //     if let Err(output) = parser.parse_queue(&mut scratch, &mut consumer) {
-    //         assert_eq!(output, Error::NoSyncByte);
-    //     } else {
-    //         assert!(false, "Expected Error but got Ok");
-    //     }
-    // }
+    #[test]
+    pub fn test_split_across_queue_reads() {
+        let mut queue_buf = [0u8; 64];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        let mut parser = Parser::new();
+        producer
+            .write(&[SYNC_BYTE, VERSION, Channel::Control as u8])
+            .unwrap();
+        assert_eq!(
+            parser.parse_queue(&mut consumer),
+            Err(Error::InCompleteHeader)
+        );
+        producer.write(&[Command::Echo as u8, 1, b'x']).unwrap();
+        assert_eq!(
+            parser.parse_queue(&mut consumer),
+            Ok(Packet::Echo(PayloadBuf::from_slice(b"x").unwrap()))
+        );
+    }
+
+    #[test]
+    pub fn test_no_sync_queue() {
+        const SIZE: usize = 1024;
+        let mut queue_buf = [0u8; SIZE];
+        let mut queue = Fifo::new(&mut queue_buf);
+        let (mut producer, mut consumer) = queue.split();
+        let buffer = [VERSION, Command::Echo as u8, 2, b'h', b'i'];
+        for b in buffer {
+            producer.push(b).unwrap();
+        }
+
+        let mut parser = Parser::new();
+        if let Err(output) = parser.parse_queue(&mut consumer) {
+            assert_eq!(output, Error::NoSyncByte);
+        } else {
+            panic!("Expected Error but got Ok");
+        }
+    }
 }
@@ -0,0 +1,378 @@
+//! Region updates and refresh control for e-paper displays
+//!
+//! UpdateRegion: x, y, width, height (u16 LE each), then 1 bit per pixel rows, MSB first,
+//! each row padded to a whole byte
+//! Refresh: `RefreshKind` (1 byte), answered with a one byte Response holding the kind the
+//! device actually did
+
+use crate::commands::{Packet, PayloadBuf, MAX_PAYLOAD_SIZE};
+
+#[cfg(feature = "std")]
+use thiserror::Error;
+
+pub const RECT_SIZE: usize = 8;
+/// Pixel bytes that fit in one UpdateRegion next to the rect
+pub const MAX_REGION_DATA_SIZE: usize = MAX_PAYLOAD_SIZE - RECT_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Error))]
+pub enum Error {
+    #[cfg_attr(feature = "std", error("pixel data doesn't match the region size"))]
+    SizeMismatch,
+    #[cfg_attr(feature = "std", error("a single row doesn't fit in a packet"))]
+    TooWide,
+    #[cfg_attr(feature = "std", error("malformed region payload"))]
+    InvalidPayload,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub const fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn area(&self) -> u32 {
+        self.width as u32 * self.height as u32
+    }
+
+    /// One past the right edge
+    pub fn right(&self) -> u32 {
+        self.x as u32 + self.width as u32
+    }
+
+    /// One past the bottom edge
+    pub fn bottom(&self) -> u32 {
+        self.y as u32 + self.height as u32
+    }
+
+    /// True if the rects overlap or share an edge, touching rects merge without growing
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.x as u32 <= other.right()
+            && other.x as u32 <= self.right()
+            && self.y as u32 <= other.bottom()
+            && other.y as u32 <= self.bottom()
+    }
+
+    /// Smallest rect covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x as u32) as u16, (bottom - y as u32) as u16)
+    }
+
+    pub fn encode(&self, buf: &mut [u8; RECT_SIZE]) {
+        buf[0..2].copy_from_slice(&self.x.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.y.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.width.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.height.to_le_bytes());
+    }
+
+    pub fn decode(buf: &[u8; RECT_SIZE]) -> Self {
+        Self::new(
+            u16::from_le_bytes([buf[0], buf[1]]),
+            u16::from_le_bytes([buf[2], buf[3]]),
+            u16::from_le_bytes([buf[4], buf[5]]),
+            u16::from_le_bytes([buf[6], buf[7]]),
+        )
+    }
+
+    /// Bytes per row of 1 bit pixel data
+    pub fn stride(&self) -> usize {
+        (self.width as usize).div_ceil(8)
+    }
+}
+
+/// Rects that changed since the last refresh, overlapping rects are merged as they're added
+/// Once `N` separate rects are tracked everything collapses into one bounding box
+#[derive(Debug, Default, Clone)]
+pub struct DirtyRegion<const N: usize> {
+    rects: heapless::Vec<Rect, N>,
+}
+
+impl<const N: usize> DirtyRegion<N> {
+    pub const fn new() -> Self {
+        Self {
+            rects: heapless::Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        let mut rect = rect;
+        //merging can make the rect touch ones it didn't before, keep going until it settles
+        while let Some(i) = self.rects.iter().position(|r| r.touches(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(i));
+        }
+        if let Err(rect) = self.rects.push(rect) {
+            let bounds = self.rects.iter().fold(rect, |acc, r| acc.union(r));
+            self.rects.clear();
+            //just cleared, always fits
+            _ = self.rects.push(bounds);
+        }
+    }
+
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    pub fn bounding_box(&self) -> Option<Rect> {
+        self.rects.iter().copied().reduce(|acc, r| acc.union(&r))
+    }
+
+    /// Dirty pixels, merged rects don't overlap so this is exact
+    pub fn area(&self) -> u32 {
+        self.rects.iter().map(Rect::area).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum RefreshKind {
+    Partial,
+    Full,
+}
+
+impl TryFrom<u8> for RefreshKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        match value {
+            0 => Ok(RefreshKind::Partial),
+            1 => Ok(RefreshKind::Full),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Turns partial refresh requests into a full refresh every `full_every` partials, partial
+/// refreshes leave ghosting that only a full refresh clears
+#[derive(Debug, Clone)]
+pub struct RefreshPolicy {
+    full_every: u16,
+    partials: u16,
+}
+
+impl RefreshPolicy {
+    /// `full_every` of 0 makes every refresh a full one
+    pub const fn new(full_every: u16) -> Self {
+        Self {
+            full_every,
+            partials: 0,
+        }
+    }
+
+    /// Partial refreshes done since the last full refresh
+    pub fn partials(&self) -> u16 {
+        self.partials
+    }
+
+    /// The refresh that should actually be done for a request
+    pub fn next(&mut self, requested: RefreshKind) -> RefreshKind {
+        if requested == RefreshKind::Full || self.partials >= self.full_every {
+            self.partials = 0;
+            return RefreshKind::Full;
+        }
+        self.partials += 1;
+        RefreshKind::Partial
+    }
+}
+
+/// Pixels for one rect of the screen
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RegionUpdate<'a> {
+    pub rect: Rect,
+    pub pixels: &'a [u8],
+}
+
+impl<'a> RegionUpdate<'a> {
+    pub fn new(rect: Rect, pixels: &'a [u8]) -> Result<Self, Error> {
+        if rect.stride() * rect.height as usize != pixels.len() {
+            return Err(Error::SizeMismatch);
+        }
+        Ok(Self { rect, pixels })
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Result<Self, Error> {
+        let Some((rect, pixels)) = payload.split_first_chunk::<RECT_SIZE>() else {
+            return Err(Error::InvalidPayload);
+        };
+        Self::new(Rect::decode(rect), pixels)
+    }
+
+    /// Fails if the pixels don't fit in one packet, see `packets` to split them up
+    pub fn encode(&self) -> Result<PayloadBuf, Error> {
+        if self.pixels.len() > MAX_REGION_DATA_SIZE {
+            return Err(Error::TooWide);
+        }
+        let mut rect = [0; RECT_SIZE];
+        self.rect.encode(&mut rect);
+        let mut buf = PayloadBuf::new();
+        //size is checked above, this always fits
+        _ = buf.extend_from_slice(&rect);
+        _ = buf.extend_from_slice(self.pixels);
+        Ok(buf)
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> bool {
+        let byte = self.pixels[y as usize * self.rect.stride() + x as usize / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Every pixel as absolute (x, y, on)
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16, bool)> + '_ {
+        (0..self.rect.height).flat_map(move |y| {
+            (0..self.rect.width).map(move |x| (self.rect.x + x, self.rect.y + y, self.pixel(x, y)))
+        })
+    }
+
+    /// UpdateRegion packets for this update, split into bands of whole rows
+    pub fn packets(&self) -> Result<impl Iterator<Item = Packet> + 'a, Error> {
+        let stride = self.rect.stride();
+        if stride > MAX_REGION_DATA_SIZE {
+            return Err(Error::TooWide);
+        }
+        let rows = (MAX_REGION_DATA_SIZE / stride.max(1)) as u16;
+        let rect = self.rect;
+        let pixels = self.pixels;
+        Ok((0..rect.height).step_by(rows as usize).map(move |row| {
+            let height = rows.min(rect.height - row);
+            let start = row as usize * stride;
+            let band = RegionUpdate {
+                rect: Rect::new(rect.x, rect.y + row, rect.width, height),
+                pixels: &pixels[start..start + height as usize * stride],
+            };
+            //bands are sized to fit above
+            Packet::UpdateRegion(band.encode().unwrap())
+        }))
+    }
+}
+
+impl Packet {
+    pub fn refresh_done(kind: RefreshKind) -> Packet {
+        Packet::Response(PayloadBuf::from_slice(&[kind as u8]).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_union() {
+        let a = Rect::new(0, 0, 10, 10);
+        let b = Rect::new(20, 5, 5, 10);
+        assert_eq!(a.union(&b), Rect::new(0, 0, 25, 15));
+        assert_eq!(a.union(&Rect::default()), a);
+        assert!(!a.touches(&b));
+        assert!(a.touches(&Rect::new(10, 0, 1, 1)));
+    }
+
+    #[test]
+    fn test_dirty_region_merges() {
+        let mut dirty = DirtyRegion::<4>::new();
+        dirty.add(Rect::new(0, 0, 10, 10));
+        dirty.add(Rect::new(50, 50, 10, 10));
+        assert_eq!(dirty.rects().len(), 2);
+        //bridges the two
+        dirty.add(Rect::new(5, 5, 50, 50));
+        assert_eq!(dirty.rects(), &[Rect::new(0, 0, 60, 60)]);
+        dirty.add(Rect::new(100, 0, 0, 10));
+        assert_eq!(dirty.rects().len(), 1);
+    }
+
+    #[test]
+    fn test_dirty_region_full() {
+        let mut dirty = DirtyRegion::<2>::new();
+        dirty.add(Rect::new(0, 0, 1, 1));
+        dirty.add(Rect::new(10, 10, 1, 1));
+        dirty.add(Rect::new(20, 20, 1, 1));
+        assert_eq!(dirty.rects(), &[Rect::new(0, 0, 21, 21)]);
+        assert_eq!(dirty.bounding_box(), Some(Rect::new(0, 0, 21, 21)));
+        dirty.clear();
+        assert!(dirty.is_empty());
+        assert_eq!(dirty.bounding_box(), None);
+    }
+
+    #[test]
+    fn test_refresh_policy() {
+        let mut policy = RefreshPolicy::new(2);
+        assert_eq!(policy.next(RefreshKind::Partial), RefreshKind::Partial);
+        assert_eq!(policy.next(RefreshKind::Partial), RefreshKind::Partial);
+        assert_eq!(policy.next(RefreshKind::Partial), RefreshKind::Full);
+        assert_eq!(policy.next(RefreshKind::Partial), RefreshKind::Partial);
+        assert_eq!(policy.next(RefreshKind::Full), RefreshKind::Full);
+        assert_eq!(policy.partials(), 0);
+        let mut always_full = RefreshPolicy::new(0);
+        assert_eq!(always_full.next(RefreshKind::Partial), RefreshKind::Full);
+    }
+
+    #[test]
+    fn test_region_roundtrip() {
+        let pixels = [0b1000_0000, 0b0100_0000];
+        let update = RegionUpdate::new(Rect::new(3, 4, 2, 2), &pixels).unwrap();
+        let payload = update.encode().unwrap();
+        let decoded = RegionUpdate::decode(&payload).unwrap();
+        assert_eq!(decoded, update);
+        let on: heapless::Vec<_, 4> = decoded.iter().filter(|p| p.2).collect();
+        assert_eq!(&on[..], &[(3, 4, true), (4, 5, true)]);
+        assert_eq!(
+            RegionUpdate::new(Rect::new(0, 0, 9, 1), &[0]),
+            Err(Error::SizeMismatch)
+        );
+        assert_eq!(RegionUpdate::decode(&[0; 4]), Err(Error::InvalidPayload));
+    }
+
+    #[test]
+    fn test_region_packets() {
+        //250 wide screen is 32 bytes a row, 7 rows fit in a packet
+        let pixels = [0xAA; 32 * 16];
+        let update = RegionUpdate::new(Rect::new(0, 0, 250, 16), &pixels).unwrap();
+        let mut rows = 0;
+        for packet in update.packets().unwrap() {
+            let Packet::UpdateRegion(payload) = packet else {
+                panic!("expected UpdateRegion");
+            };
+            let band = RegionUpdate::decode(&payload).unwrap();
+            assert_eq!(band.rect.y, rows);
+            rows += band.rect.height;
+        }
+        assert_eq!(rows, 16);
+        let wide = [0; MAX_REGION_DATA_SIZE + 1];
+        let wide = RegionUpdate::new(Rect::new(0, 0, (wide.len() * 8) as u16, 1), &wide).unwrap();
+        assert!(wide.packets().is_err());
+    }
+}
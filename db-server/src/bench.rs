@@ -0,0 +1,319 @@
+//! Echo flood for measuring what the link can actually do
+//!
+//! For each payload size echoes are kept `window` deep in flight, each carrying its sequence
+//! number so answers can be matched up however late they are. Echoes not back `timeout` after
+//! they were sent are counted lost, freeing their place in the window, and credit is synced
+//! again in case the device never saw them.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use db_link::commands::{Packet, PayloadBuf, HEADER_SIZE, MAX_PAYLOAD_SIZE};
+
+use crate::{
+    device::Port,
+    link::{Link, LinkError},
+    render::{FrameSender, Framebuffer},
+};
+
+/// Echoes start with their sequence number, so none can be shorter
+pub const MIN_SIZE: usize = 4;
+pub const DEFAULT_SIZES: [usize; 5] = [8, 32, 64, 128, MAX_PAYLOAD_SIZE];
+/// How long a read waits before checking whether there's more to send
+const POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Payload sizes to run, clamped to what an echo can carry
+    pub sizes: Vec<usize>,
+    /// Echoes sent of each size
+    pub count: u32,
+    /// Echoes in flight at once, 1 measures plain round trips
+    pub window: usize,
+    /// How long echoes get to come back
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sizes: DEFAULT_SIZES.to_vec(),
+            count: 500,
+            window: 4,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SizeResult {
+    pub size: usize,
+    pub sent: u32,
+    pub received: u32,
+    pub lost: u32,
+    /// Came back with a different payload than was sent
+    pub corrupt: u32,
+    /// Anything that wasn't an answer to an echo in flight, including ones counted lost
+    pub unexpected: u32,
+    /// Frames the link's parser dropped
+    pub parse_errors: u64,
+    /// Bytes the link's parser dropped between frames
+    pub skipped: u64,
+    /// From the first echo sent to the last one back, waiting out lost ones isn't counted
+    pub elapsed: Duration,
+    /// Round trip of every echo that came back intact, shortest first
+    pub latencies: Vec<Duration>,
+}
+
+impl SizeResult {
+    /// Column names for the `Display` rows
+    pub const HEADER: &'static str =
+        " size  sent  recv  lost  bad   p50 ms   p90 ms   p99 ms   max ms    pkt/s  kB/s errors/skipped";
+
+    /// Nearest rank percentile of the round trips, `p` from 0 to 100
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.saturating_sub(1).min(last)])
+    }
+
+    pub fn packets_per_sec(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Bytes per second each way, frame headers included
+    pub fn wire_rate(&self) -> f64 {
+        self.packets_per_sec() * (HEADER_SIZE + self.size) as f64
+    }
+}
+
+impl fmt::Display for SizeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |p| match self.percentile(p) {
+            Some(latency) => format!("{:8.2}", latency.as_secs_f64() * 1000.0),
+            None => format!("{:>8}", "-"),
+        };
+        write!(
+            f,
+            "{:5} {:5} {:5} {:5} {:4} {} {} {} {} {:8.0} {:5.1} {:6}/{}",
+            self.size,
+            self.sent,
+            self.received,
+            self.lost,
+            self.corrupt + self.unexpected,
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(100.0),
+            self.packets_per_sec(),
+            self.wire_rate() / 1000.0,
+            self.parse_errors,
+            self.skipped,
+        )
+    }
+}
+
+/// Runs every size in turn on a link that's been through the handshake
+pub fn run<T: Port>(link: &mut Link<T>, config: &Config) -> Result<Vec<SizeResult>, LinkError> {
+    link.get_mut().set_timeout(POLL)?;
+    config
+        .sizes
+        .iter()
+        .map(|size| run_size(link, (*size).clamp(MIN_SIZE, MAX_PAYLOAD_SIZE), config))
+        .collect()
+}
+
+fn run_size<T: Port>(
+    link: &mut Link<T>,
+    size: usize,
+    config: &Config,
+) -> Result<SizeResult, LinkError> {
+    let mut result = SizeResult {
+        size,
+        ..Default::default()
+    };
+    let stats = link.stats();
+    let mut in_flight: HashMap<u32, Instant> = HashMap::new();
+    let start = Instant::now();
+    let mut last_echo = None;
+    loop {
+        if result.sent < config.count && in_flight.len() < config.window.max(1) {
+            match link.send(Packet::Echo(payload(result.sent, size))) {
+                Ok(()) => {
+                    in_flight.insert(result.sent, Instant::now());
+                    result.sent += 1;
+                    continue;
+                }
+                //out of credit with nothing left to bring it back
+                Err(e) if e.is_timeout() && in_flight.is_empty() => link.sync_credits()?,
+                //out of credit and nothing came back yet
+                Err(e) if e.is_timeout() => {}
+                Err(e) => return Err(e),
+            }
+        } else if in_flight.is_empty() {
+            break;
+        }
+
+        match link.recv_frame() {
+            Ok(frame) => {
+                let Packet::Echo(echoed) = frame.packet else {
+                    result.unexpected += 1;
+                    continue;
+                };
+                let Some((seq, sent)) = sequence(&echoed)
+                    .and_then(|seq| in_flight.remove(&seq).map(|sent| (seq, sent)))
+                else {
+                    result.unexpected += 1;
+                    continue;
+                };
+                if echoed == payload(seq, size) {
+                    result.received += 1;
+                    result.latencies.push(sent.elapsed());
+                    last_echo = Some(Instant::now());
+                } else {
+                    result.corrupt += 1;
+                }
+            }
+            Err(e) if e.is_timeout() => {}
+            //counted in the link stats
+            Err(LinkError::Protocol(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let waiting = in_flight.len();
+        in_flight.retain(|_, sent| sent.elapsed() < config.timeout);
+        if in_flight.len() < waiting {
+            result.lost += (waiting - in_flight.len()) as u32;
+            //the credit lost echoes took is never handed back
+            link.sync_credits()?;
+        }
+    }
+    result.elapsed = last_echo.unwrap_or(start) - start;
+    result.latencies.sort();
+    let after = link.stats();
+    result.parse_errors = after.parse_errors - stats.parse_errors;
+    result.skipped = after.skipped - stats.skipped;
+    Ok(result)
+}
+
+/// `seq` then a pattern that changes with it, so stale and mangled echoes both stand out
+fn payload(seq: u32, size: usize) -> PayloadBuf {
+    let mut payload = PayloadBuf::new();
+    //both fit, size is at most MAX_PAYLOAD_SIZE
+    _ = payload.extend_from_slice(&seq.to_le_bytes());
+    for i in MIN_SIZE..size {
+        _ = payload.push((i as u32).wrapping_add(seq) as u8);
+    }
+    payload
+}
+
+fn sequence(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        payload.get(..MIN_SIZE)?.try_into().ok()?,
+    ))
+}
+
+/// Bytes on the wire to redraw the whole screen, for turning a rate into frames per second
+pub fn screen_update_bytes() -> usize {
+    FrameSender::new()
+        .updates(&Framebuffer::screen())
+        .expect("the screen fits in region updates")
+        .into_iter()
+        .map(|packet| packet.serialize_vec().len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+
+    use super::*;
+    use crate::{device, virtual_device::VirtualDevice};
+
+    /// Virtual device that never sees every `nth` write after the first
+    struct Dropping {
+        device: VirtualDevice,
+        nth: usize,
+        writes: usize,
+    }
+
+    impl Read for Dropping {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.device.read(buf)
+        }
+    }
+
+    impl Write for Dropping {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.writes > 1 && (self.writes - 1).is_multiple_of(self.nth) {
+                return Ok(buf.len());
+            }
+            self.device.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.device.flush()
+        }
+    }
+
+    impl Port for Dropping {
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let result = SizeResult {
+            latencies: (1..=10).map(Duration::from_millis).collect(),
+            ..Default::default()
+        };
+        assert_eq!(result.percentile(50.0), Some(Duration::from_millis(5)));
+        assert_eq!(result.percentile(99.0), Some(Duration::from_millis(10)));
+        assert_eq!(result.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(SizeResult::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn test_virtual() {
+        let (mut link, _) =
+            device::handshake(VirtualDevice::new(), "virtual", Duration::from_secs(1)).unwrap();
+        let config = Config {
+            sizes: vec![0, MAX_PAYLOAD_SIZE],
+            count: 50,
+            ..Default::default()
+        };
+        let results = run(&mut link, &config).unwrap();
+        assert_eq!(results[0].size, MIN_SIZE);
+        for result in results {
+            assert_eq!((result.sent, result.received, result.lost), (50, 50, 0));
+            assert_eq!(result.corrupt + result.unexpected, 0);
+            assert_eq!(result.latencies.len(), 50);
+        }
+    }
+
+    #[test]
+    fn test_lost() {
+        let port = Dropping {
+            device: VirtualDevice::new(),
+            nth: 10,
+            writes: 0,
+        };
+        let (mut link, _) = device::handshake(port, "dropping", Duration::from_secs(1)).unwrap();
+        //the handshake's version request was write 2
+        let config = Config {
+            sizes: vec![16],
+            count: 20,
+            timeout: Duration::from_millis(250),
+            ..Default::default()
+        };
+        let result = run(&mut link, &config).unwrap().remove(0);
+        assert_eq!((result.sent, result.received, result.lost), (20, 18, 2));
+        //waiting out the lost echoes isn't part of the run
+        assert!(result.elapsed < config.timeout);
+    }
+}
@@ -0,0 +1,87 @@
+//! Talks to a running db-server over its control socket, for one-liners in scripts
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use db_server::control::{self, Client, Request};
+use serde_json::Value;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Control socket of the server, $XDG_RUNTIME_DIR/db-server.sock by default
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Device connection, page and message on screen
+    Status,
+    /// Show a notification
+    Notify {
+        title: String,
+        #[arg(default_value = "")]
+        body: String,
+        /// How long it stays up, the server decides when left out
+        #[arg(long)]
+        seconds: Option<u64>,
+    },
+    /// Show a message for a while
+    Text {
+        text: String,
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+    /// List the pages
+    Pages,
+    /// Switch to a page
+    Page { page: String },
+    /// Read or write device params
+    #[command(subcommand)]
+    Param(Param),
+}
+
+#[derive(Subcommand)]
+enum Param {
+    Get { key: String },
+    Set { key: String, value: String },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let request = match args.command {
+        Command::Status => Request::Status,
+        Command::Notify {
+            title,
+            body,
+            seconds,
+        } => Request::Notify {
+            title,
+            body,
+            seconds,
+        },
+        Command::Text { text, seconds } => Request::Text { text, seconds },
+        Command::Pages => Request::Pages,
+        Command::Page { page } => Request::Page { page },
+        Command::Param(Param::Get { key }) => Request::GetParam { key },
+        Command::Param(Param::Set { key, value }) => Request::SetParam { key, value },
+    };
+    let socket = args.socket.unwrap_or_else(control::default_socket);
+    let result = Client::connect(&socket)
+        .map_err(|e| format!("can't connect to {}: {e}", socket.display()))
+        .and_then(|mut client| client.request(&request).map_err(|e| e.to_string()));
+    match result {
+        //strings such as param values are printed bare so scripts can use them as they are
+        Ok(Value::Null) => {}
+        Ok(Value::String(value)) => println!("{value}"),
+        Ok(value) => println!("{value:#}"),
+        Err(e) => {
+            eprintln!("db-ctl: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
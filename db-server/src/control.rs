@@ -0,0 +1,361 @@
+//! Unix socket control protocol, what `db-ctl` talks to the daemon with
+//!
+//! One JSON `Request` per line, each answered with one JSON `Response` line. A connection can
+//! send any number of requests.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use db_link::{commands::Packet, params};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream as AsyncUnixStream},
+};
+
+use crate::{
+    device::{DeviceError, DeviceHandle, Status},
+    display::{Display, DisplayError, Message, NOTIFICATION_TIME},
+    link::LinkError,
+};
+
+const SOCKET_NAME: &str = "db-server.sock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Notify {
+        title: String,
+        #[serde(default)]
+        body: String,
+        seconds: Option<u64>,
+    },
+    Text {
+        text: String,
+        seconds: u64,
+    },
+    Pages,
+    Page {
+        page: String,
+    },
+    GetParam {
+        key: String,
+    },
+    SetParam {
+        key: String,
+        value: String,
+    },
+}
+
+/// `{"ok": value}` or `{"error": "what went wrong"}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("bad message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("server closed the connection")]
+    Closed,
+    #[error("{0}")]
+    Server(String),
+}
+
+/// Why a request failed, the socket sends it as text and the HTTP API picks a status from it
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error(transparent)]
+    Display(#[from] DisplayError),
+    #[error(transparent)]
+    Param(#[from] params::Error),
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+/// $XDG_RUNTIME_DIR/db-server.sock, or under /tmp without a runtime dir
+pub fn default_socket() -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").unwrap_or_else(|| "/tmp".into());
+    PathBuf::from(dir).join(SOCKET_NAME)
+}
+
+/// Binds `path`, replacing a socket left behind by a server that's gone
+/// Fails if a server is still answering on it. Must be called from inside a tokio runtime
+/// Only the owner may connect, /tmp is shared with every other user
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a server is already listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accepts connections until the listener fails
+pub async fn serve(
+    listener: UnixListener,
+    display: Display,
+    device: DeviceHandle,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let display = display.clone();
+        let device = device.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection(stream, display, device).await {
+                log::debug!("Control connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn connection(
+    stream: AsyncUnixStream,
+    display: Display,
+    device: DeviceHandle,
+) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => match handle(request, &display, &device).await {
+                Ok(value) => Response::Ok(value),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Err(e) => Response::Error(format!("bad request: {e}")),
+        };
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+/// Carries out `request`, shared with the HTTP API
+pub async fn handle(
+    request: Request,
+    display: &Display,
+    device: &DeviceHandle,
+) -> Result<Value, RequestError> {
+    match request {
+        Request::Status => Ok(status(display, device)),
+        Request::Notify {
+            title,
+            body,
+            seconds,
+        } => {
+            let message = Message {
+                title: Some(title),
+                body,
+            };
+            let time = seconds.map_or(NOTIFICATION_TIME, Duration::from_secs);
+            display.show_message(message, time);
+            Ok(Value::Null)
+        }
+        Request::Text { text, seconds } => {
+            let time = Duration::from_secs(seconds);
+            display.show_message(Message::text(&text), time);
+            Ok(Value::Null)
+        }
+        Request::Pages => Ok(json!({
+            "pages": display.pages(),
+            "current": display.current_page(),
+        })),
+        Request::Page { page } => {
+            display.show_page(&page)?;
+            Ok(Value::Null)
+        }
+        Request::GetParam { key } => get_param(device, &key).await,
+        Request::SetParam { key, value } => set_param(device, &key, &value).await,
+    }
+}
+
+/// Device connection, page and message on screen, shared with the MQTT bridge
+pub fn status(display: &Display, device: &DeviceHandle) -> Value {
+    let device = match device.status() {
+        Status::Connected(info) => json!({
+            "connected": true,
+            "port": info.port,
+            "version": info.version,
+        }),
+        Status::Disconnected => json!({ "connected": false }),
+    };
+    let message = display.message().map(|message| {
+        json!({
+            "title": message.title,
+            "body": message.body,
+        })
+    });
+    json!({
+        "device": device,
+        "page": display.current_page(),
+        "message": message,
+    })
+}
+
+async fn get_param(device: &DeviceHandle, key: &str) -> Result<Value, RequestError> {
+    match device.request(Packet::get_param(key)?).await? {
+        Packet::Response(value) => Ok(String::from_utf8_lossy(&value).into()),
+        packet => Err(DeviceError::from(LinkError::Unexpected(Box::new(packet))).into()),
+    }
+}
+
+async fn set_param(device: &DeviceHandle, key: &str, value: &str) -> Result<Value, RequestError> {
+    device
+        .request(Packet::set_param(key, value.as_bytes())?)
+        .await?;
+    Ok(Value::Null)
+}
+
+/// Blocking client, enough for one-liners in scripts
+pub struct Client {
+    stream: BufReader<UnixStream>,
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            stream: BufReader::new(UnixStream::connect(path)?),
+        })
+    }
+
+    /// Sends `request` and waits for the answer, errors from the server are `Server`
+    pub fn request(&mut self, request: &Request) -> Result<Value, ControlError> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line)?;
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(ControlError::Closed);
+        }
+        match serde_json::from_str(&line)? {
+            Response::Ok(value) => Ok(value),
+            Response::Error(e) => Err(ControlError::Server(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        device::{Daemon, DaemonConfig, VirtualConnector},
+        render::Framebuffer,
+    };
+
+    /// Server on a virtual device, the socket lives as long as the returned dir
+    fn start() -> (tempfile::TempDir, PathBuf, Display) {
+        let (daemon, device) = Daemon::new(VirtualConnector, DaemonConfig::default());
+        thread::spawn(move || daemon.run());
+        while !matches!(device.status(), Status::Connected(_)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let display = Display::new();
+        display.add_page("metrics", Framebuffer::screen());
+        display.add_page("weather", Framebuffer::screen());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SOCKET_NAME);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = {
+            let _runtime = runtime.enter();
+            bind(&path).unwrap()
+        };
+        let served = display.clone();
+        thread::spawn(move || runtime.block_on(serve(listener, served, device)));
+        (dir, path, display)
+    }
+
+    #[test]
+    fn test_requests() {
+        let (_dir, path, display) = start();
+        let mut client = Client::connect(&path).unwrap();
+        let request = Request::Page {
+            page: "weather".to_string(),
+        };
+        assert_eq!(client.request(&request).unwrap(), Value::Null);
+        assert_eq!(display.current_page().as_deref(), Some("weather"));
+
+        let request = Request::Notify {
+            title: "deploy done".to_string(),
+            body: String::new(),
+            seconds: None,
+        };
+        client.request(&request).unwrap();
+        let status = client.request(&Request::Status).unwrap();
+        assert_eq!(status["message"]["title"], "deploy done");
+        assert_eq!(status["device"]["connected"], true);
+
+        let request = Request::GetParam {
+            key: "VERSION".to_string(),
+        };
+        assert_eq!(
+            client.request(&request).unwrap(),
+            crate::virtual_device::VERSION
+        );
+        let request = Request::Page {
+            page: "clock".to_string(),
+        };
+        assert!(matches!(
+            client.request(&request),
+            Err(ControlError::Server(e)) if e == "no page named clock"
+        ));
+    }
+
+    #[test]
+    fn test_bind_in_use() {
+        let (_dir, path, _) = start();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _runtime = runtime.enter();
+        let err = bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        //a socket nobody is listening on is replaced
+        let stale = path.with_file_name("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+        assert!(bind(&stale).is_ok());
+        let mode = std::fs::metadata(&stale).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_request_json() {
+        let request: Request = serde_json::from_str(r#"{"cmd":"notify","title":"hi"}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Notify {
+                title: "hi".to_string(),
+                body: String::new(),
+                seconds: None
+            }
+        );
+        let response = serde_json::to_string(&Response::Error("nope".to_string())).unwrap();
+        assert_eq!(response, r#"{"error":"nope"}"#);
+    }
+}
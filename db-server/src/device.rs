@@ -0,0 +1,484 @@
+//! Keeps a device connected for as long as the server runs
+//!
+//! `Daemon` owns the link on its own thread. It finds a port with its `Connector`, checks there
+//! is a device on it with a handshake and serves requests from `DeviceHandle`s until the device
+//! goes away, then starts looking again.
+
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use db_link::commands::{Frame, Packet, PayloadBuf};
+use serialport::SerialPort;
+use thiserror::Error;
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TryRecvError},
+    oneshot, watch,
+};
+
+use crate::{
+    discovery::{self, UsbId},
+    link::{Link, LinkError},
+    virtual_device::VirtualDevice,
+};
+
+/// Requests that can wait on the daemon before senders block
+const REQUEST_QUEUE: usize = 16;
+/// Unsolicited frames kept for slow subscribers
+const EVENT_QUEUE: usize = 64;
+/// USB CDC ignores it but serial adapters need one
+const BAUD_RATE: u32 = 115_200;
+
+/// Byte stream to a device whose reads can time out
+pub trait Port: Read + Write {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Port for serialport::TTYPort {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self, timeout)?)
+    }
+}
+
+/// Reads time out right away
+impl Port for VirtualDevice {
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Finds ports that might have a device on them
+pub trait Connector {
+    type Port: Port;
+
+    /// Ports to try, in order
+    fn candidates(&mut self) -> Vec<String>;
+
+    fn open(&mut self, name: &str) -> io::Result<Self::Port>;
+}
+
+/// Serial ports of USB devices with a known id, or a single port given by the user
+pub struct SerialConnector {
+    path: Option<PathBuf>,
+    ids: Vec<UsbId>,
+}
+
+impl SerialConnector {
+    pub fn new(ids: Vec<UsbId>) -> Self {
+        Self { path: None, ids }
+    }
+
+    /// Only ever tries `path`
+    pub fn fixed(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            ids: vec![],
+        }
+    }
+}
+
+impl Connector for SerialConnector {
+    type Port = serialport::TTYPort;
+
+    fn candidates(&mut self) -> Vec<String> {
+        if let Some(path) = &self.path {
+            return vec![path.display().to_string()];
+        }
+        match discovery::scan_system(&self.ids) {
+            Ok(found) => found
+                .into_iter()
+                .map(|candidate| candidate.path.display().to_string())
+                .collect(),
+            Err(e) => {
+                log::warn!("Scanning for devices failed: {e}");
+                vec![]
+            }
+        }
+    }
+
+    fn open(&mut self, name: &str) -> io::Result<Self::Port> {
+        Ok(serialport::new(name, BAUD_RATE).open_native()?)
+    }
+}
+
+/// A new virtual device on every open, for running the server without hardware
+#[derive(Debug, Default)]
+pub struct VirtualConnector;
+
+impl Connector for VirtualConnector {
+    type Port = VirtualDevice;
+
+    fn candidates(&mut self) -> Vec<String> {
+        vec!["virtual".to_string()]
+    }
+
+    fn open(&mut self, _name: &str) -> io::Result<Self::Port> {
+        Ok(VirtualDevice::new())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub port: String,
+    pub version: String,
+}
+
+/// Sets up credit and reads the firmware version, the device gets `timeout` to answer each
+pub fn handshake<T: Port>(
+    mut port: T,
+    name: &str,
+    timeout: Duration,
+) -> Result<(Link<T>, DeviceInfo), LinkError> {
+    port.set_timeout(timeout)?;
+    let mut link = Link::new(port);
+    link.sync_credits()?;
+    let version = match link.request(Packet::get_param("VERSION").unwrap())? {
+        Packet::Response(version) => String::from_utf8_lossy(&version).into_owned(),
+        packet => return Err(LinkError::Unexpected(Box::new(packet))),
+    };
+    let info = DeviceInfo {
+        port: name.to_string(),
+        version,
+    };
+    Ok((link, info))
+}
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// How long the device gets to answer a request
+    pub timeout: Duration,
+    /// How long a read waits for unsolicited frames before checking for requests again
+    pub poll: Duration,
+    /// Wait between scans while no device is connected
+    pub retry: Duration,
+    /// The device is pinged after this long without hearing from it
+    pub heartbeat: Duration,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            poll: Duration::from_millis(20),
+            retry: Duration::from_secs(2),
+            heartbeat: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Disconnected,
+    Connected(DeviceInfo),
+}
+
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("no device connected")]
+    NotConnected,
+    #[error("daemon stopped")]
+    Stopped,
+    #[error(transparent)]
+    Link(#[from] LinkError),
+}
+
+struct Request {
+    frame: Frame,
+    reply: oneshot::Sender<Result<Packet, DeviceError>>,
+}
+
+/// Talks to whatever device the daemon has connected, cheap to clone
+#[derive(Clone)]
+pub struct DeviceHandle {
+    requests: mpsc::Sender<Request>,
+    status: watch::Receiver<Status>,
+    events: broadcast::Sender<Frame>,
+}
+
+impl DeviceHandle {
+    /// Send a packet and wait for the answer, see `Link::request`
+    pub async fn request(&self, packet: Packet) -> Result<Packet, DeviceError> {
+        self.request_frame(Frame::from(packet)).await
+    }
+
+    pub async fn request_frame(&self, frame: Frame) -> Result<Packet, DeviceError> {
+        let (reply, answer) = oneshot::channel();
+        self.requests
+            .send(Request { frame, reply })
+            .await
+            .map_err(|_| DeviceError::Stopped)?;
+        answer.await.map_err(|_| DeviceError::Stopped)?
+    }
+
+    /// `request` for code outside of an async runtime, panics if called from inside one
+    pub fn blocking_request(&self, packet: Packet) -> Result<Packet, DeviceError> {
+        let (reply, answer) = oneshot::channel();
+        let frame = Frame::from(packet);
+        self.requests
+            .blocking_send(Request { frame, reply })
+            .map_err(|_| DeviceError::Stopped)?;
+        answer.blocking_recv().map_err(|_| DeviceError::Stopped)?
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    pub fn watch_status(&self) -> watch::Receiver<Status> {
+        self.status.clone()
+    }
+
+    /// Frames the device sends without being asked, such as events and logs
+    pub fn subscribe(&self) -> broadcast::Receiver<Frame> {
+        self.events.subscribe()
+    }
+}
+
+pub struct Daemon<C> {
+    connector: C,
+    config: DaemonConfig,
+    requests: mpsc::Receiver<Request>,
+    status: watch::Sender<Status>,
+    events: broadcast::Sender<Frame>,
+}
+
+impl<C: Connector> Daemon<C> {
+    pub fn new(connector: C, config: DaemonConfig) -> (Self, DeviceHandle) {
+        let (requests_tx, requests) = mpsc::channel(REQUEST_QUEUE);
+        let (status, status_rx) = watch::channel(Status::Disconnected);
+        let (events, _) = broadcast::channel(EVENT_QUEUE);
+        let handle = DeviceHandle {
+            requests: requests_tx,
+            status: status_rx,
+            events: events.clone(),
+        };
+        let daemon = Self {
+            connector,
+            config,
+            requests,
+            status,
+            events,
+        };
+        (daemon, handle)
+    }
+
+    /// Keeps a device connected, returns once every handle has been dropped
+    pub fn run(mut self) {
+        log::info!("Waiting for a device");
+        loop {
+            if let Some((link, info)) = self.connect() {
+                log::info!("Connected to {} running {}", info.port, info.version);
+                self.status.send_replace(Status::Connected(info));
+                let result = self.serve(link);
+                self.status.send_replace(Status::Disconnected);
+                match result {
+                    Ok(()) => return,
+                    Err(e) => log::warn!("Lost device: {e}, waiting for it to come back"),
+                }
+            }
+            if !self.wait(self.config.retry) {
+                return;
+            }
+        }
+    }
+
+    /// First candidate to pass the handshake
+    fn connect(&mut self) -> Option<(Link<C::Port>, DeviceInfo)> {
+        for name in self.connector.candidates() {
+            let result = self
+                .connector
+                .open(&name)
+                .map_err(LinkError::from)
+                .and_then(|port| handshake(port, &name, self.config.timeout));
+            match result {
+                Ok(connected) => return Some(connected),
+                Err(e) => log::debug!("No device on {name}: {e}"),
+            }
+        }
+        None
+    }
+
+    /// Answers requests with `NotConnected` for `duration`, returns false if every handle is gone
+    fn wait(&mut self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => {
+                    _ = request.reply.send(Err(DeviceError::NotConnected));
+                }
+                Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return true;
+                    }
+                    thread::sleep(self.config.poll.min(deadline - now));
+                }
+            }
+        }
+    }
+
+    /// Serves requests and forwards unsolicited frames until the link fails, Ok once every
+    /// handle is gone
+    fn serve(&mut self, mut link: Link<C::Port>) -> Result<(), LinkError> {
+        link.get_mut().set_timeout(self.config.poll)?;
+        let mut last_heard = Instant::now();
+        loop {
+            loop {
+                match self.requests.try_recv() {
+                    Ok(request) => {
+                        let result = self.request(&mut link, request.frame);
+                        if !matches!(&result, Err(e) if e.is_timeout()) {
+                            last_heard = Instant::now();
+                        }
+                        _ = request.reply.send(result.map_err(DeviceError::from));
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            match link.recv_frame() {
+                Ok(frame) => {
+                    last_heard = Instant::now();
+                    //nobody listening is fine
+                    _ = self.events.send(frame);
+                }
+                Err(e) if e.is_timeout() => {}
+                Err(LinkError::Protocol(e)) => log::warn!("Bad frame from device: {e}"),
+                Err(e) => return Err(e),
+            }
+            if last_heard.elapsed() >= self.config.heartbeat {
+                let ping = Packet::Echo(PayloadBuf::from_slice(b"ping").unwrap());
+                self.request(&mut link, Frame::from(ping))?;
+                last_heard = Instant::now();
+            }
+        }
+    }
+
+    /// Request with the longer request timeout, device errors aren't link failures
+    fn request(&self, link: &mut Link<C::Port>, frame: Frame) -> Result<Packet, LinkError> {
+        link.get_mut().set_timeout(self.config.timeout)?;
+        let result = link.request_frame(frame);
+        link.get_mut().set_timeout(self.config.poll)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::virtual_device::{self, Plug};
+
+    /// Hands out virtual devices in order, one per open
+    struct QueueConnector {
+        devices: VecDeque<VirtualDevice>,
+        opened: Arc<AtomicUsize>,
+    }
+
+    impl Connector for QueueConnector {
+        type Port = VirtualDevice;
+
+        fn candidates(&mut self) -> Vec<String> {
+            if self.devices.is_empty() {
+                vec![]
+            } else {
+                vec!["virtual".to_string()]
+            }
+        }
+
+        fn open(&mut self, _name: &str) -> io::Result<Self::Port> {
+            self.opened.fetch_add(1, Ordering::Relaxed);
+            self.devices
+                .pop_front()
+                .ok_or(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn start(
+        devices: usize,
+    ) -> (
+        DeviceHandle,
+        Vec<Plug>,
+        Arc<AtomicUsize>,
+        thread::JoinHandle<()>,
+    ) {
+        let devices: VecDeque<_> = (0..devices).map(|_| VirtualDevice::new()).collect();
+        let plugs = devices.iter().map(|d| d.plug()).collect();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let connector = QueueConnector {
+            devices,
+            opened: opened.clone(),
+        };
+        let config = DaemonConfig {
+            poll: Duration::from_millis(1),
+            retry: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let (daemon, handle) = Daemon::new(connector, config);
+        (handle, plugs, opened, thread::spawn(move || daemon.run()))
+    }
+
+    fn wait_until(mut f: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn echo() -> Packet {
+        Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap())
+    }
+
+    #[test]
+    fn test_request() {
+        let (handle, _plugs, _, daemon) = start(1);
+        wait_until(|| handle.status() != Status::Disconnected);
+        assert_eq!(
+            handle.status(),
+            Status::Connected(DeviceInfo {
+                port: "virtual".to_string(),
+                version: virtual_device::VERSION.to_string(),
+            })
+        );
+        assert_eq!(handle.blocking_request(echo()).unwrap(), echo());
+        //the daemon stops with the last handle
+        drop(handle);
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_not_connected() {
+        let (handle, _plugs, _, daemon) = start(0);
+        assert!(matches!(
+            handle.blocking_request(echo()),
+            Err(DeviceError::NotConnected)
+        ));
+        drop(handle);
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (handle, plugs, opened, daemon) = start(2);
+        wait_until(|| handle.status() != Status::Disconnected);
+        plugs[0].pull();
+        wait_until(|| opened.load(Ordering::Relaxed) == 2);
+        wait_until(|| handle.status() != Status::Disconnected);
+        assert_eq!(handle.blocking_request(echo()).unwrap(), echo());
+        drop(handle);
+        daemon.join().unwrap();
+    }
+}
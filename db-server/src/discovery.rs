@@ -0,0 +1,183 @@
+//! Finds device serial ports by the USB id of the device behind them, read from sysfs
+
+use std::{
+    fmt, fs, io,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+pub const SYSFS_TTY: &str = "/sys/class/tty";
+pub const DEV: &str = "/dev";
+
+/// tty name prefixes of USB CDC ACM and USB serial adapters
+const TTY_PREFIXES: [&str; 2] = ["ttyACM", "ttyUSB"];
+
+/// Espressif's built in USB serial/JTAG, what the bare metal firmware talks over
+pub const ESP_USB_JTAG: UsbId = UsbId {
+    vid: 0x303a,
+    pid: 0x1001,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UsbIdError {
+    #[error("expected vid:pid")]
+    MissingSeparator,
+    #[error("invalid hex id: {0}")]
+    InvalidHex(#[from] ParseIntError),
+}
+
+/// Parses `vid:pid` in hex, the way lsusb prints them
+impl FromStr for UsbId {
+    type Err = UsbIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (vid, pid) = s.split_once(':').ok_or(UsbIdError::MissingSeparator)?;
+        Ok(Self {
+            vid: u16::from_str_radix(vid, 16)?,
+            pid: u16::from_str_radix(pid, 16)?,
+        })
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// Device node, such as /dev/ttyACM0
+    pub path: PathBuf,
+    pub id: UsbId,
+}
+
+/// Serial ports under `sysfs_tty` backed by a USB device with one of `ids`, sorted by name
+/// `dev` is where the device nodes live, split out along with `sysfs_tty` for tests
+pub fn scan(sysfs_tty: &Path, dev: &Path, ids: &[UsbId]) -> io::Result<Vec<Candidate>> {
+    let mut candidates = vec![];
+    for entry in fs::read_dir(sysfs_tty)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !TTY_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+            continue;
+        }
+        let Some(id) = usb_id(&entry.path().join("device")) else {
+            continue;
+        };
+        if ids.contains(&id) {
+            candidates.push(Candidate {
+                path: dev.join(name),
+                id,
+            });
+        }
+    }
+    candidates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(candidates)
+}
+
+/// `scan` of the real sysfs and /dev
+pub fn scan_system(ids: &[UsbId]) -> io::Result<Vec<Candidate>> {
+    scan(Path::new(SYSFS_TTY), Path::new(DEV), ids)
+}
+
+/// The tty's device links to a USB interface, the ids live on the USB device above it
+fn usb_id(device: &Path) -> Option<UsbId> {
+    let device = fs::canonicalize(device).ok()?;
+    device.ancestors().find_map(|dir| {
+        Some(UsbId {
+            vid: read_hex(&dir.join("idVendor"))?,
+            pid: read_hex(&dir.join("idProduct"))?,
+        })
+    })
+}
+
+fn read_hex(path: &Path) -> Option<u16> {
+    let text = fs::read_to_string(path).ok()?;
+    u16::from_str_radix(text.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// Lays out a sysfs like tree, `usb` is the path of the USB device under devices/
+    fn add_tty(root: &Path, tty: &str, usb: Option<(&str, UsbId)>) {
+        let class = root.join("class/tty").join(tty);
+        fs::create_dir_all(&class).unwrap();
+        let Some((usb, id)) = usb else {
+            let device = root.join("devices/platform/serial8250");
+            fs::create_dir_all(&device).unwrap();
+            symlink(&device, class.join("device")).unwrap();
+            return;
+        };
+        let device = root.join("devices").join(usb);
+        let interface = device.join(format!("{}:1.0", usb.rsplit('/').next().unwrap()));
+        fs::create_dir_all(&interface).unwrap();
+        fs::write(device.join("idVendor"), format!("{:04x}\n", id.vid)).unwrap();
+        fs::write(device.join("idProduct"), format!("{:04x}\n", id.pid)).unwrap();
+        symlink(&interface, class.join("device")).unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let root = tempfile::tempdir().unwrap();
+        let other = UsbId {
+            vid: 0x10c4,
+            pid: 0xea60,
+        };
+        add_tty(root.path(), "ttyACM1", Some(("usb1/1-2", ESP_USB_JTAG)));
+        add_tty(root.path(), "ttyACM0", Some(("usb1/1-1", ESP_USB_JTAG)));
+        add_tty(root.path(), "ttyUSB0", Some(("usb2/2-1", other)));
+        add_tty(root.path(), "ttyS0", None);
+
+        let tty = root.path().join("class/tty");
+        let found = scan(&tty, Path::new("/dev"), &[ESP_USB_JTAG]).unwrap();
+        let paths: Vec<_> = found.iter().map(|c| c.path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["/dev/ttyACM0", "/dev/ttyACM1"]);
+
+        let found = scan(&tty, Path::new("/dev"), &[other]).unwrap();
+        assert_eq!(
+            found,
+            [Candidate {
+                path: "/dev/ttyUSB0".into(),
+                id: other
+            }]
+        );
+    }
+
+    #[test]
+    fn test_scan_no_usb_parent() {
+        let root = tempfile::tempdir().unwrap();
+        add_tty(root.path(), "ttyACM0", None);
+        let tty = root.path().join("class/tty");
+        assert!(scan(&tty, Path::new("/dev"), &[ESP_USB_JTAG])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_usb_id_parse() {
+        assert_eq!("303a:1001".parse(), Ok(ESP_USB_JTAG));
+        assert_eq!(ESP_USB_JTAG.to_string(), "303a:1001");
+        assert_eq!("303a".parse::<UsbId>(), Err(UsbIdError::MissingSeparator));
+        assert!(matches!(
+            "303a:zz".parse::<UsbId>(),
+            Err(UsbIdError::InvalidHex(_))
+        ));
+    }
+}
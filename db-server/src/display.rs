@@ -0,0 +1,453 @@
+//! Decides what the screen shows and keeps the device showing it
+//!
+//! The screen is one of a set of named pages, with an optional message drawn over it for a
+//! while. `Display` is cheap to clone so every frontend, such as the HTTP API, can change it,
+//! and `Display::run` redraws and sends the device what changed whenever anything does.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyleBuilder, Rectangle, StrokeAlignment},
+    text::{Baseline, Text},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, Notify},
+    time,
+};
+
+use crate::{
+    device::{DeviceHandle, Status},
+    render::{FrameSender, Framebuffer, Page},
+    source::Latest,
+};
+
+/// How long notifications stay up when whoever sent them doesn't say
+pub const NOTIFICATION_TIME: Duration = Duration::from_secs(10);
+/// Gap between the message box and the edge of the screen
+const MESSAGE_MARGIN: i32 = 4;
+const MESSAGE_BORDER: u32 = 2;
+const MESSAGE_PADDING: i32 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Drawn large above the body when there is one
+    pub title: Option<String>,
+    pub body: String,
+}
+
+impl Message {
+    pub fn text(body: &str) -> Self {
+        Self {
+            title: None,
+            body: body.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DisplayError {
+    #[error("no page named {0}")]
+    UnknownPage(String),
+}
+
+struct Shown {
+    message: Message,
+    until: Instant,
+}
+
+struct State {
+    pages: Vec<(String, Box<dyn Page + Send>)>,
+    current: usize,
+    message: Option<Shown>,
+    frame: Framebuffer,
+}
+
+/// What's on screen, shared by everything that changes it
+#[derive(Clone)]
+pub struct Display {
+    state: Arc<Mutex<State>>,
+    redraw: Arc<Notify>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display {
+    /// No pages and a blank screen
+    pub fn new() -> Self {
+        let state = State {
+            pages: vec![],
+            current: 0,
+            message: None,
+            frame: Framebuffer::screen(),
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            redraw: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Adds a page after the others, the first page added is shown
+    pub fn add_page(&self, name: &str, page: impl Page + Send + 'static) {
+        let mut state = self.state.lock().unwrap();
+        state.pages.push((name.to_string(), Box::new(page)));
+        if state.pages.len() == 1 {
+            self.redraw.notify_one();
+        }
+    }
+
+    pub fn pages(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.pages.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn current_page(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.pages.get(state.current).map(|(name, _)| name.clone())
+    }
+
+    pub fn show_page(&self, name: &str) -> Result<(), DisplayError> {
+        let mut state = self.state.lock().unwrap();
+        let index = state
+            .pages
+            .iter()
+            .position(|(page, _)| page == name)
+            .ok_or_else(|| DisplayError::UnknownPage(name.to_string()))?;
+        state.current = index;
+        self.redraw.notify_one();
+        Ok(())
+    }
+
+    /// Draws `message` over the page for `duration`, replacing any message already up
+    pub fn show_message(&self, message: Message, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.message = Some(Shown {
+            message,
+            until: Instant::now() + duration,
+        });
+        self.redraw.notify_one();
+    }
+
+    pub fn clear_message(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.message.take().is_some() {
+            self.redraw.notify_one();
+        }
+    }
+
+    /// Draws the screen again, for pages whose content changed
+    pub fn redraw(&self) {
+        self.redraw.notify_one();
+    }
+
+    /// The message on screen, if it hasn't run out
+    pub fn message(&self) -> Option<Message> {
+        let state = self.state.lock().unwrap();
+        let shown = state.message.as_ref()?;
+        (shown.until > Instant::now()).then(|| shown.message.clone())
+    }
+
+    /// The last frame drawn, what the device shows once it has caught up
+    pub fn frame(&self) -> Framebuffer {
+        self.state.lock().unwrap().frame.clone()
+    }
+
+    /// Draws the current page and message as of `now`, dropping the message once it's over
+    pub fn render(&self, now: Instant) -> anyhow::Result<Framebuffer> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            pages,
+            current,
+            message,
+            frame,
+        } = &mut *state;
+        if message.as_ref().is_some_and(|shown| shown.until <= now) {
+            *message = None;
+        }
+        match pages.get_mut(*current) {
+            Some((_, page)) => page.draw(frame)?,
+            None => frame.clear(BinaryColor::On)?,
+        }
+        if let Some(shown) = message {
+            draw_message(frame, &shown.message)?;
+        }
+        Ok(frame.clone())
+    }
+
+    /// When the message on screen runs out
+    fn message_until(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.message.as_ref().map(|shown| shown.until)
+    }
+
+    /// Keeps the device showing the display, redrawing when it's changed, a source in
+    /// `changes` has a new value or a message runs out. Returns once the daemon stops
+    pub async fn run(self, device: DeviceHandle, mut changes: broadcast::Receiver<Arc<str>>) {
+        let mut status = device.watch_status();
+        let mut sender = FrameSender::new();
+        loop {
+            let until = self.message_until();
+            //never waited on, the branch is disabled without a message
+            let deadline = until.unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+            tokio::select! {
+                _ = self.redraw.notified() => {}
+                change = changes.recv() => {
+                    //lagging behind only means missed redraws, the next one catches up
+                    if let Err(broadcast::error::RecvError::Closed) = change {
+                        return;
+                    }
+                }
+                changed = status.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    //a device that just connected shows whatever it had before
+                    sender.invalidate();
+                }
+                _ = time::sleep_until(deadline.into()), if until.is_some() => {}
+            }
+            let frame = match self.render(Instant::now()) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::warn!("Drawing failed: {e:#}");
+                    continue;
+                }
+            };
+            if !matches!(*status.borrow_and_update(), Status::Connected(_)) {
+                continue;
+            }
+            match sender.send(&device, &frame).await {
+                Ok(Some(kind)) => log::debug!("Sent frame, {kind:?} refresh"),
+                Ok(None) => {}
+                Err(e) => log::warn!("Sending frame failed: {e}"),
+            }
+        }
+    }
+}
+
+/// Page showing the latest value of a data source
+pub struct SourcePage<T>(pub Latest<T>);
+
+impl<T: Page + Clone + Send + Sync> Page for SourcePage<T> {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+        let latest = self.0.borrow().clone();
+        match latest {
+            Some(mut value) => value.draw(target),
+            None => {
+                target.clear(BinaryColor::On)?;
+                let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::Off);
+                Text::with_baseline("Waiting for data", Point::new(2, 2), style, Baseline::Top)
+                    .draw(target)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Box with a border over most of the screen, the title and body wrapped to fit
+fn draw_message(target: &mut Framebuffer, message: &Message) -> anyhow::Result<()> {
+    let area = target.bounding_box().offset(-MESSAGE_MARGIN);
+    let style = PrimitiveStyleBuilder::new()
+        .fill_color(BinaryColor::On)
+        .stroke_color(BinaryColor::Off)
+        .stroke_width(MESSAGE_BORDER)
+        .stroke_alignment(StrokeAlignment::Inside)
+        .build();
+    area.into_styled(style).draw(target)?;
+
+    let inner = area.offset(-(MESSAGE_BORDER as i32 + MESSAGE_PADDING));
+    let mut position = inner.top_left;
+    if let Some(title) = &message.title {
+        position = draw_wrapped(target, title, position, &inner, &FONT_10X20)?;
+    }
+    draw_wrapped(target, &message.body, position, &inner, &FONT_6X10)?;
+    Ok(())
+}
+
+/// Word wraps `text` to the width of `area` from `position`, lines past the bottom are left
+/// off. Returns where the next line would go
+fn draw_wrapped(
+    target: &mut Framebuffer,
+    text: &str,
+    mut position: Point,
+    area: &Rectangle,
+    font: &embedded_graphics::mono_font::MonoFont,
+) -> anyhow::Result<Point> {
+    let style = MonoTextStyle::new(font, BinaryColor::Off);
+    let columns = (area.size.width / font.character_size.width).max(1) as usize;
+    let line_height = font.character_size.height as i32;
+    let bottom = area.top_left.y + area.size.height as i32;
+    for line in wrap(text, columns) {
+        if position.y + line_height > bottom {
+            break;
+        }
+        Text::with_baseline(&line, position, style, Baseline::Top).draw(target)?;
+        position.y += line_height;
+    }
+    Ok(position)
+}
+
+/// Breaks `text` into lines of at most `columns` characters, at spaces where it can
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word = word;
+            //words longer than a line are split wherever the line ends
+            while word.chars().count() > columns {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                let split = word.char_indices().nth(columns).unwrap().0;
+                lines.push(word[..split].to_string());
+                word = &word[split..];
+            }
+            let len = line.chars().count();
+            if len > 0 && len + 1 + word.chars().count() > columns {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "ui")]
+    use db_ui::pages::system::{self, System};
+
+    use super::*;
+    #[cfg(feature = "ui")]
+    use crate::{
+        metrics::Snapshot,
+        source::{DataSource, Registry},
+    };
+
+    /// Fills the screen, on or off
+    struct Solid(bool);
+
+    impl Page for Solid {
+        fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+            target.clear(BinaryColor::from(self.0))?;
+            Ok(())
+        }
+    }
+
+    /// Hands out its snapshots in turn, then keeps repeating the last
+    #[cfg(feature = "ui")]
+    struct Snapshots(Vec<Snapshot>);
+
+    #[cfg(feature = "ui")]
+    impl DataSource for Snapshots {
+        type Output = Snapshot;
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+
+        async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+            match self.0.len() {
+                1 => Ok(self.0[0].clone()),
+                _ => Ok(self.0.remove(0)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("build finished in 3 minutes", 10),
+            ["build", "finished", "in 3", "minutes"]
+        );
+        assert_eq!(wrap("abcdefghij klm", 4), ["abcd", "efgh", "ij", "klm"]);
+        assert_eq!(wrap("one\ntwo", 20), ["one", "two"]);
+    }
+
+    #[test]
+    fn test_pages() {
+        let display = Display::new();
+        display.add_page("dark", Solid(false));
+        display.add_page("light", Solid(true));
+        assert_eq!(display.current_page().as_deref(), Some("dark"));
+        let start = Instant::now();
+        assert!(!display.render(start).unwrap().pixel(0, 0));
+
+        display.show_page("light").unwrap();
+        assert!(display.render(start).unwrap().pixel(0, 0));
+        assert_eq!(
+            display.show_page("weather"),
+            Err(DisplayError::UnknownPage("weather".to_string()))
+        );
+        assert_eq!(display.pages(), ["dark", "light"]);
+    }
+
+    #[test]
+    fn test_message() {
+        let display = Display::new();
+        display.add_page("light", Solid(true));
+        display.show_message(Message::text("hello"), Duration::from_secs(5));
+        assert_eq!(display.message(), Some(Message::text("hello")));
+
+        let now = Instant::now();
+        let frame = display.render(now).unwrap();
+        //the border is drawn dark inside the margin
+        assert!(frame.pixel(0, 0));
+        assert!(!frame.pixel(MESSAGE_MARGIN as u16, MESSAGE_MARGIN as u16));
+        assert_eq!(display.frame(), frame);
+
+        let frame = display.render(now + Duration::from_secs(5)).unwrap();
+        assert!(frame.pixel(MESSAGE_MARGIN as u16, MESSAGE_MARGIN as u16));
+        assert_eq!(display.message(), None);
+    }
+
+    #[cfg(feature = "ui")]
+    #[tokio::test(start_paused = true)]
+    async fn test_source_page() {
+        let quiet = Snapshot {
+            cpu_percent: Some(5.0),
+            ..Default::default()
+        };
+        let busy = Snapshot {
+            cpu_percent: Some(95.0),
+            ..Default::default()
+        };
+        let mut registry = Registry::new();
+        let latest = registry
+            .register("metrics", Snapshots(vec![quiet.clone(), busy.clone()]))
+            .unwrap();
+        let mut changes = registry.changes();
+        let display = Display::new();
+        display.add_page("metrics", SourcePage(latest));
+
+        //nothing fetched yet
+        let mut frame = display.render(Instant::now()).unwrap();
+        for snapshot in [quiet, busy] {
+            assert_eq!(&*changes.recv().await.unwrap(), "metrics");
+            let mut expected = Framebuffer::screen();
+            system::draw(&mut expected, &System::from(&snapshot)).unwrap();
+            let drawn = display.render(Instant::now()).unwrap();
+            assert_ne!(drawn, frame);
+            assert_eq!(drawn, expected);
+            frame = drawn;
+        }
+    }
+}
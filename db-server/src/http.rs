@@ -0,0 +1,327 @@
+//! Localhost HTTP API for other tools to put things on the display
+//!
+//! Every response is JSON, errors are `{"error": "..."}` with a matching status code.
+//!
+//! GET  /status                                      device, page and message on screen
+//! GET  /screenshot                                  last frame drawn, see `Screenshot`
+//! GET  /pages                                       page names and the current page
+//! POST /page     {"page": "metrics"}                switch pages
+//! POST /notify   {"title", "body", "seconds"?}      show a notification
+//! POST /text     {"text", "seconds"}                show a message
+//! POST /params   {"key", "value"}                   set a device param, value as text
+
+use std::{fmt::Write as _, net::SocketAddr};
+
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use db_link::params;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{
+    control::{self, RequestError},
+    device::{DeviceError, DeviceHandle},
+    display::{Display, DisplayError},
+    link::LinkError,
+    render::Framebuffer,
+};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("not found")]
+    NotFound,
+    #[error("method not allowed")]
+    MethodNotAllowed,
+    #[error(transparent)]
+    Display(#[from] DisplayError),
+    #[error(transparent)]
+    Param(#[from] params::Error),
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<RequestError> for ApiError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Display(e) => ApiError::Display(e),
+            RequestError::Param(e) => ApiError::Param(e),
+            RequestError::Device(e) => ApiError::Device(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ApiError::BadRequest(_) | ApiError::Param(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound | ApiError::Display(DisplayError::UnknownPage(_)) => {
+                StatusCode::NOT_FOUND
+            }
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Device(DeviceError::NotConnected | DeviceError::Stopped) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            //the device understood and said no
+            ApiError::Device(DeviceError::Link(LinkError::Device(_))) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Device(_) => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+#[derive(Clone)]
+struct ApiState {
+    display: Display,
+    device: DeviceHandle,
+}
+
+pub fn router(display: Display, device: DeviceHandle) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/screenshot", get(screenshot))
+        .route("/pages", get(pages))
+        .route("/page", post(show_page))
+        .route("/notify", post(notify))
+        .route("/text", post(text))
+        .route("/params", post(set_param))
+        .fallback(|| async { ApiError::NotFound })
+        .method_not_allowed_fallback(|| async { ApiError::MethodNotAllowed })
+        .with_state(ApiState { display, device })
+}
+
+/// Serves the API on `addr` until the listener fails
+pub async fn serve(
+    addr: SocketAddr,
+    display: Display,
+    device: DeviceHandle,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("HTTP API listening on {}", listener.local_addr()?);
+    axum::serve(listener, router(display, device)).await
+}
+
+/// Carries out `request` the same way the control socket does
+async fn run(state: &ApiState, request: control::Request) -> ApiResult {
+    let value = control::handle(request, &state.display, &state.device).await?;
+    //requests that only do something answer null over the socket
+    Ok(Json(if value.is_null() {
+        json!({ "ok": true })
+    } else {
+        value
+    }))
+}
+
+async fn status(State(state): State<ApiState>) -> ApiResult {
+    run(&state, control::Request::Status).await
+}
+
+/// Packed 1 bit pixels as hex, rows MSB first and padded to a whole byte, set bits are light
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: u16,
+    pub height: u16,
+    pub data: String,
+}
+
+impl From<&Framebuffer> for Screenshot {
+    fn from(frame: &Framebuffer) -> Self {
+        let mut data = String::with_capacity(frame.as_bytes().len() * 2);
+        for byte in frame.as_bytes() {
+            //writing to a String can't fail
+            _ = write!(data, "{byte:02x}");
+        }
+        Self {
+            width: frame.width(),
+            height: frame.height(),
+            data,
+        }
+    }
+}
+
+async fn screenshot(State(state): State<ApiState>) -> Json<Screenshot> {
+    Json(Screenshot::from(&state.display.frame()))
+}
+
+async fn pages(State(state): State<ApiState>) -> ApiResult {
+    run(&state, control::Request::Pages).await
+}
+
+#[derive(Deserialize)]
+struct ShowPage {
+    page: String,
+}
+
+async fn show_page(
+    State(state): State<ApiState>,
+    body: Result<Json<ShowPage>, JsonRejection>,
+) -> ApiResult {
+    let Json(ShowPage { page }) = body?;
+    run(&state, control::Request::Page { page }).await
+}
+
+#[derive(Deserialize)]
+struct Notify {
+    title: String,
+    #[serde(default)]
+    body: String,
+    seconds: Option<u64>,
+}
+
+async fn notify(
+    State(state): State<ApiState>,
+    body: Result<Json<Notify>, JsonRejection>,
+) -> ApiResult {
+    let Json(Notify {
+        title,
+        body,
+        seconds,
+    }) = body?;
+    run(
+        &state,
+        control::Request::Notify {
+            title,
+            body,
+            seconds,
+        },
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct Text {
+    text: String,
+    seconds: u64,
+}
+
+async fn text(State(state): State<ApiState>, body: Result<Json<Text>, JsonRejection>) -> ApiResult {
+    let Json(Text { text, seconds }) = body?;
+    run(&state, control::Request::Text { text, seconds }).await
+}
+
+#[derive(Deserialize)]
+struct SetParam {
+    key: String,
+    value: String,
+}
+
+async fn set_param(
+    State(state): State<ApiState>,
+    body: Result<Json<SetParam>, JsonRejection>,
+) -> ApiResult {
+    let Json(SetParam { key, value }) = body?;
+    run(&state, control::Request::SetParam { key, value }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::device::{Daemon, DaemonConfig, Status, VirtualConnector};
+
+    /// Router in front of a virtual device, once it's connected
+    async fn connected() -> Router {
+        let (daemon, device) = Daemon::new(VirtualConnector, DaemonConfig::default());
+        thread::spawn(move || daemon.run());
+        let mut status = device.watch_status();
+        status
+            .wait_for(|status| matches!(status, Status::Connected(_)))
+            .await
+            .unwrap();
+        let display = Display::new();
+        display.add_page("one", Framebuffer::screen());
+        display.add_page("two", Framebuffer::screen());
+        router(display, device)
+    }
+
+    async fn call(router: &Router, method: &str, uri: &str, body: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_pages_and_messages() {
+        let router = connected().await;
+        let (code, body) = call(&router, "POST", "/page", json!({"page": "two"})).await;
+        assert_eq!((code, body), (StatusCode::OK, json!({"ok": true})));
+        let (code, body) = call(&router, "POST", "/page", json!({"page": "three"})).await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert_eq!(body, json!({"error": "no page named three"}));
+
+        call(
+            &router,
+            "POST",
+            "/text",
+            json!({"text": "hi", "seconds": 60}),
+        )
+        .await;
+        let (_, body) = call(&router, "GET", "/status", Value::Null).await;
+        assert_eq!(body["device"]["connected"], true);
+        assert_eq!(body["page"], "two");
+        assert_eq!(body["message"], json!({"title": null, "body": "hi"}));
+
+        let (_, body) = call(&router, "GET", "/pages", Value::Null).await;
+        assert_eq!(body, json!({"pages": ["one", "two"], "current": "two"}));
+    }
+
+    #[tokio::test]
+    async fn test_params() {
+        let router = connected().await;
+        let body = json!({"key": "NAME", "value": "desk"});
+        let (code, _) = call(&router, "POST", "/params", body).await;
+        assert_eq!(code, StatusCode::OK);
+        let body = json!({"key": "VERSION", "value": "2"});
+        let (code, body) = call(&router, "POST", "/params", body).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_json() {
+        let router = connected().await;
+        let (code, body) = call(&router, "POST", "/text", json!({"seconds": 1})).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("text"));
+        let (code, _) = call(&router, "GET", "/nope", Value::Null).await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let (code, _) = call(&router, "GET", "/notify", Value::Null).await;
+        assert_eq!(code, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (_, body) = call(&router, "GET", "/screenshot", Value::Null).await;
+        let screenshot: Screenshot = serde_json::from_value(body).unwrap();
+        assert_eq!(screenshot.data.len(), 32 * 122 * 2);
+    }
+}
@@ -0,0 +1,16 @@
+pub mod bench;
+pub mod control;
+pub mod device;
+pub mod discovery;
+pub mod display;
+pub mod http;
+pub mod link;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "notifications")]
+pub mod notifications;
+pub mod render;
+pub mod shell;
+pub mod source;
+pub mod virtual_device;
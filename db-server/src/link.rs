@@ -0,0 +1,523 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+};
+
+use db_link::{
+    commands::{
+        Channel, ErrorCode, ErrorPayload, Frame, Packet, PayloadBuf, CHANNEL_COUNT, MAX_PACKET_SIZE,
+    },
+    flow::{self, CreditSender},
+    mux::Mux,
+    parser::{self, Parser},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(#[from] parser::Error),
+    #[error("device error: {0}")]
+    Device(Box<ErrorPayload>),
+    #[error("link closed")]
+    Closed,
+    #[error("unexpected reply: {0:?}")]
+    Unexpected(Box<Packet>),
+}
+
+impl LinkError {
+    /// A read timed out on the port, the link itself is still usable
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            LinkError::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+        )
+    }
+}
+
+/// Frames per channel that can be queued with `Link::queue`
+const OUTBOX_DEPTH: usize = 16;
+
+/// Frames kept for `recv` before the oldest are dropped
+const INBOX_DEPTH: usize = 64;
+
+/// What the parser made of the bytes read so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Frames parsed, credit grants included
+    pub frames: u64,
+    /// Frames dropped as malformed
+    pub parse_errors: u64,
+    /// Bytes dropped looking for the start of a frame
+    pub skipped: u64,
+    /// Frames dropped because the inbox was full or they answered a timed out request
+    pub dropped: u64,
+}
+
+/// Packet level connection to a device over any byte stream
+/// Keeps the host inside the device's credit window, see `db_link::flow`
+pub struct Link<T> {
+    port: T,
+    parser: Parser,
+    credits: CreditSender,
+    /// frames that arrived while we were waiting on credit or a response
+    inbox: VecDeque<Frame>,
+    /// channels where a timed out request may still be answered, see `resync_channel`
+    stale: [bool; CHANNEL_COUNT],
+    /// fences sent so far, numbers the next one
+    fences: u32,
+    outbox: Box<Mux<OUTBOX_DEPTH>>,
+    rx_buf: [u8; MAX_PACKET_SIZE],
+    rx_pos: usize,
+    rx_len: usize,
+    stats: LinkStats,
+}
+
+impl<T: Read + Write> Link<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            parser: Parser::new(),
+            credits: CreditSender::new(),
+            inbox: VecDeque::new(),
+            stale: [false; CHANNEL_COUNT],
+            fences: 0,
+            outbox: Box::new(Mux::new()),
+            rx_buf: [0u8; MAX_PACKET_SIZE],
+            rx_pos: 0,
+            rx_len: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// The underlying port, for settings such as timeouts. Reading or writing through it will
+    /// desync the link
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Ask the device how much it can buffer, must be done before sending anything else
+    /// Firmware without flow control answers with an error, in that case we don't limit sends
+    pub fn sync_credits(&mut self) -> Result<(), LinkError> {
+        self.credits = CreditSender::new();
+        self.write_packet(Packet::CreditRequest)?;
+        loop {
+            match self.read_frame()?.packet {
+                advertise @ Packet::CreditAdvertise(_) => {
+                    self.credits.handle(&advertise);
+                    return Ok(());
+                }
+                Packet::Error(error)
+                    if matches!(
+                        error.code,
+                        ErrorCode::UnknownCommand | ErrorCode::NotSupported
+                    ) =>
+                {
+                    self.credits = CreditSender::unlimited();
+                    return Ok(());
+                }
+                //anything else is left over from before the resync, credit batches included
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a packet on its default channel, see `send_frame`
+    pub fn send(&mut self, packet: Packet) -> Result<(), LinkError> {
+        self.send_frame(Frame::from(packet))
+    }
+
+    /// Send a frame right away, blocking on reads until the device has granted enough credit
+    pub fn send_frame(&mut self, frame: Frame) -> Result<(), LinkError> {
+        if flow::is_exempt(&frame.packet) {
+            self.port.write_all(&frame.serialize_vec())?;
+            return Ok(());
+        }
+        let buf = frame.serialize_vec();
+        while !self.credits.can_send(buf.len()) {
+            let frame = self.read_frame()?;
+            if !self.credits.handle(&frame.packet) {
+                self.keep(frame);
+            }
+        }
+        //can't fail, checked above
+        _ = self.credits.consume(buf.len());
+        self.port.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Send bytes as they are, such as hand made frames for poking at the device
+    /// Not counted against credit since the device only credits back what parses into frames,
+    /// `sync_credits` once the answer is in to drop whatever the device credited back
+    pub fn send_raw(&mut self, buf: &[u8]) -> Result<(), LinkError> {
+        self.port.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Queue a frame to go out on the next `flush`, hands it back if its channel is full
+    #[allow(clippy::result_large_err)]
+    pub fn queue(&mut self, frame: Frame) -> Result<(), Frame> {
+        self.outbox.push(frame)
+    }
+
+    /// Send everything queued, highest priority channel first
+    pub fn flush(&mut self) -> Result<(), LinkError> {
+        while let Some(frame) = self.outbox.pop() {
+            self.send_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Next packet from the device, credit grants are applied and never returned
+    pub fn recv(&mut self) -> Result<Packet, LinkError> {
+        self.recv_frame().map(|frame| frame.packet)
+    }
+
+    /// Same as `recv` but keeps the channel
+    pub fn recv_frame(&mut self) -> Result<Frame, LinkError> {
+        if let Some(frame) = self.inbox.pop_front() {
+            return Ok(frame);
+        }
+        self.read_data_frame()
+    }
+
+    /// Send a packet and wait for the answer, device errors are returned as `LinkError::Device`
+    /// Frames on other channels that show up in the meantime are kept for `recv`
+    pub fn request(&mut self, packet: Packet) -> Result<Packet, LinkError> {
+        self.request_frame(Frame::from(packet))
+    }
+
+    /// Same as `request` but the answer is waited for on `frame`'s channel
+    /// After a timeout the channel is resynced before the next request, so a late answer
+    /// isn't taken for the next one
+    pub fn request_frame(&mut self, frame: Frame) -> Result<Packet, LinkError> {
+        let channel = frame.channel;
+        if self.stale[channel as usize] {
+            self.resync_channel(channel)?;
+        }
+        self.send_frame(frame)?;
+        let result = self.recv_on(channel);
+        if matches!(&result, Err(e) if e.is_timeout()) {
+            self.stale[channel as usize] = true;
+        }
+        match result? {
+            Packet::Error(error) => Err(LinkError::Device(Box::new(error))),
+            packet => Ok(packet),
+        }
+    }
+
+    /// Sends a numbered echo on `channel` and drops everything on it until the echo comes
+    /// back, the device answers in order so nothing older can show up after it
+    fn resync_channel(&mut self, channel: Channel) -> Result<(), LinkError> {
+        self.fences += 1;
+        let fence = fence(self.fences);
+        self.send_frame(Frame::new(channel, fence.clone()))?;
+        loop {
+            let packet = self.recv_on(channel)?;
+            if packet == fence {
+                self.stale[channel as usize] = false;
+                return Ok(());
+            }
+            self.stats.dropped += 1;
+            log::debug!("Dropped late answer on {channel:?}: {packet:?}");
+        }
+    }
+
+    fn recv_on(&mut self, channel: Channel) -> Result<Packet, LinkError> {
+        if let Some(pos) = self.inbox.iter().position(|f| f.channel == channel) {
+            //just found it
+            return Ok(self.inbox.remove(pos).unwrap().packet);
+        }
+        loop {
+            let frame = self.read_data_frame()?;
+            if frame.channel == channel {
+                return Ok(frame.packet);
+            }
+            self.keep(frame);
+        }
+    }
+
+    /// Reads frames, applying credit, until one with data shows up
+    fn read_data_frame(&mut self) -> Result<Frame, LinkError> {
+        loop {
+            let frame = self.read_frame()?;
+            if !self.credits.handle(&frame.packet) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Queues a frame for `recv`, dropping the oldest once nobody has read `INBOX_DEPTH`
+    fn keep(&mut self, frame: Frame) {
+        if self.inbox.len() >= INBOX_DEPTH {
+            let dropped = self.inbox.pop_front();
+            self.stats.dropped += 1;
+            log::warn!("Inbox full, dropped {dropped:?}");
+        }
+        self.inbox.push_back(frame);
+    }
+
+    fn write_packet(&mut self, packet: Packet) -> Result<(), LinkError> {
+        self.port.write_all(&packet.serialize_vec())?;
+        Ok(())
+    }
+
+    /// Reads until a full frame is parsed, bytes after it stay buffered for the next call
+    fn read_frame(&mut self) -> Result<Frame, LinkError> {
+        loop {
+            while self.rx_pos < self.rx_len {
+                let byte = self.rx_buf[self.rx_pos];
+                self.rx_pos += 1;
+                match self.parser.parse_frame(&[byte]) {
+                    Ok(frame) => {
+                        self.stats.frames += 1;
+                        return Ok(frame);
+                    }
+                    Err(parser::Error::InvalidVersion) => {
+                        self.stats.parse_errors += 1;
+                        return Err(parser::Error::InvalidVersion.into());
+                    }
+                    Err(parser::Error::NoSyncByte) => self.stats.skipped += 1,
+                    Err(parser::Error::InCompleteHeader | parser::Error::InCompletePayload) => {}
+                    Err(_) => self.stats.parse_errors += 1,
+                }
+            }
+            self.rx_len = self.port.read(&mut self.rx_buf)?;
+            self.rx_pos = 0;
+            if self.rx_len == 0 {
+                return Err(LinkError::Closed);
+            }
+        }
+    }
+}
+
+/// Echo used by `Link::resync_channel`
+fn fence(n: u32) -> Packet {
+    Packet::Echo(PayloadBuf::from_slice(format!("fence {n}").as_bytes()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Fake port that replays canned device output and records what the host wrote
+    struct ScriptedPort {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        /// input offsets where one read times out, as if the device went quiet
+        pauses: VecDeque<u64>,
+    }
+
+    impl ScriptedPort {
+        fn new(packets: &[Packet]) -> Self {
+            let input = packets
+                .iter()
+                .flat_map(|p| p.clone().serialize_vec())
+                .collect();
+            Self {
+                input: Cursor::new(input),
+                output: vec![],
+                pauses: VecDeque::new(),
+            }
+        }
+    }
+
+    impl Read for ScriptedPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let pos = self.input.position();
+            match self.pauses.front() {
+                Some(&pause) if pause == pos => {
+                    self.pauses.pop_front();
+                    Err(ErrorKind::TimedOut.into())
+                }
+                Some(&pause) => {
+                    let len = buf.len().min((pause - pos) as usize);
+                    self.input.read(&mut buf[..len])
+                }
+                None => self.input.read(buf),
+            }
+        }
+    }
+
+    impl Write for ScriptedPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn echo(msg: &[u8]) -> Packet {
+        Packet::Echo(PayloadBuf::from_slice(msg).unwrap())
+    }
+
+    #[test]
+    fn test_send_waits_for_credit() {
+        let port = ScriptedPort::new(&[
+            //batch that crossed the request, the advertisement already counts it
+            Packet::Credit(30),
+            Packet::CreditAdvertise(10),
+            echo(b"hi"),
+            Packet::Credit(20),
+        ]);
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert_eq!(link.credits.credit(), 10);
+
+        link.send(echo(b"hi")).unwrap();
+        assert_eq!(link.credits.credit(), 3);
+        //second send has to read the grant, the echo in front of it is kept for recv
+        link.send(echo(b"hi")).unwrap();
+        assert_eq!(link.credits.credit(), 16);
+        assert_eq!(link.recv().unwrap(), echo(b"hi"));
+
+        let mut expected = Packet::CreditRequest.serialize_vec();
+        expected.extend(echo(b"hi").serialize_vec());
+        expected.extend(echo(b"hi").serialize_vec());
+        assert_eq!(link.port.output, expected);
+    }
+
+    #[test]
+    fn test_raw_is_uncredited() {
+        let port = ScriptedPort::new(&[Packet::CreditAdvertise(10)]);
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        link.send_raw(&[0x55; 20]).unwrap();
+        assert_eq!(link.credits.credit(), 10);
+    }
+
+    #[test]
+    fn test_flush_priority() {
+        let port = ScriptedPort::new(&[Packet::CreditAdvertise(1000)]);
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        let bulk = Frame::new(Channel::Bulk, echo(b"frame data"));
+        let control = Frame::new(Channel::Control, echo(b"ping"));
+        link.queue(bulk.clone()).unwrap();
+        link.queue(control.clone()).unwrap();
+        link.flush().unwrap();
+
+        let mut expected = Packet::CreditRequest.serialize_vec();
+        expected.extend(control.serialize_vec());
+        expected.extend(bulk.serialize_vec());
+        assert_eq!(link.port.output, expected);
+    }
+
+    #[test]
+    fn test_request_skips_other_channels() {
+        let event = Frame::new(Channel::Event, echo(b"button"));
+        let mut port = ScriptedPort::new(&[Packet::CreditAdvertise(1000)]);
+        let mut input = port.input.into_inner();
+        input.extend(event.clone().serialize_vec());
+        input.extend(echo(b"hi").serialize_vec());
+        port.input = Cursor::new(input);
+
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert_eq!(link.request(echo(b"hi")).unwrap(), echo(b"hi"));
+        assert_eq!(link.recv_frame().unwrap(), event);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut port = ScriptedPort::new(&[Packet::CreditAdvertise(1000)]);
+        let mut input = port.input.into_inner();
+        input.extend([0x00, 0x42]);
+        //header of an echo with a command nobody knows
+        let mut bad = echo(b"").serialize_vec();
+        bad[3] = 0xee;
+        input.extend(bad);
+        input.extend(echo(b"hi").serialize_vec());
+        port.input = Cursor::new(input);
+
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert_eq!(link.recv().unwrap(), echo(b"hi"));
+        assert_eq!(
+            link.stats(),
+            LinkStats {
+                frames: 2,
+                parse_errors: 1,
+                skipped: 2,
+                dropped: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_old_firmware_unlimited() {
+        let port = ScriptedPort::new(&[Packet::Error(ErrorCode::UnknownCommand.into())]);
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert!(link.credits.is_unlimited());
+        link.send(echo(b"hi")).unwrap();
+    }
+
+    #[test]
+    fn test_request_device_error() {
+        let port = ScriptedPort::new(&[
+            Packet::CreditAdvertise(100),
+            Packet::Credit(6),
+            Packet::Error(ErrorCode::UnknownParam.into()),
+        ]);
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        let err = link
+            .request(Packet::GetParam(PayloadBuf::from_slice(b"NOPE").unwrap()))
+            .unwrap_err();
+        assert!(matches!(err, LinkError::Device(e) if e.code == ErrorCode::UnknownParam));
+        assert!(matches!(link.recv(), Err(LinkError::Closed)));
+    }
+
+    #[test]
+    fn test_late_answer_dropped() {
+        let mut port = ScriptedPort::new(&[Packet::CreditAdvertise(1000)]);
+        let mut input = port.input.into_inner();
+        port.pauses.push_back(input.len() as u64);
+        //the answer to the timed out request, then the fence and the real answer
+        input.extend(echo(b"slow").serialize_vec());
+        input.extend(fence(1).serialize_vec());
+        input.extend(echo(b"hi").serialize_vec());
+        port.input = Cursor::new(input);
+
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert!(link.request(echo(b"slow")).unwrap_err().is_timeout());
+        assert_eq!(link.request(echo(b"hi")).unwrap(), echo(b"hi"));
+        assert_eq!(link.stats().dropped, 1);
+
+        let mut expected = Packet::CreditRequest.serialize_vec();
+        expected.extend(echo(b"slow").serialize_vec());
+        expected.extend(fence(1).serialize_vec());
+        expected.extend(echo(b"hi").serialize_vec());
+        assert_eq!(link.port.output, expected);
+    }
+
+    #[test]
+    fn test_inbox_drops_oldest() {
+        let mut port = ScriptedPort::new(&[Packet::CreditAdvertise(1000)]);
+        let mut input = port.input.into_inner();
+        for i in 0..INBOX_DEPTH + 2 {
+            let event = Frame::new(Channel::Event, echo(format!("{i}").as_bytes()));
+            input.extend(event.serialize_vec());
+        }
+        input.extend(echo(b"hi").serialize_vec());
+        port.input = Cursor::new(input);
+
+        let mut link = Link::new(port);
+        link.sync_credits().unwrap();
+        assert_eq!(link.request(echo(b"hi")).unwrap(), echo(b"hi"));
+        assert_eq!(link.stats().dropped, 2);
+        assert_eq!(link.recv().unwrap(), echo(b"2"));
+    }
+}
@@ -1,52 +1,436 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::{self, Read, Write},
-    sync::mpsc::Sender,
+use std::{fs::OpenOptions, future, net::SocketAddr, path::PathBuf, thread, time::Duration};
+
+use anyhow::Context;
+use clap::{builder::TypedValueParser, Parser, Subcommand};
+use db_link::commands::{Packet, PayloadBuf, MAX_PAYLOAD_SIZE};
+#[cfg(feature = "ui")]
+use db_server::display::SourcePage;
+#[cfg(feature = "mqtt")]
+use db_server::mqtt;
+#[cfg(feature = "notifications")]
+use db_server::notifications::{self, Filter, Forwarder, Mode, NotificationPage};
+use db_server::{
+    bench::{self, SizeResult},
+    control,
+    device::{
+        self, Connector, Daemon, DaemonConfig, DeviceHandle, Port, SerialConnector,
+        VirtualConnector,
+    },
+    discovery::{self, UsbId, ESP_USB_JTAG},
+    display::Display,
+    http,
+    link::{Link, LinkError},
+    metrics::{Collector, MetricsSource},
+    shell::Shell,
+    source::Registry,
+    virtual_device::VirtualDevice,
 };
 
-use anyhow::anyhow;
-use clap::Parser;
-use db_link::commands::{Packet, PayloadBuf, MAX_PACKET_SIZE};
+/// How long the device gets to answer outside of the daemon
+const TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Default)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    serial_port_path: String,
+    /// Serial port of the device, found by USB id when left out
+    #[arg(short, long, global = true)]
+    serial_port_path: Option<PathBuf>,
+    /// USB vid:pid of devices to look for, can be given more than once
+    #[arg(long = "usb-id", global = true, default_values_t = [ESP_USB_JTAG])]
+    usb_ids: Vec<UsbId>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args)]
+struct DaemonArgs {
+    /// Seconds between scans while no device is connected
+    #[arg(long, default_value_t = 2)]
+    retry_secs: u64,
+    /// Seconds without hearing from the device before it's pinged
+    #[arg(long, default_value_t = 5)]
+    heartbeat_secs: u64,
+    /// Seconds between host metrics snapshots on the metrics page
+    #[cfg(feature = "ui")]
+    #[arg(long, default_value_t = 10)]
+    metrics_secs: u64,
+    /// Mount points to report disk usage for
+    #[cfg(feature = "ui")]
+    #[arg(long = "mount", default_values_t = [String::from("/")])]
+    mounts: Vec<String>,
+    /// Serve the HTTP API, on localhost unless an address is given
+    #[arg(long, num_args = 0..=1, default_missing_value = http::DEFAULT_ADDR)]
+    http: Option<SocketAddr>,
+    /// Control socket for db-ctl, $XDG_RUNTIME_DIR/db-server.sock by default
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// Drive an in process virtual device instead of hardware
+    #[arg(long = "virtual")]
+    virtual_device: bool,
+    /// Mirror desktop notifications, as the session's notification server or by monitoring
+    /// the one it has
+    #[cfg(feature = "notifications")]
+    #[arg(long)]
+    notifications: Option<notifications::Mode>,
+    /// Only mirror notifications from these apps, can be given more than once
+    #[cfg(feature = "notifications")]
+    #[arg(long = "allow-app")]
+    allow_apps: Vec<String>,
+    /// Never mirror notifications from these apps, can be given more than once
+    #[cfg(feature = "notifications")]
+    #[arg(long = "deny-app")]
+    deny_apps: Vec<String>,
+    #[cfg(feature = "mqtt")]
+    #[command(flatten)]
+    mqtt: MqttArgs,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(clap::Args)]
+struct MqttArgs {
+    /// Bridge to the MQTT broker on this host
+    #[arg(long = "mqtt")]
+    host: Option<String>,
+    #[arg(long = "mqtt-port", default_value_t = mqtt::DEFAULT_PORT)]
+    port: u16,
+    #[arg(long = "mqtt-client-id", default_value = mqtt::DEFAULT_CLIENT_ID)]
+    client_id: String,
+    #[arg(long = "mqtt-username", requires = "password")]
+    username: Option<String>,
+    #[arg(long = "mqtt-password", requires = "username")]
+    password: Option<String>,
+    /// Topic status and events are published under, the client id by default
+    #[arg(long = "mqtt-base-topic")]
+    base_topic: Option<String>,
+    /// Home Assistant discovery prefix
+    #[arg(long = "mqtt-discovery-prefix", default_value = mqtt::DEFAULT_DISCOVERY_PREFIX)]
+    discovery_prefix: String,
+    /// Don't announce the display for Home Assistant discovery
+    #[arg(long = "no-mqtt-discovery")]
+    no_discovery: bool,
+    /// Show values from a topic on a page, as topic=page or topic=page:label. Can be given
+    /// more than once
+    #[arg(long = "mqtt-subscribe")]
+    subscriptions: Vec<mqtt::Subscription>,
+    /// Button names to announce to Home Assistant, can be given more than once
+    #[arg(long = "mqtt-button")]
+    buttons: Vec<String>,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttArgs {
+    fn config(self) -> Option<mqtt::Config> {
+        let mut config = mqtt::Config::new(&self.host?, self.port);
+        config.base_topic = self.base_topic.unwrap_or_else(|| self.client_id.clone());
+        config.client_id = self.client_id;
+        config.credentials = self.username.zip(self.password);
+        config.discovery_prefix = (!self.no_discovery).then_some(self.discovery_prefix);
+        config.subscriptions = self.subscriptions;
+        config.buttons = self.buttons;
+        Some(config)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Stay connected to the device and keep its screen up to date
+    Daemon(Box<DaemonArgs>),
+    /// Interactive shell for sending packets by hand
+    Shell {
+        /// Talk to an in process virtual device instead of hardware
+        #[arg(long = "virtual")]
+        virtual_device: bool,
+    },
+    /// Print host metrics snapshots, what the display would be fed
+    Metrics {
+        /// Seconds between snapshots
+        #[arg(long, default_value_t = 2)]
+        interval_secs: u64,
+        /// Mount points to report disk usage for
+        #[arg(long = "mount", default_values_t = [String::from("/")])]
+        mounts: Vec<String>,
+    },
+    /// Flood the device with echoes and report latency, throughput and losses
+    Bench {
+        /// Run against an in process virtual device instead of hardware
+        #[arg(long = "virtual")]
+        virtual_device: bool,
+        /// Payload sizes to run, comma separated
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_values_t = bench::DEFAULT_SIZES,
+            value_parser = clap::value_parser!(u64)
+                .range(bench::MIN_SIZE as u64..=MAX_PAYLOAD_SIZE as u64)
+                .map(|size| size as usize),
+        )]
+        sizes: Vec<usize>,
+        /// Echoes sent of each size
+        #[arg(long, default_value_t = 500)]
+        count: u32,
+        /// Echoes in flight at once, 1 measures plain round trips
+        #[arg(long, default_value_t = 4)]
+        window: usize,
+        /// Milliseconds echoes get to come back before they count as lost
+        #[arg(long, default_value_t = 1000)]
+        timeout_ms: u64,
+    },
 }
 
 fn main() -> Result<(), anyhow::Error> {
+    //zbus logs every call it handles at info
+    let filter = "info,zbus=warn,tracing=warn";
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
     let args = Args::parse();
-    let mut serial = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(args.serial_port_path)?;
-    let packets = [
-        Packet::Echo(PayloadBuf::from_slice(b"bye").unwrap()).serialize(),
-        Packet::GetParam(PayloadBuf::from_slice(b"VERSION").unwrap()).serialize(),
-    ];
-    for i in 0..2 {
-        serial.write_all(&packets[i])?;
-        let mut parser = db_link::parser::Parser::new();
-        //read untill we get a packet or error
-        loop {
-            let mut read_buffer = [0u8; MAX_PACKET_SIZE];
-            let bytes = serial.read(&mut read_buffer)?;
-            if bytes > 0 {
-                match parser.parse(&read_buffer) {
-                    Ok(packet) => {
-                        println!("Got: {packet:?}");
-                        break;
-                    }
-                    Err(db_link::parser::Error::InvalidVersion) => {
-                        return Err(anyhow!("Invalid protocol version"));
-                    }
-                    Err(_) => {
-                        println!("{}", String::from_utf8_lossy(&read_buffer[..bytes]));
+    match args.command {
+        None => hello(find_port(args.serial_port_path, &args.usb_ids)?),
+        Some(Command::Daemon(daemon_args)) => {
+            let connector = serial_connector(args.serial_port_path, args.usb_ids);
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(daemon(*daemon_args, connector))
+        }
+        Some(Command::Shell { virtual_device }) => {
+            if virtual_device {
+                let (link, _) = device::handshake(VirtualDevice::new(), "virtual", TIMEOUT)?;
+                return Shell::new(link).run();
+            }
+            Shell::new(open_device(args.serial_port_path, &args.usb_ids)?).run()
+        }
+        Some(Command::Bench {
+            virtual_device,
+            sizes,
+            count,
+            window,
+            timeout_ms,
+        }) => {
+            let config = bench::Config {
+                sizes,
+                count,
+                window,
+                timeout: Duration::from_millis(timeout_ms),
+            };
+            if virtual_device {
+                let (link, _) = device::handshake(VirtualDevice::new(), "virtual", TIMEOUT)?;
+                return run_bench(link, config);
+            }
+            run_bench(open_device(args.serial_port_path, &args.usb_ids)?, config)
+        }
+        Some(Command::Metrics {
+            interval_secs,
+            mounts,
+        }) => {
+            let source = MetricsSource {
+                collector: Collector::new(mounts.into_iter().map(PathBuf::from).collect()),
+                interval: Duration::from_secs(interval_secs),
+            };
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()?;
+            runtime.block_on(async {
+                let mut registry = Registry::new();
+                let mut latest = registry.register("metrics", source)?;
+                while latest.changed().await.is_ok() {
+                    if let Some(snapshot) = &*latest.borrow_and_update() {
+                        println!("{snapshot}");
                     }
                 }
+                Ok(())
+            })
+        }
+    }
+}
+
+fn serial_connector(path: Option<PathBuf>, ids: Vec<UsbId>) -> SerialConnector {
+    match path {
+        Some(path) => SerialConnector::fixed(path),
+        None => SerialConnector::new(ids),
+    }
+}
+
+/// Runs a daemon on its own thread, it stops once the handle and its clones are dropped
+fn spawn_daemon<C: Connector + Send + 'static>(connector: C, config: DaemonConfig) -> DeviceHandle {
+    let (daemon, handle) = Daemon::new(connector, config);
+    thread::spawn(move || daemon.run());
+    handle
+}
+
+/// Draws pages for the device and serves whichever frontends were asked for
+async fn daemon(args: DaemonArgs, connector: SerialConnector) -> Result<(), anyhow::Error> {
+    //bound before touching the device so a second server bails out without opening the port
+    let socket = args.socket.unwrap_or_else(control::default_socket);
+    let listener = control::bind(&socket)
+        .with_context(|| format!("binding control socket {}", socket.display()))?;
+    log::info!("Control socket at {}", socket.display());
+
+    let config = DaemonConfig {
+        retry: Duration::from_secs(args.retry_secs),
+        heartbeat: Duration::from_secs(args.heartbeat_secs),
+        ..Default::default()
+    };
+    let device = if args.virtual_device {
+        spawn_daemon(VirtualConnector, config)
+    } else {
+        spawn_daemon(connector, config)
+    };
+
+    #[cfg_attr(not(feature = "ui"), allow(unused_mut))]
+    let mut registry = Registry::new();
+    let display = Display::new();
+    #[cfg(feature = "ui")]
+    {
+        let source = MetricsSource {
+            collector: Collector::new(args.mounts.into_iter().map(PathBuf::from).collect()),
+            interval: Duration::from_secs(args.metrics_secs),
+        };
+        display.add_page("metrics", SourcePage(registry.register("metrics", source)?));
+    }
+
+    #[cfg(feature = "notifications")]
+    let notifications = {
+        let filter = Filter {
+            allow: args.allow_apps,
+            deny: args.deny_apps,
+        };
+        mirror_notifications(args.notifications, filter, display.clone())
+    };
+    #[cfg(not(feature = "notifications"))]
+    let notifications = future::pending::<Result<(), anyhow::Error>>();
+
+    #[cfg(feature = "mqtt")]
+    let mqtt = {
+        let bridge = args
+            .mqtt
+            .config()
+            .map(|config| mqtt::Bridge::new(config, display.clone(), device.clone()));
+        async {
+            match bridge {
+                Some(bridge) => bridge.run().await,
+                None => future::pending().await,
             }
         }
+    };
+    #[cfg(not(feature = "mqtt"))]
+    let mqtt = future::pending::<()>();
+
+    let http = async {
+        match args.http {
+            Some(addr) => http::serve(addr, display.clone(), device.clone()).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::select! {
+        () = display.clone().run(device.clone(), registry.changes()) => Ok(()),
+        result = http => result.context("HTTP API failed"),
+        result = control::serve(listener, display.clone(), device.clone()) => {
+            result.context("control socket failed")
+        }
+        result = notifications => result,
+        () = mqtt => Ok(()),
+    }
+}
+
+/// Adds a notifications page and keeps it fed from the session bus, never returns without a
+/// `mode`
+#[cfg(feature = "notifications")]
+async fn mirror_notifications(
+    mode: Option<Mode>,
+    filter: Filter,
+    display: Display,
+) -> Result<(), anyhow::Error> {
+    let Some(mode) = mode else {
+        return future::pending().await;
+    };
+    let page = NotificationPage::new();
+    display.add_page("notifications", page.clone());
+    let forwarder = Forwarder {
+        filter,
+        page,
+        display,
+    };
+    let bus = notifications::bus(None).context("connecting to the session bus")?;
+    log::info!("Mirroring desktop notifications as a {mode}");
+    match mode {
+        Mode::Server => {
+            let _connection = notifications::serve(bus, forwarder)
+                .await
+                .with_context(|| format!("serving {}", notifications::NAME))?;
+            future::pending().await
+        }
+        Mode::Monitor => {
+            notifications::monitor(bus, forwarder)
+                .await
+                .context("monitoring notifications")?;
+            anyhow::bail!("session bus connection closed")
+        }
+    }
+}
+
+/// Handshake with the device on the given port or the first one found
+fn open_device(
+    path: Option<PathBuf>,
+    ids: &[UsbId],
+) -> Result<Link<<SerialConnector as Connector>::Port>, anyhow::Error> {
+    let path = find_port(path, ids)?;
+    let name = path.display().to_string();
+    let port = SerialConnector::fixed(path).open(&name)?;
+    let (link, info) = device::handshake(port, &name, TIMEOUT)
+        .with_context(|| format!("no answer from {name}"))?;
+    println!("Connected to {} running {}", info.port, info.version);
+    Ok(link)
+}
+
+/// Prints each size's results as it finishes, then what they mean for redrawing the screen
+fn run_bench<T: Port>(mut link: Link<T>, config: bench::Config) -> Result<(), anyhow::Error> {
+    println!("{}", SizeResult::HEADER);
+    let mut best = 0.0f64;
+    for size in &config.sizes {
+        let config = bench::Config {
+            sizes: vec![*size],
+            ..config.clone()
+        };
+        for result in bench::run(&mut link, &config)? {
+            println!("{result}");
+            best = best.max(result.wire_rate());
+        }
+    }
+    let screen = bench::screen_update_bytes();
+    println!(
+        "A full screen update is {screen} bytes, {:.2} per second at the best rate",
+        best / screen as f64
+    );
+    Ok(())
+}
+
+/// The given port, or the first one with a matching device
+fn find_port(path: Option<PathBuf>, ids: &[UsbId]) -> Result<PathBuf, anyhow::Error> {
+    if let Some(path) = path {
+        return Ok(path);
+    }
+    let found = discovery::scan_system(ids).context("scanning for devices")?;
+    found
+        .into_iter()
+        .next()
+        .map(|candidate| candidate.path)
+        .context("no device found, pass --serial-port-path")
+}
+
+/// Sends a couple of packets and prints the answers
+fn hello(path: PathBuf) -> Result<(), anyhow::Error> {
+    let serial = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut link = Link::new(serial);
+    link.sync_credits()?;
+    let packets = [
+        Packet::Echo(PayloadBuf::from_slice(b"bye").unwrap()),
+        Packet::GetParam(PayloadBuf::from_slice(b"VERSION").unwrap()),
+    ];
+    for packet in packets {
+        match link.request(packet) {
+            Ok(packet) => println!("Got: {packet:?}"),
+            Err(LinkError::Device(error)) => println!("Device error: {error}"),
+            Err(e) => return Err(e.into()),
+        }
     }
     Ok(())
 }
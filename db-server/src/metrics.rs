@@ -0,0 +1,620 @@
+//! Host system metrics read from procfs and sysfs
+//!
+//! `Collector` reads everything under a root directory, `/` on a real system or a fixture tree
+//! in tests, and turns counters into rates between samples.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "ui")]
+use db_ui::pages::system::{self as page, System};
+
+#[cfg(feature = "ui")]
+use crate::render::{Framebuffer, Page};
+use crate::source::DataSource;
+
+/// Busy and total jiffies of one cpu line in /proc/stat
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
+}
+
+impl CpuTimes {
+    /// Share of the time between `earlier` and this spent busy, 0 to 100
+    pub fn percent_since(&self, earlier: &CpuTimes) -> f32 {
+        let total = self.total.saturating_sub(earlier.total);
+        if total == 0 {
+            return 0.0;
+        }
+        self.busy.saturating_sub(earlier.busy) as f32 * 100.0 / total as f32
+    }
+}
+
+/// All cpus first then one entry per core
+pub fn parse_stat(text: &str) -> Vec<CpuTimes> {
+    text.lines()
+        .filter(|line| line.starts_with("cpu"))
+        .filter_map(|line| {
+            let fields: Vec<u64> = line
+                .split_whitespace()
+                .skip(1)
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            //user nice system idle iowait irq softirq steal, guest time is already in user
+            let total: u64 = fields.iter().take(8).sum();
+            let idle = fields.get(3)? + fields.get(4).unwrap_or(&0);
+            Some(CpuTimes {
+                busy: total - idle,
+                total,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Memory {
+    pub total_kib: u64,
+    pub available_kib: u64,
+    pub swap_total_kib: u64,
+    pub swap_free_kib: u64,
+}
+
+impl Memory {
+    pub fn used_percent(&self) -> f32 {
+        if self.total_kib == 0 {
+            return 0.0;
+        }
+        (self.total_kib - self.available_kib.min(self.total_kib)) as f32 * 100.0
+            / self.total_kib as f32
+    }
+}
+
+pub fn parse_meminfo(text: &str) -> Option<Memory> {
+    let mut memory = Memory::default();
+    let mut found = false;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Some(value) = value.split_whitespace().next().and_then(|v| v.parse().ok()) else {
+            continue;
+        };
+        let field = match key {
+            "MemTotal" => {
+                found = true;
+                &mut memory.total_kib
+            }
+            "MemAvailable" => &mut memory.available_kib,
+            "SwapTotal" => &mut memory.swap_total_kib,
+            "SwapFree" => &mut memory.swap_free_kib,
+            _ => continue,
+        };
+        *field = value;
+    }
+    found.then_some(memory)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LoadAvg {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+
+pub fn parse_loadavg(text: &str) -> Option<LoadAvg> {
+    let mut fields = text.split_whitespace().map(|field| field.parse().ok());
+    Some(LoadAvg {
+        one: fields.next()??,
+        five: fields.next()??,
+        fifteen: fields.next()??,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetCounters {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Byte counters per interface, loopback is left out
+pub fn parse_net_dev(text: &str) -> Vec<NetCounters> {
+    text.lines()
+        .filter_map(|line| {
+            let (interface, counters) = line.split_once(':')?;
+            let interface = interface.trim();
+            if interface == "lo" {
+                return None;
+            }
+            let counters: Vec<u64> = counters
+                .split_whitespace()
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            Some(NetCounters {
+                interface: interface.to_string(),
+                rx_bytes: *counters.first()?,
+                tx_bytes: *counters.get(8)?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetRate {
+    pub interface: String,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Temperature {
+    pub label: String,
+    pub celsius: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    pub mount: PathBuf,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl Disk {
+    pub fn used_percent(&self) -> f32 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        (self.total_bytes - self.available_bytes.min(self.total_bytes)) as f32 * 100.0
+            / self.total_bytes as f32
+    }
+}
+
+/// Everything the collector could read, anything missing on this system is None or empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    /// All cpus, since the previous sample or since boot on the first one
+    pub cpu_percent: Option<f32>,
+    pub core_percent: Vec<f32>,
+    pub memory: Option<Memory>,
+    pub load: Option<LoadAvg>,
+    pub temperatures: Vec<Temperature>,
+    /// Empty on the first sample, rates need two
+    pub network: Vec<NetRate>,
+    pub disks: Vec<Disk>,
+}
+
+impl Snapshot {
+    /// Hottest sensor, the one worth showing on a small screen
+    pub fn max_temperature(&self) -> Option<&Temperature> {
+        self.temperatures
+            .iter()
+            .max_by(|a, b| a.celsius.total_cmp(&b.celsius))
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cpu) = self.cpu_percent {
+            writeln!(f, "cpu {cpu:.0}%")?;
+        }
+        if let Some(load) = &self.load {
+            writeln!(
+                f,
+                "load {:.2} {:.2} {:.2}",
+                load.one, load.five, load.fifteen
+            )?;
+        }
+        if let Some(memory) = &self.memory {
+            writeln!(
+                f,
+                "mem {:.0}% of {} MiB",
+                memory.used_percent(),
+                memory.total_kib / 1024
+            )?;
+        }
+        for temp in &self.temperatures {
+            writeln!(f, "{} {:.1}C", temp.label, temp.celsius)?;
+        }
+        for net in &self.network {
+            writeln!(
+                f,
+                "{} rx {:.1} KiB/s tx {:.1} KiB/s",
+                net.interface,
+                net.rx_bytes_per_sec / 1024.0,
+                net.tx_bytes_per_sec / 1024.0
+            )?;
+        }
+        for disk in &self.disks {
+            writeln!(
+                f,
+                "{} {:.0}% of {} GiB",
+                disk.mount.display(),
+                disk.used_percent(),
+                disk.total_bytes >> 30
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Collector {
+    root: PathBuf,
+    mounts: Vec<PathBuf>,
+    last_cpu: Vec<CpuTimes>,
+    last_net: Option<(Instant, Vec<NetCounters>)>,
+}
+
+impl Collector {
+    /// Reads the running system, disk usage is reported for `mounts`
+    pub fn new(mounts: Vec<PathBuf>) -> Self {
+        Self::with_root("/", mounts)
+    }
+
+    /// Reads proc and sys under `root` instead of /
+    pub fn with_root(root: impl Into<PathBuf>, mounts: Vec<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            mounts,
+            last_cpu: vec![],
+            last_net: None,
+        }
+    }
+
+    pub fn sample(&mut self, now: Instant) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+
+        let cpus = self.read("proc/stat").map(|text| parse_stat(&text));
+        if let Some(cpus) = cpus.filter(|cpus| !cpus.is_empty()) {
+            let percents: Vec<f32> = cpus
+                .iter()
+                .enumerate()
+                .map(|(i, times)| {
+                    times.percent_since(self.last_cpu.get(i).unwrap_or(&CpuTimes::default()))
+                })
+                .collect();
+            snapshot.cpu_percent = Some(percents[0]);
+            snapshot.core_percent = percents[1..].to_vec();
+            self.last_cpu = cpus;
+        }
+
+        snapshot.memory = self
+            .read("proc/meminfo")
+            .and_then(|text| parse_meminfo(&text));
+        snapshot.load = self
+            .read("proc/loadavg")
+            .and_then(|text| parse_loadavg(&text));
+
+        snapshot.temperatures = self.thermal_zones();
+        snapshot.temperatures.extend(self.hwmon());
+
+        if let Some(counters) = self.read("proc/net/dev").map(|text| parse_net_dev(&text)) {
+            if let Some((then, last)) = &self.last_net {
+                snapshot.network =
+                    net_rates(&counters, last, now.duration_since(*then).as_secs_f64());
+            }
+            self.last_net = Some((now, counters));
+        }
+
+        snapshot.disks = self.disks();
+        snapshot
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    /// Zones with their type as label, temps are in millidegrees
+    fn thermal_zones(&self) -> Vec<Temperature> {
+        let mut temps = vec![];
+        for dir in sorted_dirs(&self.root.join("sys/class/thermal"), "thermal_zone") {
+            let (Some(label), Some(celsius)) = (
+                read_trimmed(&dir.join("type")),
+                read_milli(&dir.join("temp")),
+            ) else {
+                continue;
+            };
+            temps.push(Temperature { label, celsius });
+        }
+        temps
+    }
+
+    /// Every tempN_input of every chip, labelled `chip label` or `chip tempN` without a label
+    fn hwmon(&self) -> Vec<Temperature> {
+        let mut temps = vec![];
+        for dir in sorted_dirs(&self.root.join("sys/class/hwmon"), "hwmon") {
+            let chip = read_trimmed(&dir.join("name")).unwrap_or_else(|| "hwmon".to_string());
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut inputs: Vec<String> = entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with("temp") && name.ends_with("_input"))
+                .collect();
+            inputs.sort();
+            for input in inputs {
+                let Some(celsius) = read_milli(&dir.join(&input)) else {
+                    continue;
+                };
+                let sensor = input.trim_end_matches("_input");
+                let label = read_trimmed(&dir.join(format!("{sensor}_label")))
+                    .unwrap_or_else(|| sensor.to_string());
+                temps.push(Temperature {
+                    label: format!("{chip} {label}"),
+                    celsius,
+                });
+            }
+        }
+        temps
+    }
+
+    fn disks(&self) -> Vec<Disk> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| {
+                let path = self.root.join(mount.strip_prefix("/").unwrap_or(mount));
+                let stat = match nix::sys::statvfs::statvfs(&path) {
+                    Ok(stat) => stat,
+                    Err(e) => {
+                        log::debug!("statvfs {} failed: {e}", path.display());
+                        return None;
+                    }
+                };
+                let block = stat.fragment_size() as u64;
+                Some(Disk {
+                    mount: mount.clone(),
+                    total_bytes: stat.blocks() as u64 * block,
+                    available_bytes: stat.blocks_available() as u64 * block,
+                })
+            })
+            .collect()
+    }
+}
+
+/// What the db-ui system page shows of the snapshot
+#[cfg(feature = "ui")]
+impl From<&Snapshot> for System {
+    fn from(snapshot: &Snapshot) -> Self {
+        System {
+            cpu_percent: snapshot.cpu_percent,
+            memory_percent: snapshot.memory.as_ref().map(Memory::used_percent),
+            load: snapshot.load.map(|load| load.one),
+            celsius: snapshot.max_temperature().map(|temp| temp.celsius),
+            network: snapshot
+                .network
+                .iter()
+                .map(|net| page::Interface {
+                    name: net.interface.clone(),
+                    rx_bytes_per_sec: net.rx_bytes_per_sec,
+                    tx_bytes_per_sec: net.tx_bytes_per_sec,
+                })
+                .collect(),
+            disks: snapshot
+                .disks
+                .iter()
+                .map(|disk| page::Disk {
+                    mount: disk.mount.display().to_string(),
+                    used_percent: disk.used_percent(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "ui")]
+impl Page for Snapshot {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+        page::draw(target, &System::from(&*self))?;
+        Ok(())
+    }
+}
+
+/// `Collector` sampled every `interval` for the source registry
+pub struct MetricsSource {
+    pub collector: Collector,
+    pub interval: Duration,
+}
+
+impl DataSource for MetricsSource {
+    type Output = Snapshot;
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+        Ok(self.collector.sample(Instant::now()))
+    }
+}
+
+/// Rates of interfaces seen in both samples, counters that went backwards read as 0
+fn net_rates(counters: &[NetCounters], last: &[NetCounters], secs: f64) -> Vec<NetRate> {
+    if secs <= 0.0 {
+        return vec![];
+    }
+    counters
+        .iter()
+        .filter_map(|now| {
+            let then = last.iter().find(|then| then.interface == now.interface)?;
+            Some(NetRate {
+                interface: now.interface.clone(),
+                rx_bytes_per_sec: now.rx_bytes.saturating_sub(then.rx_bytes) as f64 / secs,
+                tx_bytes_per_sec: now.tx_bytes.saturating_sub(then.tx_bytes) as f64 / secs,
+            })
+        })
+        .collect()
+}
+
+/// Entries of `dir` starting with `prefix`, sorted so zone10 comes after zone9
+fn sorted_dirs(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut dirs: Vec<(u32, PathBuf)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let index = name.strip_prefix(prefix)?.parse().ok()?;
+            Some((index, entry.path()))
+        })
+        .collect();
+    dirs.sort();
+    dirs.into_iter().map(|(_, path)| path).collect()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    Some(fs::read_to_string(path).ok()?.trim().to_string())
+}
+
+fn read_milli(path: &Path) -> Option<f32> {
+    read_trimmed(path)?
+        .parse::<i64>()
+        .ok()
+        .map(|milli| milli as f32 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/metrics");
+
+    #[test]
+    fn test_parse_stat() {
+        let text = fs::read_to_string(Path::new(FIXTURES).join("proc/stat")).unwrap();
+        let cpus = parse_stat(&text);
+        assert_eq!(cpus.len(), 3);
+        assert_eq!(
+            cpus[0],
+            CpuTimes {
+                busy: 5668,
+                total: 9390
+            }
+        );
+        let earlier = CpuTimes {
+            busy: cpus[0].busy - 50,
+            total: cpus[0].total - 200,
+        };
+        assert_eq!(cpus[0].percent_since(&earlier), 25.0);
+        assert_eq!(cpus[0].percent_since(&cpus[0]), 0.0);
+    }
+
+    #[test]
+    fn test_parse_meminfo() {
+        let text = fs::read_to_string(Path::new(FIXTURES).join("proc/meminfo")).unwrap();
+        let memory = parse_meminfo(&text).unwrap();
+        assert_eq!(memory.total_kib, 16314352);
+        assert_eq!(memory.available_kib, 12001232);
+        assert_eq!(memory.swap_free_kib, 1048574);
+        assert!((memory.used_percent() - 26.4).abs() < 0.1);
+        assert_eq!(parse_meminfo("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_loadavg() {
+        assert_eq!(
+            parse_loadavg("0.52 0.58 0.59 2/812 21530\n"),
+            Some(LoadAvg {
+                one: 0.52,
+                five: 0.58,
+                fifteen: 0.59
+            })
+        );
+        assert_eq!(parse_loadavg("0.52"), None);
+    }
+
+    #[test]
+    fn test_parse_net_dev() {
+        let text = fs::read_to_string(Path::new(FIXTURES).join("proc/net/dev")).unwrap();
+        let counters = parse_net_dev(&text);
+        assert_eq!(
+            counters[0],
+            NetCounters {
+                interface: "enp5s0".to_string(),
+                rx_bytes: 98123456,
+                tx_bytes: 12345678
+            }
+        );
+        assert_eq!(counters.len(), 2);
+    }
+
+    #[test]
+    fn test_collector() {
+        let mut collector = Collector::with_root(FIXTURES, vec![PathBuf::from("/")]);
+        let start = Instant::now();
+        let snapshot = collector.sample(start);
+        assert!((snapshot.cpu_percent.unwrap() - 60.4).abs() < 0.1);
+        assert_eq!(snapshot.core_percent.len(), 2);
+        assert_eq!(snapshot.load.unwrap().one, 0.52);
+        assert!(snapshot.network.is_empty());
+        let labels: Vec<_> = snapshot
+            .temperatures
+            .iter()
+            .map(|t| t.label.as_str())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "x86_pkg_temp",
+                "acpitz",
+                "k10temp Tctl",
+                "k10temp temp3",
+                "nvme Composite"
+            ]
+        );
+        assert_eq!(snapshot.max_temperature().unwrap().celsius, 51.25);
+        assert_eq!(snapshot.disks.len(), 1);
+        assert!(snapshot.disks[0].total_bytes >= snapshot.disks[0].available_bytes);
+
+        //counters don't move in the fixtures
+        let snapshot = collector.sample(start + Duration::from_secs(2));
+        assert_eq!(snapshot.cpu_percent, Some(0.0));
+        assert_eq!(snapshot.network.len(), 2);
+        assert_eq!(snapshot.network[0].rx_bytes_per_sec, 0.0);
+        assert!(snapshot
+            .to_string()
+            .starts_with("cpu 0%\nload 0.52 0.58 0.59\n"));
+    }
+
+    #[test]
+    fn test_net_rates() {
+        let counters = |rx, tx| {
+            vec![NetCounters {
+                interface: "eth0".to_string(),
+                rx_bytes: rx,
+                tx_bytes: tx,
+            }]
+        };
+        let rates = net_rates(&counters(3000, 500), &counters(1000, 700), 2.0);
+        assert_eq!(rates[0].rx_bytes_per_sec, 1000.0);
+        assert_eq!(rates[0].tx_bytes_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_missing_root() {
+        let mut collector = Collector::with_root("/nonexistent", vec![]);
+        assert_eq!(collector.sample(Instant::now()), Snapshot::default());
+    }
+
+    #[cfg(feature = "ui")]
+    #[test]
+    fn test_system_page() {
+        let mut collector = Collector::with_root(FIXTURES, vec![PathBuf::from("/")]);
+        let start = Instant::now();
+        collector.sample(start);
+        let mut snapshot = collector.sample(start + Duration::from_secs(2));
+        let system = System::from(&snapshot);
+        assert_eq!(system.cpu_percent, Some(0.0));
+        assert_eq!(system.load, Some(0.52));
+        assert_eq!(system.celsius, Some(51.25));
+        assert_eq!(system.network.len(), 2);
+        assert_eq!(system.disks[0].mount, "/");
+
+        let mut frame = Framebuffer::screen();
+        snapshot.draw(&mut frame).unwrap();
+        assert_ne!(frame, Framebuffer::screen());
+    }
+}
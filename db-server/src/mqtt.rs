@@ -0,0 +1,755 @@
+//! MQTT bridge for home automation
+//!
+//! Values published on the subscribed topics are shown on pages, and the device's status and
+//! button presses are published under `Config::base_topic`. With a discovery prefix the
+//! display announces itself for Home Assistant MQTT discovery.
+//!
+//! availability   "online", or "offline" once the connection drops, retained
+//! status         `control::status` JSON, retained
+//! event          `{"event_type": "<button>"}` for every button press
+//! page/set       page name to switch to
+//!
+//! A button press is an Echo carrying the button's name on the event channel.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use db_link::commands::{Channel, Packet};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Publish, QoS};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+
+use crate::{
+    control,
+    device::DeviceHandle,
+    display::Display,
+    render::{Framebuffer, Page},
+};
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_CLIENT_ID: &str = "db-server";
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Requests the client can queue before publishing fails
+const QUEUE: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait before reconnecting after the connection fails
+const RETRY: Duration = Duration::from_secs(5);
+/// The status is published this often on top of whenever it changes, the page can change
+/// without the bridge hearing about it
+const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shows the values published on `filter` on a page, each labelled with `label` or else the
+/// levels of its topic that the wildcards matched, or its last level without wildcards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub filter: String,
+    pub page: String,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SubscriptionError {
+    #[error("expected topic=page or topic=page:label")]
+    MissingPage,
+    #[error("invalid topic filter {0}")]
+    InvalidFilter(String),
+}
+
+impl FromStr for Subscription {
+    type Err = SubscriptionError;
+
+    /// `topic=page` or `topic=page:label`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (filter, target) = s.rsplit_once('=').ok_or(SubscriptionError::MissingPage)?;
+        let (page, label) = match target.split_once(':') {
+            Some((page, label)) => (page, Some(label.to_string())),
+            None => (target, None),
+        };
+        if page.is_empty() {
+            return Err(SubscriptionError::MissingPage);
+        }
+        if filter.is_empty() || !rumqttc::valid_filter(filter) {
+            return Err(SubscriptionError::InvalidFilter(filter.to_string()));
+        }
+        Ok(Self {
+            filter: filter.to_string(),
+            page: page.to_string(),
+            label,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Username and password
+    pub credentials: Option<(String, String)>,
+    /// Where the display publishes, and takes commands
+    pub base_topic: String,
+    /// Home Assistant discovery prefix, the display isn't announced without one
+    pub discovery_prefix: Option<String>,
+    pub subscriptions: Vec<Subscription>,
+    /// Buttons announced to Home Assistant, presses of others are still published
+    pub buttons: Vec<String>,
+}
+
+impl Config {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            credentials: None,
+            base_topic: DEFAULT_CLIENT_ID.to_string(),
+            discovery_prefix: Some(DEFAULT_DISCOVERY_PREFIX.to_string()),
+            subscriptions: vec![],
+            buttons: vec![],
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.base_topic)
+    }
+
+    /// Home Assistant discovery topics and configs
+    fn discovery(&self, pages: Vec<String>) -> Vec<(String, Value)> {
+        let Some(prefix) = &self.discovery_prefix else {
+            return vec![];
+        };
+        //discovery topics only allow these
+        let node: String = self
+            .client_id
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+        let status = self.topic("status");
+        let mut entities = vec![
+            (
+                "binary_sensor",
+                "connected",
+                json!({
+                    "name": "Connected",
+                    "device_class": "connectivity",
+                    "state_topic": status,
+                    "value_template": "{{ 'ON' if value_json.device.connected else 'OFF' }}",
+                }),
+            ),
+            (
+                "select",
+                "page",
+                json!({
+                    "name": "Page",
+                    "state_topic": status,
+                    "value_template": "{{ value_json.page }}",
+                    "command_topic": self.topic("page/set"),
+                    "options": pages,
+                }),
+            ),
+        ];
+        if !self.buttons.is_empty() {
+            entities.push((
+                "event",
+                "button",
+                json!({
+                    "name": "Button",
+                    "state_topic": self.topic("event"),
+                    "event_types": self.buttons,
+                }),
+            ));
+        }
+        let device = json!({
+            "identifiers": [node],
+            "name": "Desk display",
+            "model": "db",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        entities
+            .into_iter()
+            .map(|(component, object, mut config)| {
+                let config_map = config.as_object_mut().unwrap();
+                config_map.insert("unique_id".into(), format!("{node}_{object}").into());
+                config_map.insert(
+                    "availability_topic".into(),
+                    self.topic("availability").into(),
+                );
+                config_map.insert("device".into(), device.clone());
+                let topic = format!("{prefix}/{component}/{node}/{object}/config");
+                (topic, config)
+            })
+            .collect()
+    }
+}
+
+/// Labelled values from MQTT, clones share them
+#[derive(Debug, Clone, Default)]
+pub struct DataPage {
+    values: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl DataPage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows `value` for `label`, labels keep the order they were first seen in. Returns
+    /// whether anything changed
+    pub fn set(&self, label: &str, value: &str) -> bool {
+        let mut values = self.values.lock().unwrap();
+        match values.iter_mut().find(|(name, _)| name == label) {
+            Some((_, old)) if old == value => false,
+            Some((_, old)) => {
+                *old = value.to_string();
+                true
+            }
+            None => {
+                values.push((label.to_string(), value.to_string()));
+                true
+            }
+        }
+    }
+
+    pub fn values(&self) -> Vec<(String, String)> {
+        self.values.lock().unwrap().clone()
+    }
+}
+
+impl Page for DataPage {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+        target.clear(BinaryColor::On)?;
+        let values = self.values();
+        let text = match values.is_empty() {
+            true => "Waiting for data".to_string(),
+            false => values
+                .iter()
+                .map(|(label, value)| format!("{label}: {value}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::Off);
+        Text::with_baseline(&text, Point::new(2, 2), style, Baseline::Top).draw(target)?;
+        Ok(())
+    }
+}
+
+/// What the event loop task hands the bridge
+enum Incoming {
+    Connected,
+    Publish(Publish),
+}
+
+/// Keeps the broker and the display in sync
+pub struct Bridge {
+    config: Config,
+    display: Display,
+    device: DeviceHandle,
+    pages: HashMap<String, DataPage>,
+}
+
+impl Bridge {
+    /// Adds a page to `display` for every page the subscriptions name
+    pub fn new(config: Config, display: Display, device: DeviceHandle) -> Self {
+        let mut pages = HashMap::new();
+        for subscription in &config.subscriptions {
+            pages.entry(subscription.page.clone()).or_insert_with(|| {
+                let page = DataPage::new();
+                display.add_page(&subscription.page, page.clone());
+                page
+            });
+        }
+        Self {
+            config,
+            display,
+            device,
+            pages,
+        }
+    }
+
+    pub fn page(&self, name: &str) -> Option<DataPage> {
+        self.pages.get(name).cloned()
+    }
+
+    /// Connects and keeps reconnecting to the broker, returns once the daemon stops
+    pub async fn run(self) {
+        let mut options =
+            MqttOptions::new(&self.config.client_id, &self.config.host, self.config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            self.config.topic("availability"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = &self.config.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut event_loop) = AsyncClient::new(options, QUEUE);
+        //polled on its own, the event loop isn't safe to cancel mid poll in a select
+        let (incoming_tx, mut incoming) = mpsc::channel(QUEUE);
+        let poller = tokio::spawn(async move {
+            loop {
+                let event = match event_loop.poll().await {
+                    Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => Incoming::Connected,
+                    Ok(Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                        Incoming::Publish(publish)
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        log::warn!("MQTT connection failed: {e}");
+                        time::sleep(RETRY).await;
+                        continue;
+                    }
+                };
+                if incoming_tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut status = self.device.watch_status();
+        let mut events = self.device.subscribe();
+        let mut interval = time::interval(STATUS_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(event) = incoming.recv() => match event {
+                    Incoming::Connected => self.connected(&client),
+                    Incoming::Publish(publish) => self.received(&client, &publish),
+                },
+                changed = status.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    self.publish_status(&client);
+                }
+                event = events.recv() => match event {
+                    Ok(frame) if frame.channel == Channel::Event => {
+                        self.publish_event(&client, &frame.packet);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("Missed {missed} device events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = interval.tick() => self.publish_status(&client),
+            }
+        }
+        poller.abort();
+    }
+
+    /// Subscribes and publishes everything retained again, the broker may have lost it
+    fn connected(&self, client: &AsyncClient) {
+        log::info!("Connected to MQTT broker {}", self.config.host);
+        let filters = self
+            .config
+            .subscriptions
+            .iter()
+            .map(|subscription| subscription.filter.clone())
+            .chain([self.config.topic("page/set")]);
+        for filter in filters {
+            if let Err(e) = client.try_subscribe(&filter, QoS::AtLeastOnce) {
+                log::warn!("Subscribing to {filter} failed: {e}");
+            }
+        }
+        publish(client, self.config.topic("availability"), true, "online");
+        for (topic, config) in self.config.discovery(self.display.pages()) {
+            publish(client, topic, true, config.to_string());
+        }
+        self.publish_status(client);
+    }
+
+    fn received(&self, client: &AsyncClient, publish: &Publish) {
+        let value = String::from_utf8_lossy(&publish.payload);
+        let value = value.trim();
+        if publish.topic == self.config.topic("page/set") {
+            match self.display.show_page(value) {
+                Ok(()) => self.publish_status(client),
+                Err(e) => log::warn!("Can't switch pages from MQTT: {e}"),
+            }
+            return;
+        }
+        let mut changed = false;
+        for subscription in &self.config.subscriptions {
+            if !rumqttc::matches(&publish.topic, &subscription.filter) {
+                continue;
+            }
+            let label = match &subscription.label {
+                Some(label) => label.clone(),
+                None => wildcard_levels(&publish.topic, &subscription.filter),
+            };
+            if let Some(page) = self.pages.get(&subscription.page) {
+                changed |= page.set(&label, value);
+            }
+        }
+        if changed {
+            self.display.redraw();
+        }
+    }
+
+    fn publish_status(&self, client: &AsyncClient) {
+        let status = control::status(&self.display, &self.device);
+        publish(
+            client,
+            self.config.topic("status"),
+            true,
+            status.to_string(),
+        );
+    }
+
+    fn publish_event(&self, client: &AsyncClient, packet: &Packet) {
+        let Packet::Echo(name) = packet else {
+            log::debug!("Ignoring device event {packet:?}");
+            return;
+        };
+        let name = String::from_utf8_lossy(name);
+        let event = json!({ "event_type": name });
+        publish(client, self.config.topic("event"), false, event.to_string());
+    }
+}
+
+/// The levels of `topic` matched by wildcards in `filter`, or the last level without any
+fn wildcard_levels(topic: &str, filter: &str) -> String {
+    let mut levels = topic.split('/');
+    let mut matched = vec![];
+    for level in filter.split('/') {
+        match level {
+            "+" => matched.extend(levels.next()),
+            "#" => matched.extend(levels.by_ref()),
+            _ => _ = levels.next(),
+        }
+    }
+    match matched.is_empty() {
+        true => topic.rsplit('/').next().unwrap_or_default().to_string(),
+        false => matched.join("/"),
+    }
+}
+
+/// Queues a publish, dropped with a warning when the queue is full
+fn publish(client: &AsyncClient, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+    if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, retain, payload) {
+        log::warn!("Publishing to {topic} failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, SubAck, SubscribeReasonCode};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        device::{Connector, Daemon, DaemonConfig, Status},
+        virtual_device::{Buttons, VirtualDevice},
+    };
+
+    const MAX_PACKET: usize = 64 * 1024;
+
+    #[derive(Default)]
+    struct BrokerState {
+        /// Filters each client subscribed to and where its packets go
+        clients: Vec<(Vec<String>, mpsc::UnboundedSender<rumqttc::Packet>)>,
+        retained: HashMap<String, Vec<u8>>,
+        events: Vec<Vec<u8>>,
+    }
+
+    /// Just enough of an MQTT 3.1.1 broker, everything is delivered at QoS 0
+    #[derive(Clone)]
+    struct Broker {
+        state: Arc<Mutex<BrokerState>>,
+        port: u16,
+    }
+
+    impl Broker {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let broker = Self {
+                state: Arc::default(),
+                port: listener.local_addr().unwrap().port(),
+            };
+            let accepting = broker.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(accepting.clone().client(stream));
+                }
+            });
+            broker
+        }
+
+        async fn client(self, stream: TcpStream) {
+            let (mut read, mut write) = stream.into_split();
+            let (tx, mut rx) = mpsc::unbounded_channel::<rumqttc::Packet>();
+            tokio::spawn(async move {
+                while let Some(packet) = rx.recv().await {
+                    let mut out = BytesMut::new();
+                    packet.write(&mut out, MAX_PACKET).unwrap();
+                    if write.write_all(&out).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            let index = {
+                let mut state = self.state.lock().unwrap();
+                state.clients.push((vec![], tx.clone()));
+                state.clients.len() - 1
+            };
+            let mut buf = BytesMut::new();
+            loop {
+                let packet = match rumqttc::Packet::read(&mut buf, MAX_PACKET) {
+                    Ok(packet) => packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        if read.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("bad packet from client: {e}"),
+                };
+                let reply = match packet {
+                    rumqttc::Packet::Connect(_) => Some(rumqttc::Packet::ConnAck(ConnAck::new(
+                        ConnectReturnCode::Success,
+                        false,
+                    ))),
+                    rumqttc::Packet::Subscribe(subscribe) => {
+                        let mut codes = vec![];
+                        for filter in subscribe.filters {
+                            codes.push(SubscribeReasonCode::Success(QoS::AtMostOnce));
+                            let mut state = self.state.lock().unwrap();
+                            for (topic, payload) in &state.retained {
+                                if rumqttc::matches(topic, &filter.path) {
+                                    _ = tx.send(message(topic, payload.clone()));
+                                }
+                            }
+                            state.clients[index].0.push(filter.path);
+                        }
+                        Some(rumqttc::Packet::SubAck(SubAck::new(subscribe.pkid, codes)))
+                    }
+                    rumqttc::Packet::Publish(publish) => {
+                        self.publish(&publish.topic, &publish.payload, publish.retain);
+                        let pkid = publish.pkid;
+                        (publish.qos != QoS::AtMostOnce)
+                            .then(|| rumqttc::Packet::PubAck(PubAck::new(pkid)))
+                    }
+                    rumqttc::Packet::PingReq => Some(rumqttc::Packet::PingResp),
+                    rumqttc::Packet::Disconnect => return,
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    _ = tx.send(reply);
+                }
+            }
+        }
+
+        /// Delivers to every client subscribed to `topic`
+        fn publish(&self, topic: &str, payload: &[u8], retain: bool) {
+            let mut state = self.state.lock().unwrap();
+            if retain {
+                state.retained.insert(topic.to_string(), payload.to_vec());
+            }
+            if topic.ends_with("/event") {
+                state.events.push(payload.to_vec());
+            }
+            for (filters, tx) in &state.clients {
+                if filters.iter().any(|filter| rumqttc::matches(topic, filter)) {
+                    _ = tx.send(message(topic, payload.to_vec()));
+                }
+            }
+        }
+
+        fn retained(&self, topic: &str) -> Option<Value> {
+            let state = self.state.lock().unwrap();
+            let payload = state.retained.get(topic)?;
+            Some(
+                serde_json::from_slice(payload).unwrap_or_else(|_| {
+                    Value::String(String::from_utf8_lossy(payload).into_owned())
+                }),
+            )
+        }
+
+        fn subscribed(&self, filter: &str) -> bool {
+            let state = self.state.lock().unwrap();
+            state
+                .clients
+                .iter()
+                .any(|(filters, _)| filters.iter().any(|f| f == filter))
+        }
+    }
+
+    fn message(topic: &str, payload: Vec<u8>) -> rumqttc::Packet {
+        rumqttc::Packet::Publish(Publish::new(topic, QoS::AtMostOnce, payload))
+    }
+
+    /// Virtual devices whose buttons the test can press
+    struct ButtonConnector(Arc<Mutex<Option<Buttons>>>);
+
+    impl Connector for ButtonConnector {
+        type Port = VirtualDevice;
+
+        fn candidates(&mut self) -> Vec<String> {
+            vec!["virtual".to_string()]
+        }
+
+        fn open(&mut self, _name: &str) -> std::io::Result<Self::Port> {
+            let device = VirtualDevice::new();
+            *self.0.lock().unwrap() = Some(device.buttons());
+            Ok(device)
+        }
+    }
+
+    async fn wait_until(mut f: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if f() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn test_subscription() {
+        assert_eq!(
+            "home/+/temperature=home".parse(),
+            Ok(Subscription {
+                filter: "home/+/temperature".to_string(),
+                page: "home".to_string(),
+                label: None,
+            })
+        );
+        let subscription: Subscription = "office/co2=office:CO2 ppm".parse().unwrap();
+        assert_eq!(subscription.label.as_deref(), Some("CO2 ppm"));
+        assert_eq!(
+            "office/co2".parse::<Subscription>(),
+            Err(SubscriptionError::MissingPage)
+        );
+        assert_eq!(
+            "office/#/co2=office".parse::<Subscription>(),
+            Err(SubscriptionError::InvalidFilter("office/#/co2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_wildcard_levels() {
+        assert_eq!(
+            wildcard_levels("home/kitchen/temperature", "home/+/temperature"),
+            "kitchen"
+        );
+        assert_eq!(
+            wildcard_levels("home/kitchen/light/state", "home/#"),
+            "kitchen/light/state"
+        );
+        assert_eq!(wildcard_levels("office/co2", "office/co2"), "co2");
+    }
+
+    #[test]
+    fn test_data_page() {
+        let page = DataPage::new();
+        assert!(page.set("temperature", "21.5"));
+        assert!(page.set("humidity", "40"));
+        assert!(!page.set("temperature", "21.5"));
+        assert!(page.set("temperature", "22"));
+        assert_eq!(
+            page.values(),
+            [
+                ("temperature".to_string(), "22".to_string()),
+                ("humidity".to_string(), "40".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bridge() {
+        let broker = Broker::start().await;
+        let buttons = Arc::default();
+        let (daemon, device) = Daemon::new(
+            ButtonConnector(Arc::clone(&buttons)),
+            DaemonConfig::default(),
+        );
+        thread::spawn(move || daemon.run());
+        let display = Display::new();
+        display.add_page("metrics", Framebuffer::screen());
+
+        let mut config = Config::new("127.0.0.1", broker.port);
+        config.subscriptions = vec![
+            "home/+/temperature=home".parse().unwrap(),
+            "office/co2=office:CO2".parse().unwrap(),
+        ];
+        config.buttons = vec!["next".to_string()];
+        let bridge = Bridge::new(config, display.clone(), device.clone());
+        let home = bridge.page("home").unwrap();
+        assert_eq!(display.pages(), ["metrics", "home", "office"]);
+        tokio::spawn(bridge.run());
+
+        wait_until(|| broker.subscribed("db-server/page/set")).await;
+        assert_eq!(
+            broker.retained("db-server/availability"),
+            Some(Value::String("online".to_string()))
+        );
+        let select = broker
+            .retained("homeassistant/select/db-server/page/config")
+            .unwrap();
+        assert_eq!(select["options"], json!(["metrics", "home", "office"]));
+        assert_eq!(select["command_topic"], "db-server/page/set");
+        assert!(broker
+            .retained("homeassistant/event/db-server/button/config")
+            .is_some());
+
+        broker.publish("home/kitchen/temperature", b"21.5", false);
+        broker.publish("home/attic/humidity", b"80", false);
+        wait_until(|| !home.values().is_empty()).await;
+        assert_eq!(home.values(), [("kitchen".to_string(), "21.5".to_string())]);
+
+        broker.publish("db-server/page/set", b"office", false);
+        wait_until(|| display.current_page().as_deref() == Some("office")).await;
+        wait_until(|| {
+            broker
+                .retained("db-server/status")
+                .is_some_and(|status| status["page"] == "office")
+        })
+        .await;
+
+        //status is published again once the device connects
+        let mut status = device.watch_status();
+        status
+            .wait_for(|status| matches!(status, Status::Connected(_)))
+            .await
+            .unwrap();
+        wait_until(|| {
+            broker
+                .retained("db-server/status")
+                .is_some_and(|status| status["device"]["connected"] == true)
+        })
+        .await;
+        buttons.lock().unwrap().as_ref().unwrap().press("next");
+        wait_until(|| !broker.state.lock().unwrap().events.is_empty()).await;
+        let event = broker.state.lock().unwrap().events[0].clone();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&event).unwrap(),
+            json!({"event_type": "next"})
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Mirrors desktop notifications from the session bus onto the display
+//!
+//! As the `Server` the daemon owns `org.freedesktop.Notifications` and is the desktop's
+//! notification daemon. As a `Monitor` it watches the `Notify` calls going to whatever daemon
+//! the desktop already has, which keeps showing them as usual. Either way notifications the
+//! `Filter` lets through pop up as a message and are listed on the notifications page.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use db_ui::pages::notifications::{self as page, Notification};
+use futures_lite::StreamExt;
+use thiserror::Error;
+use zbus::{
+    connection, fdo::MonitoringProxy, message, zvariant::OwnedValue, Connection, MatchRule,
+    MessageStream,
+};
+
+use crate::{
+    display::{Display, Message, NOTIFICATION_TIME},
+    render::{Framebuffer, Page},
+};
+
+pub const NAME: &str = "org.freedesktop.Notifications";
+pub const PATH: &str = "/org/freedesktop/Notifications";
+/// How many notifications the page keeps, more than fit on screen
+const KEEP: usize = 16;
+
+/// `Notify` arguments: app name, replaces id, icon, summary, body, actions, hints and timeout
+type NotifyArgs = (
+    String,
+    u32,
+    String,
+    String,
+    String,
+    Vec<String>,
+    HashMap<String, OwnedValue>,
+    i32,
+);
+
+/// How to get at the notifications on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Be the notification daemon, fails if the desktop already has one
+    Server,
+    /// Watch notifications sent to the desktop's own daemon
+    Monitor,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("expected server or monitor")]
+pub struct ModeError;
+
+impl FromStr for Mode {
+    type Err = ModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(Mode::Server),
+            "monitor" => Ok(Mode::Monitor),
+            _ => Err(ModeError),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Server => f.write_str("server"),
+            Mode::Monitor => f.write_str("monitor"),
+        }
+    }
+}
+
+/// Which apps get their notifications shown, by app name ignoring case
+/// Denied apps never are, with an allow list only the apps on it are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl Filter {
+    pub fn allows(&self, app: &str) -> bool {
+        let listed = |apps: &[String]| apps.iter().any(|name| name.eq_ignore_ascii_case(app));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// Page listing the latest notifications, clones share the list
+#[derive(Debug, Clone, Default)]
+pub struct NotificationPage {
+    recent: Arc<Mutex<VecDeque<Notification>>>,
+}
+
+impl NotificationPage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `notification` at the top, dropping the oldest past `KEEP`
+    pub fn push(&self, notification: Notification) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_front(notification);
+        recent.truncate(KEEP);
+    }
+
+    /// Newest first
+    pub fn recent(&self) -> Vec<Notification> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Page for NotificationPage {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+        let recent = self.recent();
+        page::draw(target, &recent)?;
+        Ok(())
+    }
+}
+
+/// Puts the notifications the filter lets through on the display
+#[derive(Clone)]
+pub struct Forwarder {
+    pub filter: Filter,
+    pub page: NotificationPage,
+    pub display: Display,
+}
+
+impl Forwarder {
+    /// `expire_timeout` is the `Notify` argument, milliseconds or -1 to leave it to us.
+    /// Returns whether it was shown
+    pub fn forward(&self, notification: Notification, expire_timeout: i32) -> bool {
+        if !self.filter.allows(&notification.app) {
+            log::debug!("Filtered out notification from {}", notification.app);
+            return false;
+        }
+        log::info!(
+            "Notification from {}: {}",
+            notification.app,
+            notification.summary
+        );
+        //0 is never expiring, which would leave it over the page for good
+        let time = match u64::try_from(expire_timeout) {
+            Ok(0) | Err(_) => NOTIFICATION_TIME,
+            Ok(millis) => Duration::from_millis(millis),
+        };
+        let message = Message {
+            title: Some(notification.summary.clone()),
+            body: match notification.body.is_empty() {
+                true => notification.app.clone(),
+                false => format!("{}: {}", notification.app, notification.body),
+            },
+        };
+        self.display.show_message(message, time);
+        self.page.push(notification);
+        true
+    }
+}
+
+/// `org.freedesktop.Notifications` that shows everything on the display
+pub struct Server {
+    forwarder: Forwarder,
+    last_id: AtomicU32,
+}
+
+impl Server {
+    pub fn new(forwarder: Forwarder) -> Self {
+        Self {
+            forwarder,
+            last_id: AtomicU32::new(0),
+        }
+    }
+}
+
+#[zbus::interface(name = "org.freedesktop.Notifications")]
+impl Server {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: String,
+        replaces_id: u32,
+        _app_icon: String,
+        summary: String,
+        body: String,
+        _actions: Vec<String>,
+        _hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+    ) -> u32 {
+        let notification = Notification {
+            app: app_name,
+            summary,
+            body,
+        };
+        self.forwarder.forward(notification, expire_timeout);
+        match replaces_id {
+            0 => self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            id => id,
+        }
+    }
+
+    /// Nothing to close, messages go away on their own
+    fn close_notification(&self, _id: u32) {}
+
+    fn get_capabilities(&self) -> Vec<String> {
+        vec!["body".to_string()]
+    }
+
+    /// Name, vendor, version and the version of the spec
+    fn get_server_information(&self) -> (String, String, String, String) {
+        (
+            env!("CARGO_PKG_NAME").to_string(),
+            "db".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+            "1.2".to_string(),
+        )
+    }
+}
+
+/// The session bus, or the bus at `address`
+pub fn bus(address: Option<&str>) -> zbus::Result<connection::Builder<'static>> {
+    match address {
+        Some(address) => connection::Builder::address(address),
+        None => connection::Builder::session(),
+    }
+}
+
+/// Serves `org.freedesktop.Notifications` on `bus`, the connection is kept open as long as
+/// the returned one is
+pub async fn serve(bus: connection::Builder<'_>, forwarder: Forwarder) -> zbus::Result<Connection> {
+    bus.name(NAME)?
+        .serve_at(PATH, Server::new(forwarder))?
+        .build()
+        .await
+}
+
+/// Forwards the notifications sent on `bus` until the connection drops
+pub async fn monitor(bus: connection::Builder<'_>, forwarder: Forwarder) -> zbus::Result<()> {
+    let connection = bus.build().await?;
+    let rule = MatchRule::builder()
+        .msg_type(message::Type::MethodCall)
+        .interface(NAME)?
+        .member("Notify")?
+        .build();
+    MonitoringProxy::new(&connection)
+        .await?
+        .become_monitor(&[rule], 0)
+        .await?;
+    let mut messages = MessageStream::from(connection);
+    while let Some(message) = messages.next().await {
+        let message = message?;
+        let header = message.header();
+        if header.message_type() != message::Type::MethodCall
+            || header.member().is_none_or(|member| member != "Notify")
+        {
+            continue;
+        }
+        match message.body().deserialize::<NotifyArgs>() {
+            Ok((app, _, _, summary, body, _, _, expire_timeout)) => {
+                let notification = Notification { app, summary, body };
+                forwarder.forward(notification, expire_timeout);
+            }
+            Err(e) => log::warn!("Couldn't read a notification: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    use super::*;
+
+    /// Private session bus, killed on drop
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        /// Fails the test when there's no dbus-daemon to run, the feature can't be tested
+        /// without one
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("these tests need dbus-daemon on PATH");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Self {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn connect(&self) -> connection::Builder<'static> {
+            bus(Some(&self.address)).unwrap()
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            _ = self.daemon.kill();
+            _ = self.daemon.wait();
+        }
+    }
+
+    fn forwarder(filter: Filter) -> Forwarder {
+        Forwarder {
+            filter,
+            page: NotificationPage::new(),
+            display: Display::new(),
+        }
+    }
+
+    #[zbus::proxy(
+        interface = "org.freedesktop.Notifications",
+        default_service = "org.freedesktop.Notifications",
+        default_path = "/org/freedesktop/Notifications"
+    )]
+    trait Notifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: &str,
+            replaces_id: u32,
+            app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: &[&str],
+            hints: HashMap<&str, zbus::zvariant::Value<'_>>,
+            expire_timeout: i32,
+        ) -> zbus::Result<u32>;
+    }
+
+    async fn notify(proxy: &NotificationsProxy<'_>, app: &str, summary: &str) -> u32 {
+        proxy
+            .notify(app, 0, "", summary, "body", &[], HashMap::new(), -1)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            allow: vec![],
+            deny: vec!["Slack".to_string()],
+        };
+        assert!(filter.allows("firefox"));
+        assert!(!filter.allows("slack"));
+        let filter = Filter {
+            allow: vec!["firefox".to_string(), "slack".to_string()],
+            deny: vec!["slack".to_string()],
+        };
+        assert!(filter.allows("Firefox"));
+        assert!(!filter.allows("slack"));
+        assert!(!filter.allows("evolution"));
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let bus = Bus::start();
+        let filter = Filter {
+            allow: vec![],
+            deny: vec!["spam".to_string()],
+        };
+        let forwarder = forwarder(filter);
+        let _server = serve(bus.connect(), forwarder.clone()).await.unwrap();
+
+        let client = bus.connect().build().await.unwrap();
+        let proxy = NotificationsProxy::new(&client).await.unwrap();
+        assert_eq!(notify(&proxy, "mail", "3 new messages").await, 1);
+        assert_eq!(notify(&proxy, "spam", "buy now").await, 2);
+        assert_eq!(
+            forwarder.display.message(),
+            Some(Message {
+                title: Some("3 new messages".to_string()),
+                body: "mail: body".to_string(),
+            })
+        );
+        let recent = forwarder.page.recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].app, "mail");
+    }
+
+    #[tokio::test]
+    async fn test_monitor() {
+        let bus = Bus::start();
+        //the desktop's own daemon, which shows everything
+        let desktop = forwarder(Filter::default());
+        let _server = serve(bus.connect(), desktop.clone()).await.unwrap();
+
+        let watched = forwarder(Filter {
+            allow: vec!["build".to_string()],
+            deny: vec![],
+        });
+        tokio::spawn(monitor(bus.connect(), watched.clone()));
+        let client = bus.connect().build().await.unwrap();
+        let proxy = NotificationsProxy::new(&client).await.unwrap();
+        //the monitor may not be watching yet, keep notifying until it's seen one
+        for _ in 0..100 {
+            notify(&proxy, "chat", "hi").await;
+            notify(&proxy, "build", "passed").await;
+            if !watched.page.recent().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(watched.page.recent().iter().all(|n| n.app == "build"));
+        assert_eq!(watched.page.recent()[0].summary, "passed");
+        assert!(desktop.page.recent().iter().any(|n| n.app == "chat"));
+    }
+}
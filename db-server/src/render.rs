@@ -0,0 +1,314 @@
+//! Draws pages on the host and streams them to the device as region updates
+//!
+//! Pages draw into a `Framebuffer` the size of the device screen. `FrameSender` diffs each
+//! frame against the last one the device got, so only the rows that changed go over the link.
+
+use std::convert::Infallible;
+
+use db_link::{
+    commands::Packet,
+    region::{self, DirtyRegion, Rect, RefreshKind, RegionUpdate},
+};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use crate::device::{DeviceError, DeviceHandle};
+
+/// The 2.13" ssd1680 panel, rotated 90 degrees like the firmware draws it
+pub const WIDTH: u16 = 250;
+pub const HEIGHT: u16 = 122;
+/// Separate changed areas sent before they collapse into one bounding box
+const DIFF_RECTS: usize = 8;
+
+/// Something that can be drawn to the screen, such as a db-ui page
+pub trait Page {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()>;
+}
+
+/// 1 bit per pixel in the `RegionUpdate` layout, rows MSB first and padded to a whole byte
+/// On pixels are set bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    /// All pixels off
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; Self::stride_of(width) * height as usize],
+        }
+    }
+
+    /// Framebuffer the size of the device screen
+    pub fn screen() -> Self {
+        Self::new(WIDTH, HEIGHT)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    fn stride_of(width: u16) -> usize {
+        (width as usize).div_ceil(8)
+    }
+
+    fn stride(&self) -> usize {
+        Self::stride_of(self.width)
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Off outside of the screen
+    pub fn pixel(&self, x: u16, y: u16) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+        let byte = self.pixels[y as usize * self.stride() + x as usize / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Pixels outside of the screen are dropped, like drawing off the edge
+    pub fn set_pixel(&mut self, x: u16, y: u16, on: bool) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let index = y as usize * self.stride() + x as usize / 8;
+        let mask = 0x80 >> (x % 8);
+        if on {
+            self.pixels[index] |= mask;
+        } else {
+            self.pixels[index] &= !mask;
+        }
+    }
+
+    /// Pixels of `rect` packed for a `RegionUpdate`
+    pub fn region(&self, rect: Rect) -> Vec<u8> {
+        let mut packed = Framebuffer::new(rect.width, rect.height);
+        for y in 0..rect.height {
+            for x in 0..rect.width {
+                packed.set_pixel(x, y, self.pixel(rect.x + x, rect.y + y));
+            }
+        }
+        packed.pixels
+    }
+
+    pub fn apply(&mut self, update: &RegionUpdate) {
+        for (x, y, on) in update.iter() {
+            self.set_pixel(x, y, on);
+        }
+    }
+
+    /// Rects covering every pixel that differs from `other`, x is rounded out to whole bytes
+    /// Framebuffers of different sizes differ everywhere
+    pub fn diff(&self, other: &Framebuffer) -> Vec<Rect> {
+        if self.width != other.width || self.height != other.height {
+            return vec![self.bounds()];
+        }
+        let stride = self.stride();
+        let mut dirty = DirtyRegion::<DIFF_RECTS>::new();
+        let rows = self.pixels.chunks(stride).zip(other.pixels.chunks(stride));
+        for (y, (row, other)) in rows.enumerate() {
+            let changed = |(a, b): (&u8, &u8)| a != b;
+            let Some(first) = row.iter().zip(other).position(changed) else {
+                continue;
+            };
+            //found a difference going forwards so there is one going backwards
+            let last = stride - 1 - row.iter().zip(other).rev().position(changed).unwrap();
+            let x = first as u16 * 8;
+            let right = ((last as u16 + 1) * 8).min(self.width);
+            dirty.add(Rect::new(x, y as u16, right - x, 1));
+        }
+        dirty.rects().to_vec()
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u16::try_from(point.x), u16::try_from(point.y)) {
+                self.set_pixel(x, y, color.is_on());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fixed image, drawn from the top left
+impl Page for Framebuffer {
+    fn draw(&mut self, target: &mut Framebuffer) -> anyhow::Result<()> {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                target.set_pixel(x, y, self.pixel(x, y));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remembers what the device is showing and sends it only what changed
+#[derive(Debug, Default)]
+pub struct FrameSender {
+    sent: Option<Framebuffer>,
+}
+
+impl FrameSender {
+    pub fn new() -> Self {
+        Self { sent: None }
+    }
+
+    /// Forget what the device shows, the next frame is sent whole with a full refresh
+    /// Needed whenever the device may have lost it, such as after a reconnect
+    pub fn invalidate(&mut self) {
+        self.sent = None;
+    }
+
+    /// Packets that take the device from the last frame to `frame`, empty if nothing changed
+    /// `frame` counts as sent from here on, `invalidate` if the packets don't make it
+    pub fn updates(&mut self, frame: &Framebuffer) -> Result<Vec<Packet>, region::Error> {
+        let (rects, refresh) = match &self.sent {
+            Some(sent) => (frame.diff(sent), RefreshKind::Partial),
+            None => (vec![frame.bounds()], RefreshKind::Full),
+        };
+        if rects.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut packets = vec![];
+        for rect in rects {
+            let pixels = frame.region(rect);
+            packets.extend(RegionUpdate::new(rect, &pixels)?.packets()?);
+        }
+        packets.push(Packet::Refresh(refresh));
+        self.sent = Some(frame.clone());
+        Ok(packets)
+    }
+
+    /// Sends `frame` to the device, returns the refresh it did or None if nothing changed
+    pub async fn send(
+        &mut self,
+        device: &DeviceHandle,
+        frame: &Framebuffer,
+    ) -> Result<Option<RefreshKind>, DeviceError> {
+        //the diff only holds whole rows of a screen sized frame, this can't fail
+        let packets = self.updates(frame).expect("frame doesn't fit in packets");
+        let mut refresh = None;
+        for packet in packets {
+            match device.request(packet).await {
+                Ok(Packet::Response(kind)) if kind.len() == 1 => {
+                    refresh = RefreshKind::try_from(kind[0]).ok();
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.invalidate();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(refresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+
+    use super::*;
+    use crate::{link::Link, virtual_device::VirtualDevice};
+
+    fn fill(frame: &mut Framebuffer, x: i32, y: i32, width: u32, height: u32) {
+        Rectangle::new(Point::new(x, y), Size::new(width, height))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(frame)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let blank = Framebuffer::screen();
+        let mut frame = blank.clone();
+        assert!(frame.diff(&blank).is_empty());
+        fill(&mut frame, 10, 20, 3, 2);
+        fill(&mut frame, 200, 100, 50, 40);
+        assert_eq!(
+            frame.diff(&blank),
+            [Rect::new(8, 20, 8, 2), Rect::new(200, 100, 50, 22)]
+        );
+        assert_eq!(
+            frame.diff(&Framebuffer::new(10, 10)),
+            [Rect::new(0, 0, WIDTH, HEIGHT)]
+        );
+    }
+
+    #[test]
+    fn test_region() {
+        let mut frame = Framebuffer::new(16, 2);
+        fill(&mut frame, 3, 0, 6, 1);
+        assert_eq!(frame.as_bytes(), [0x1f, 0x80, 0, 0]);
+        assert_eq!(frame.region(Rect::new(3, 0, 6, 2)), [0xfc, 0]);
+        let mut copy = Framebuffer::new(16, 2);
+        copy.apply(&RegionUpdate::new(Rect::new(3, 0, 6, 2), &[0xfc, 0]).unwrap());
+        assert_eq!(copy, frame);
+    }
+
+    /// Sends a frame the way `FrameSender::send` does, returns how many packets it took
+    fn send(
+        sender: &mut FrameSender,
+        link: &mut Link<VirtualDevice>,
+        frame: &Framebuffer,
+    ) -> usize {
+        let packets = sender.updates(frame).unwrap();
+        let count = packets.len();
+        for packet in packets {
+            link.request(packet).unwrap();
+        }
+        count
+    }
+
+    #[test]
+    fn test_sender() {
+        let device = VirtualDevice::new();
+        let screen = device.screen();
+        let mut link = Link::new(device);
+        link.sync_credits().unwrap();
+        let mut sender = FrameSender::new();
+        let mut frame = Framebuffer::screen();
+        fill(&mut frame, 0, 0, 250, 1);
+
+        //whole screen at 7 rows a packet, then the refresh
+        assert_eq!(send(&mut sender, &mut link, &frame), 19);
+        assert_eq!(screen.frame(), frame);
+
+        fill(&mut frame, 100, 50, 1, 1);
+        assert_eq!(send(&mut sender, &mut link, &frame), 2);
+        assert_eq!(send(&mut sender, &mut link, &frame), 0);
+        assert_eq!(screen.frame(), frame);
+
+        sender.invalidate();
+        assert_eq!(send(&mut sender, &mut link, &frame), 19);
+    }
+}
@@ -0,0 +1,425 @@
+//! Interactive shell for poking at a device without recompiling the server
+
+use std::{borrow::Cow, fmt::Write as _, path::PathBuf};
+
+use db_link::{
+    commands::{Packet, PayloadBuf, MAX_PAYLOAD_SIZE},
+    params,
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+use thiserror::Error;
+
+use crate::{
+    device::Port,
+    link::{Link, LinkError},
+};
+
+const COMMANDS: [&str; 8] = [
+    "echo", "get", "set", "params", "raw", "help", "quit", "exit",
+];
+const HISTORY_FILE: &str = ".db-server-history";
+
+const HELP: &str = "\
+echo <text>         send an Echo
+get <key>           read a param
+set <key> <value>   write a param, the value is sent as text
+params              list param keys
+raw <hex>           send bytes as they are, such as a hand made frame
+help                show this
+quit                leave the shell";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Echo(String),
+    Get(String),
+    Set(String, String),
+    Params,
+    Raw(Vec<u8>),
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("unknown command {0}, try help")]
+    Unknown(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("invalid hex: {0}")]
+    Hex(String),
+    #[error(transparent)]
+    Param(#[from] params::Error),
+    #[error("more than {MAX_PAYLOAD_SIZE} bytes")]
+    TooLong,
+}
+
+#[derive(Debug, Error)]
+pub enum ShellError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Link(#[from] LinkError),
+}
+
+impl Command {
+    /// None for a blank line
+    pub fn parse(line: &str) -> Result<Option<Self>, ParseError> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let command = match name {
+            "" => return Ok(None),
+            "echo" => Command::Echo(rest.to_string()),
+            "get" if !rest.is_empty() => Command::Get(rest.to_string()),
+            "get" => return Err(ParseError::Usage("get <key>")),
+            "set" => match rest.split_once(char::is_whitespace) {
+                Some((key, value)) => Command::Set(key.to_string(), value.trim().to_string()),
+                None => return Err(ParseError::Usage("set <key> <value>")),
+            },
+            "params" => Command::Params,
+            "raw" if !rest.is_empty() => Command::Raw(parse_hex(rest)?),
+            "raw" => return Err(ParseError::Usage("raw <hex>")),
+            "help" => Command::Help,
+            "quit" | "exit" => Command::Quit,
+            _ => return Err(ParseError::Unknown(name.to_string())),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Hex bytes, spaces and 0x prefixes are allowed so dumps can be pasted in
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, ParseError> {
+    let digits: String = text
+        .split_whitespace()
+        .map(|word| word.trim_start_matches("0x"))
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(ParseError::Hex("odd number of digits".to_string()));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| ParseError::Hex(digits[i..i + 2].to_string()))
+        })
+        .collect()
+}
+
+/// Packet as a person would want to read it
+pub fn describe(packet: &Packet) -> String {
+    match packet {
+        Packet::Response(payload) if payload.is_empty() => "Ok".to_string(),
+        Packet::Response(payload) | Packet::Echo(payload) => {
+            let name = if let Packet::Echo(_) = packet {
+                "Echo"
+            } else {
+                "Response"
+            };
+            match std::str::from_utf8(payload) {
+                Ok(text) if !text.contains(char::is_control) => format!("{name} {text:?}"),
+                _ => format!("{name} {}", hex(payload)),
+            }
+        }
+        Packet::Error(error) => format!("Error: {error}"),
+        packet => format!("{packet:?}"),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        _ = write!(out, "{sep}{byte:02x}");
+    }
+    out
+}
+
+/// Completions for the word under the cursor, the start of that word and the candidates
+pub fn complete(line: &str, pos: usize, params: &[String]) -> (usize, Vec<String>) {
+    let line = &line[..pos];
+    let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    let word = &line[start..];
+    let words: Vec<_> = line[..start].split_whitespace().collect();
+    let options: Vec<&str> = match words.as_slice() {
+        [] => COMMANDS.to_vec(),
+        ["get" | "set"] => params.iter().map(String::as_str).collect(),
+        _ => vec![],
+    };
+    let matches = options
+        .into_iter()
+        .filter(|option| option.starts_with(word))
+        .map(str::to_string)
+        .collect();
+    (start, matches)
+}
+
+/// Runs shell commands against a link
+pub struct Shell<T> {
+    link: Link<T>,
+    /// Known keys, refreshed whenever they're listed
+    params: Vec<String>,
+}
+
+impl<T: Port> Shell<T> {
+    pub fn new(link: Link<T>) -> Self {
+        Self {
+            link,
+            params: vec![],
+        }
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    /// Output of a command, device errors are part of the output
+    pub fn execute(&mut self, command: Command) -> Result<String, ShellError> {
+        let packet = match command {
+            Command::Echo(text) => Packet::Echo(
+                PayloadBuf::from_slice(text.as_bytes()).map_err(|_| ParseError::TooLong)?,
+            ),
+            Command::Get(key) => Packet::get_param(&key).map_err(ParseError::from)?,
+            Command::Set(key, value) => {
+                Packet::set_param(&key, value.as_bytes()).map_err(ParseError::from)?
+            }
+            Command::Params => return Ok(self.list_params()?.join("\n")),
+            Command::Raw(bytes) => {
+                self.link.send_raw(&bytes)?;
+                let frame = self.link.recv_frame();
+                //the device may or may not have credited the bytes back
+                self.link.sync_credits()?;
+                let frame = frame?;
+                return Ok(format!("{:?} {}", frame.channel, describe(&frame.packet)));
+            }
+            Command::Help => return Ok(HELP.to_string()),
+            Command::Quit => return Ok(String::new()),
+        };
+        match self.link.request(packet) {
+            Ok(packet) => Ok(describe(&packet)),
+            Err(LinkError::Device(error)) => Ok(describe(&Packet::Error(*error))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn list_params(&mut self) -> Result<Vec<String>, LinkError> {
+        match self.link.request(Packet::GetParamList)? {
+            Packet::Response(payload) => {
+                self.params = params::param_list(&payload).map(str::to_string).collect();
+                Ok(self.params.clone())
+            }
+            packet => Err(LinkError::Unexpected(Box::new(packet))),
+        }
+    }
+
+    /// Reads commands until quit or end of input, history is kept in the home directory
+    pub fn run(&mut self) -> Result<(), anyhow::Error> {
+        let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+        //not every device lists params, completion just stays empty
+        let params = self.list_params().unwrap_or_default();
+        editor.set_helper(Some(ShellHelper { params }));
+        let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(history) = &history {
+            //missing on first run
+            _ = editor.load_history(history);
+        }
+        loop {
+            let line = match editor.readline("db> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            };
+            _ = editor.add_history_entry(line.as_str());
+            let command = match Command::parse(&line) {
+                Ok(Some(Command::Quit)) => break,
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("{e}");
+                    continue;
+                }
+            };
+            match self.execute(command) {
+                Ok(output) => println!("{output}"),
+                Err(ShellError::Parse(e)) => println!("{e}"),
+                Err(ShellError::Link(e)) if e.is_timeout() => println!("No answer from device"),
+                Err(ShellError::Link(e)) => return Err(e.into()),
+            }
+            if let Some(helper) = editor.helper_mut() {
+                helper.params.clone_from(&self.params);
+            }
+        }
+        if let Some(history) = &history {
+            if let Err(e) = editor.save_history(history) {
+                log::warn!("Saving shell history failed: {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ShellHelper {
+    params: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(line, pos, &self.params))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Borrowed(line)
+    }
+}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use db_link::commands::{Channel, Command as LinkCommand, SYNC_BYTE, VERSION};
+
+    use super::*;
+    use crate::virtual_device::{self, VirtualDevice};
+
+    fn shell() -> Shell<VirtualDevice> {
+        let mut link = Link::new(VirtualDevice::new());
+        link.sync_credits().unwrap();
+        Shell::new(link)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("  "), Ok(None));
+        assert_eq!(
+            Command::parse("echo hello there"),
+            Ok(Some(Command::Echo("hello there".to_string())))
+        );
+        assert_eq!(
+            Command::parse("set NAME my desk"),
+            Ok(Some(Command::Set(
+                "NAME".to_string(),
+                "my desk".to_string()
+            )))
+        );
+        assert_eq!(
+            Command::parse("set NAME"),
+            Err(ParseError::Usage("set <key> <value>"))
+        );
+        assert_eq!(
+            Command::parse("raw a1 0x02 0003"),
+            Ok(Some(Command::Raw(vec![0xa1, 0x02, 0x00, 0x03])))
+        );
+        assert_eq!(
+            Command::parse("bogus"),
+            Err(ParseError::Unknown("bogus".to_string()))
+        );
+        assert!(matches!(parse_hex("abc"), Err(ParseError::Hex(_))));
+        assert!(matches!(parse_hex("zz"), Err(ParseError::Hex(_))));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let mut shell = shell();
+        assert!(matches!(
+            shell.execute(Command::Get("".to_string())),
+            Err(ShellError::Parse(ParseError::Param(_)))
+        ));
+        assert!(matches!(
+            shell.execute(Command::Echo("x".repeat(300))),
+            Err(ShellError::Parse(ParseError::TooLong))
+        ));
+    }
+
+    #[test]
+    fn test_complete() {
+        let params = vec!["VERSION".to_string(), "VOLUME".to_string()];
+        assert_eq!(complete("pa", 2, &params), (0, vec!["params".to_string()]));
+        assert_eq!(
+            complete("get V", 5, &params),
+            (4, vec!["VERSION".to_string(), "VOLUME".to_string()])
+        );
+        assert_eq!(
+            complete("set VO", 6, &params),
+            (4, vec!["VOLUME".to_string()])
+        );
+        assert!(complete("echo V", 6, &params).1.is_empty());
+    }
+
+    #[test]
+    fn test_execute() {
+        let mut shell = shell();
+        assert_eq!(
+            shell.execute(Command::Echo("hi".to_string())).unwrap(),
+            "Echo \"hi\""
+        );
+        assert_eq!(
+            shell
+                .execute(Command::Set("NAME".to_string(), "desk".to_string()))
+                .unwrap(),
+            "Ok"
+        );
+        assert_eq!(
+            shell.execute(Command::Get("NAME".to_string())).unwrap(),
+            "Response \"desk\""
+        );
+        assert_eq!(shell.execute(Command::Params).unwrap(), "VERSION\nNAME");
+        assert_eq!(shell.params(), ["VERSION", "NAME"]);
+        assert_eq!(
+            shell.execute(Command::Get("NOPE".to_string())).unwrap(),
+            "Error: unknown param"
+        );
+        assert_eq!(
+            shell.execute(Command::Get("VERSION".to_string())).unwrap(),
+            format!("Response {:?}", virtual_device::VERSION)
+        );
+    }
+
+    #[test]
+    fn test_raw() {
+        let mut shell = shell();
+        let frame = vec![
+            SYNC_BYTE,
+            VERSION,
+            Channel::Event as u8,
+            LinkCommand::Echo as u8,
+            2,
+            0x00,
+            0xff,
+        ];
+        assert_eq!(
+            shell.execute(Command::Raw(frame)).unwrap(),
+            "Event Echo 00 ff"
+        );
+    }
+
+    #[test]
+    fn test_raw_junk_keeps_credit() {
+        let mut shell = shell();
+        //more junk than the device's whole window, none of it parses into a frame
+        for _ in 0..20 {
+            assert!(shell.execute(Command::Raw(vec![0x55; 250])).is_err());
+        }
+        assert_eq!(
+            shell.execute(Command::Echo("hi".to_string())).unwrap(),
+            "Echo \"hi\""
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Data feeds for pages, such as weather and host metrics
+//!
+//! Each `DataSource` is polled on its own task at its own interval. The `Registry` keeps the
+//! latest value of every source in a watch channel, so subscribers can read it at any time and
+//! wake up only when it actually changed.
+
+use std::{any::Any, collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinSet,
+    time::{self, MissedTickBehavior},
+};
+
+/// Source names kept for subscribers to `changes` that fall behind
+const CHANGE_QUEUE: usize = 64;
+
+pub trait DataSource: Send + 'static {
+    type Output: Clone + PartialEq + Send + Sync + 'static;
+
+    /// Time between fetches, the first fetch happens right away
+    fn interval(&self) -> Duration;
+
+    /// Get the current value, on failure subscribers keep seeing the last good one
+    fn fetch(&mut self) -> impl Future<Output = anyhow::Result<Self::Output>> + Send;
+}
+
+/// Latest value of a source, None until its first successful fetch
+pub type Latest<T> = watch::Receiver<Option<T>>;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RegistryError {
+    #[error("source {0} is already registered")]
+    Duplicate(String),
+    #[error("no source named {0}")]
+    Unknown(String),
+    #[error("source {0} has a different output type")]
+    WrongType(String),
+}
+
+/// Drives sources and caches their values, dropping it stops every source
+pub struct Registry {
+    latest: HashMap<String, Box<dyn Any + Send + Sync>>,
+    changes: broadcast::Sender<Arc<str>>,
+    tasks: JoinSet<()>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            latest: HashMap::new(),
+            changes: broadcast::channel(CHANGE_QUEUE).0,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Start polling `source` under `name`, must be called from inside a tokio runtime
+    pub fn register<S: DataSource>(
+        &mut self,
+        name: &str,
+        mut source: S,
+    ) -> Result<Latest<S::Output>, RegistryError> {
+        if self.latest.contains_key(name) {
+            return Err(RegistryError::Duplicate(name.to_string()));
+        }
+        let (latest, receiver) = watch::channel(None);
+        self.latest
+            .insert(name.to_string(), Box::new(latest.clone()));
+        let name: Arc<str> = name.into();
+        let changes = self.changes.clone();
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(source.interval());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let value = match source.fetch().await {
+                    Ok(value) => value,
+                    Err(e) => {
+                        log::warn!("fetching {name} failed: {e:#}");
+                        continue;
+                    }
+                };
+                let changed = latest.send_if_modified(|current| {
+                    if current.as_ref() == Some(&value) {
+                        return false;
+                    }
+                    *current = Some(value);
+                    true
+                });
+                if changed {
+                    //nobody listening is fine
+                    let _ = changes.send(name.clone());
+                }
+            }
+        });
+        Ok(receiver)
+    }
+
+    /// Latest value of the source called `name`, for pages that only know it by name
+    pub fn subscribe<T: 'static>(&self, name: &str) -> Result<Latest<T>, RegistryError> {
+        let latest = self
+            .latest
+            .get(name)
+            .ok_or_else(|| RegistryError::Unknown(name.to_string()))?;
+        latest
+            .downcast_ref::<watch::Sender<Option<T>>>()
+            .map(watch::Sender::subscribe)
+            .ok_or_else(|| RegistryError::WrongType(name.to_string()))
+    }
+
+    /// Names of sources as their values change, for redrawing whatever shows them
+    pub fn changes(&self) -> broadcast::Receiver<Arc<str>> {
+        self.changes.subscribe()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.latest.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Counts up every other fetch and fails on every fifth
+    struct Counter {
+        fetches: u32,
+    }
+
+    impl DataSource for Counter {
+        type Output = u32;
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+
+        async fn fetch(&mut self) -> anyhow::Result<u32> {
+            self.fetches += 1;
+            if self.fetches.is_multiple_of(5) {
+                return Err(anyhow!("unlucky"));
+            }
+            Ok(self.fetches / 2)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registry() {
+        let mut registry = Registry::new();
+        let mut latest = registry
+            .register("counter", Counter { fetches: 0 })
+            .unwrap();
+        let mut changes = registry.changes();
+        assert_eq!(*latest.borrow(), None);
+
+        let mut seen = vec![];
+        for _ in 0..4 {
+            latest.changed().await.unwrap();
+            seen.push(latest.borrow_and_update().unwrap());
+            assert_eq!(&*changes.recv().await.unwrap(), "counter");
+        }
+        //repeats don't wake anyone, the failed fifth fetch keeps 2
+        assert_eq!(seen, [0, 1, 2, 3]);
+        assert!(changes.try_recv().is_err());
+
+        let by_name = registry.subscribe::<u32>("counter").unwrap();
+        assert_eq!(*by_name.borrow(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_registry_errors() {
+        let mut registry = Registry::new();
+        registry
+            .register("counter", Counter { fetches: 0 })
+            .unwrap();
+        assert_eq!(
+            registry.register("counter", Counter { fetches: 0 }).err(),
+            Some(RegistryError::Duplicate("counter".to_string()))
+        );
+        assert_eq!(
+            registry.subscribe::<String>("counter").err(),
+            Some(RegistryError::WrongType("counter".to_string()))
+        );
+        assert_eq!(
+            registry.subscribe::<u32>("weather").err(),
+            Some(RegistryError::Unknown("weather".to_string()))
+        );
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["counter"]);
+    }
+}
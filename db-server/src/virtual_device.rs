@@ -0,0 +1,342 @@
+//! In process stand in for the firmware, answers the same packets over a fake port so the
+//! host side can be run without hardware
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use db_link::{
+    commands::{
+        Channel, ErrorCode, ErrorPayload, Frame, Packet, PayloadBuf, MAX_PACKET_SIZE,
+        MAX_PAYLOAD_SIZE,
+    },
+    flow::CreditReceiver,
+    pages::PageRegistry,
+    params::{ParamListBuilder, SetParam},
+    parser::{self, Parser},
+    region::{RefreshPolicy, RegionUpdate},
+};
+use db_settings::{sim::MemFlash, Settings};
+
+use crate::render::Framebuffer;
+
+/// Same receive buffer as the bare metal firmware
+pub const QUEUE_CAPACITY: u16 = 4095;
+pub const VERSION: &str = concat!("virtual-", env!("CARGO_PKG_VERSION"));
+const SETTINGS_BANK_SIZE: u32 = 0x1000;
+/// How long a read with nothing to return waits before timing out, like a port timeout
+const READ_TIMEOUT: Duration = Duration::from_millis(1);
+/// Same as the bare metal firmware
+const FULL_REFRESH_EVERY: u16 = 10;
+/// Same pages as the bare metal firmware, switching between them isn't drawn
+const PAGES: [&str; 2] = ["host", "hello"];
+
+pub struct VirtualDevice {
+    parser: Parser,
+    credits: CreditReceiver,
+    settings: Settings<MemFlash>,
+    output: VecDeque<u8>,
+    plugged: Arc<AtomicBool>,
+    /// Region updates land here and show up on `screen` at the next refresh
+    drawing: Framebuffer,
+    screen: Arc<Mutex<Framebuffer>>,
+    policy: RefreshPolicy,
+    pages: PageRegistry<{ PAGES.len() }>,
+    started: Instant,
+    /// Names of buttons pressed since the last read
+    pressed: Arc<Mutex<VecDeque<String>>>,
+}
+
+/// What the virtual device shows, readable from another thread
+#[derive(Debug, Clone)]
+pub struct Screen(Arc<Mutex<Framebuffer>>);
+
+impl Screen {
+    /// The frame as of the last refresh
+    pub fn frame(&self) -> Framebuffer {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Presses the virtual device's buttons from another thread
+#[derive(Debug, Clone)]
+pub struct Buttons(Arc<Mutex<VecDeque<String>>>);
+
+impl Buttons {
+    /// The device reports the press as an Echo of the button's name on the event channel
+    pub fn press(&self, name: &str) {
+        self.0.lock().unwrap().push_back(name.to_string());
+    }
+}
+
+/// Pulls the virtual cable out from another thread
+#[derive(Debug, Clone)]
+pub struct Plug(Arc<AtomicBool>);
+
+impl Plug {
+    /// Every read and write after this fails as if the device was unplugged
+    pub fn pull(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl VirtualDevice {
+    pub fn new() -> Self {
+        let flash = MemFlash::new(2 * SETTINGS_BANK_SIZE as usize);
+        let mut pages = PageRegistry::new();
+        for name in PAGES {
+            //sized to fit
+            pages.register(name).unwrap();
+        }
+        Self {
+            parser: Parser::new(),
+            credits: CreditReceiver::new(QUEUE_CAPACITY, MAX_PACKET_SIZE as u16),
+            //fresh flash always formats
+            settings: Settings::new(flash, 0, SETTINGS_BANK_SIZE).unwrap(),
+            output: VecDeque::new(),
+            plugged: Arc::new(AtomicBool::new(true)),
+            drawing: Framebuffer::screen(),
+            screen: Arc::new(Mutex::new(Framebuffer::screen())),
+            policy: RefreshPolicy::new(FULL_REFRESH_EVERY),
+            pages,
+            started: Instant::now(),
+            pressed: Arc::default(),
+        }
+    }
+
+    pub fn plug(&self) -> Plug {
+        Plug(self.plugged.clone())
+    }
+
+    pub fn screen(&self) -> Screen {
+        Screen(self.screen.clone())
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        Buttons(self.pressed.clone())
+    }
+
+    fn check_plugged(&self) -> io::Result<()> {
+        if self.plugged.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn send(&mut self, frame: Frame) {
+        self.output.extend(frame.serialize_vec());
+    }
+
+    fn handle(&mut self, packet: Packet) -> Packet {
+        match &packet {
+            Packet::Echo(_) => packet,
+            Packet::GetParam(param) if param.as_slice() == b"VERSION" => {
+                Packet::Response(PayloadBuf::from_slice(VERSION.as_bytes()).unwrap())
+            }
+            Packet::SetParam(param)
+                if SetParam::decode(param).is_ok_and(|param| param.key == "VERSION") =>
+            {
+                Packet::Error(ErrorPayload::with_detail(
+                    ErrorCode::InvalidValue,
+                    "read only",
+                ))
+            }
+            Packet::GetParamList => {
+                let mut list = ParamListBuilder::new();
+                list.push("VERSION");
+                _ = self.settings.list_keys(&mut list);
+                list.finish()
+            }
+            Packet::UpdateRegion(payload) => match RegionUpdate::decode(payload) {
+                Ok(update) => {
+                    self.drawing.apply(&update);
+                    Packet::Response(PayloadBuf::new())
+                }
+                Err(_) => Packet::Error(ErrorCode::InvalidValue.into()),
+            },
+            Packet::Refresh(requested) => {
+                let kind = self.policy.next(*requested);
+                self.screen.lock().unwrap().clone_from(&self.drawing);
+                Packet::refresh_done(kind)
+            }
+            _ => self
+                .pages
+                .handle(&packet, self.started.elapsed())
+                .or_else(|| self.settings.handle(&packet))
+                .unwrap_or_else(|| Packet::Error(ErrorCode::UnknownCommand.into())),
+        }
+    }
+}
+
+impl Default for VirtualDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Read for VirtualDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_plugged()?;
+        let pressed: Vec<String> = self.pressed.lock().unwrap().drain(..).collect();
+        for name in pressed {
+            let name = &name.as_bytes()[..name.len().min(MAX_PAYLOAD_SIZE)];
+            let event = Packet::Echo(PayloadBuf::from_slice(name).unwrap());
+            self.send(Frame::new(Channel::Event, event));
+        }
+        if self.output.is_empty() {
+            thread::sleep(READ_TIMEOUT);
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.output.read(buf)
+    }
+}
+
+impl Write for VirtualDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_plugged()?;
+        for byte in buf {
+            match self.parser.parse_frame(&[*byte]) {
+                Ok(Frame {
+                    packet: Packet::CreditRequest,
+                    ..
+                }) => {
+                    let credit = self.credits.advertise(QUEUE_CAPACITY as usize);
+                    self.send(credit.into());
+                }
+                Ok(frame) => {
+                    //frames are handled as soon as they arrive so each one hands its credit back
+                    if let Some(credit) = self.credits.received(&frame.packet) {
+                        self.send(credit.into());
+                    }
+                    let reply = self.handle(frame.packet);
+                    self.send(Frame::new(frame.channel, reply));
+                }
+                Err(parser::Error::InvalidCommand) => {
+                    self.send(Packet::Error(ErrorCode::UnknownCommand.into()).into());
+                }
+                Err(_) => {}
+            }
+        }
+        if let Some(credit) = self.credits.flush() {
+            self.send(credit.into());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_plugged()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db_link::{
+        pages::{decode_current, page_list},
+        region::RefreshKind,
+    };
+
+    use super::*;
+    use crate::link::{Link, LinkError};
+
+    #[test]
+    fn test_request() {
+        let mut link = Link::new(VirtualDevice::new());
+        link.sync_credits().unwrap();
+        let echo = Packet::Echo(PayloadBuf::from_slice(b"hi").unwrap());
+        assert_eq!(link.request(echo.clone()).unwrap(), echo);
+        let version = link.request(Packet::get_param("VERSION").unwrap()).unwrap();
+        assert_eq!(
+            version,
+            Packet::Response(PayloadBuf::from_slice(VERSION.as_bytes()).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_refresh_policy() {
+        let mut link = Link::new(VirtualDevice::new());
+        link.sync_credits().unwrap();
+        let partial = Packet::Refresh(RefreshKind::Partial);
+        for _ in 0..FULL_REFRESH_EVERY {
+            let done = link.request(partial.clone()).unwrap();
+            assert_eq!(done, Packet::refresh_done(RefreshKind::Partial));
+        }
+        let done = link.request(partial).unwrap();
+        assert_eq!(done, Packet::refresh_done(RefreshKind::Full));
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut link = Link::new(VirtualDevice::new());
+        link.sync_credits().unwrap();
+        link.request(Packet::set_param("NAME", b"desk").unwrap())
+            .unwrap();
+        let frame = Frame::new(Channel::Event, Packet::get_param("NAME").unwrap());
+        link.send_frame(frame).unwrap();
+        assert_eq!(
+            link.recv_frame().unwrap(),
+            Frame::new(
+                Channel::Event,
+                Packet::Response(PayloadBuf::from_slice(b"desk").unwrap())
+            )
+        );
+        let err = link
+            .request(Packet::set_param("VERSION", b"2").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, LinkError::Device(e) if e.code == ErrorCode::InvalidValue));
+    }
+
+    #[test]
+    fn test_pages() {
+        let mut link = Link::new(VirtualDevice::new());
+        link.sync_credits().unwrap();
+        let Packet::Response(list) = link.request(Packet::ListPages).unwrap() else {
+            panic!("expected Response");
+        };
+        let names: Vec<_> = page_list(&list).map(|page| page.name).collect();
+        assert_eq!(names, PAGES);
+        link.request(Packet::ShowPage(1)).unwrap();
+        let Packet::Response(current) = link.request(Packet::GetCurrentPage).unwrap() else {
+            panic!("expected Response");
+        };
+        assert_eq!(decode_current(&current), Ok(1));
+        let err = link.request(Packet::ShowPage(7)).unwrap_err();
+        assert!(matches!(err, LinkError::Device(e) if e.code == ErrorCode::InvalidValue));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let device = VirtualDevice::new();
+        let buttons = device.buttons();
+        let mut link = Link::new(device);
+        link.sync_credits().unwrap();
+        buttons.press("next");
+        assert_eq!(
+            link.recv_frame().unwrap(),
+            Frame::new(
+                Channel::Event,
+                Packet::Echo(PayloadBuf::from_slice(b"next").unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn test_unplug() {
+        let device = VirtualDevice::new();
+        let plug = device.plug();
+        let mut link = Link::new(device);
+        link.sync_credits().unwrap();
+        plug.pull();
+        assert!(matches!(
+            link.send(Packet::GetParamList),
+            Err(LinkError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,639 @@
+#![no_std]
+//! Key value settings store for the device's flash
+//!
+//! Two banks of flash are used as an append only log. Setting a key appends a record, the
+//! newest record for a key wins. When the active bank fills up the live records are copied
+//! into the other bank and the old one is erased, so erases alternate between the banks and
+//! rewriting a value with the same data doesn't touch flash at all.
+//!
+//! Power loss safety:
+//!     Every record and bank header carries a crc, a torn write fails the check and everything
+//!     from that point on is ignored until the next compaction.
+//!     The bank header of a compacted bank is written last, if power is lost mid compaction the
+//!     old bank is still the newest valid one. If both banks are valid the higher sequence wins.
+
+#[cfg(feature = "std")]
+extern crate std;
+
+mod crc;
+pub mod params;
+#[cfg(feature = "std")]
+pub mod sim;
+
+use crc::crc32;
+use db_link::params::{SetParam, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use embedded_storage::nor_flash::NorFlash;
+
+#[cfg(feature = "std")]
+use thiserror::Error;
+
+const MAGIC: u32 = 0xDB5E_7701;
+/// magic, sequence, crc
+const BANK_HEADER_SIZE: usize = 12;
+/// key length, flags, value length (u16), crc
+const RECORD_HEADER_SIZE: usize = 8;
+const FLAG_TOMBSTONE: u8 = 0x01;
+/// Largest flash read/write size supported
+const MAX_ALIGN: usize = 16;
+const RECORD_BUF_SIZE: usize = RECORD_HEADER_SIZE + MAX_KEY_SIZE + MAX_VALUE_SIZE + MAX_ALIGN;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Error))]
+pub enum Error<E> {
+    #[cfg_attr(feature = "std", error("flash error: {0:?}"))]
+    Flash(E),
+    #[cfg_attr(feature = "std", error(transparent))]
+    Param(db_link::params::Error),
+    #[cfg_attr(feature = "std", error("settings store is full"))]
+    Full,
+    #[cfg_attr(feature = "std", error("buffer too small for value"))]
+    BufferTooSmall,
+    #[cfg_attr(feature = "std", error("flash geometry not supported"))]
+    Geometry,
+}
+
+/// Record found while walking a bank, its bytes are in the buffer it was read into
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    key_len: usize,
+    value_len: usize,
+    tombstone: bool,
+    /// size on flash including padding
+    len: u32,
+}
+
+impl Record {
+    fn key<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + self.key_len]
+    }
+
+    fn value<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        let start = RECORD_HEADER_SIZE + self.key_len;
+        &buf[start..start + self.value_len]
+    }
+}
+
+enum Slot {
+    Record(Record),
+    /// erased flash, end of the log
+    End,
+    /// torn or garbage write, nothing after it can be trusted
+    Corrupt,
+}
+
+type RecordBuf = [u8; RECORD_BUF_SIZE];
+
+pub struct Settings<F> {
+    flash: F,
+    base: u32,
+    bank_size: u32,
+    active: u32,
+    seq: u32,
+    /// offset in the active bank the next record goes to
+    write_pos: u32,
+    /// active bank has a corrupt record, compact before appending
+    dirty: bool,
+}
+
+impl<F: NorFlash> Settings<F> {
+    /// Mount the store in the two banks at `base` and `base + bank_size`
+    /// Formats the first bank if neither holds a store yet
+    pub fn new(flash: F, base: u32, bank_size: u32) -> Result<Self, Error<F::Error>> {
+        let align = Self::align();
+        let erase = F::ERASE_SIZE as u32;
+        if align as usize > MAX_ALIGN
+            || erase == 0
+            || !base.is_multiple_of(erase)
+            || !bank_size.is_multiple_of(erase)
+            || (base as usize + 2 * bank_size as usize) > flash.capacity()
+            || bank_size < Self::records_start() + Self::round_up(RECORD_BUF_SIZE as u32)
+        {
+            return Err(Error::Geometry);
+        }
+        let mut settings = Self {
+            flash,
+            base,
+            bank_size,
+            active: 0,
+            seq: 0,
+            write_pos: 0,
+            dirty: false,
+        };
+        settings.mount()?;
+        Ok(settings)
+    }
+
+    /// Give the flash back, e.g. to remount it
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Bytes used in the active bank
+    pub fn used(&self) -> u32 {
+        self.write_pos
+    }
+
+    pub fn bank_size(&self) -> u32 {
+        self.bank_size
+    }
+
+    /// Copies the value of `key` into `out`, returning its length
+    pub fn get(&mut self, key: &str, out: &mut [u8]) -> Result<Option<usize>, Error<F::Error>> {
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        let Some(record) = self.find(key.as_bytes(), &mut buf)? else {
+            return Ok(None);
+        };
+        let value = record.value(&buf);
+        let Some(out) = out.get_mut(..value.len()) else {
+            return Err(Error::BufferTooSmall);
+        };
+        out.copy_from_slice(value);
+        Ok(Some(value.len()))
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), Error<F::Error>> {
+        SetParam::new(key, value).map_err(Error::Param)?;
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        if let Some(record) = self.find(key.as_bytes(), &mut buf)? {
+            if record.value(&buf) == value {
+                //nothing changed, save the write
+                return Ok(());
+            }
+        }
+        self.append(key.as_bytes(), value, 0)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), Error<F::Error>> {
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        if self.find(key.as_bytes(), &mut buf)?.is_none() {
+            return Ok(());
+        }
+        self.append(key.as_bytes(), &[], FLAG_TOMBSTONE)
+    }
+
+    /// Calls `f` with every key that has a value
+    pub fn for_each_key(&mut self, mut f: impl FnMut(&str)) -> Result<(), Error<F::Error>> {
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        let mut scratch = [0u8; RECORD_BUF_SIZE];
+        let mut pos = Self::records_start();
+        while let Slot::Record(record) = self.read_record(self.active, pos, &mut buf)? {
+            if !record.tombstone && self.is_latest(self.active, pos, &record, &buf, &mut scratch)? {
+                //keys are checked to be utf8 before they are written
+                if let Ok(key) = core::str::from_utf8(record.key(&buf)) {
+                    f(key);
+                }
+            }
+            pos += record.len;
+        }
+        Ok(())
+    }
+
+    /// Newest live record for `key`, its bytes are left in `buf`
+    fn find(&mut self, key: &[u8], buf: &mut RecordBuf) -> Result<Option<Record>, Error<F::Error>> {
+        let mut found = None;
+        let mut pos = Self::records_start();
+        while let Slot::Record(record) = self.read_record(self.active, pos, buf)? {
+            if record.key(buf) == key {
+                found = Some(pos);
+            }
+            pos += record.len;
+        }
+        let Some(pos) = found else {
+            return Ok(None);
+        };
+        match self.read_record(self.active, pos, buf)? {
+            Slot::Record(record) if !record.tombstone => Ok(Some(record)),
+            _ => Ok(None),
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8], flags: u8) -> Result<(), Error<F::Error>> {
+        let len = Self::round_up((RECORD_HEADER_SIZE + key.len() + value.len()) as u32);
+        let mut buf = [0xFFu8; RECORD_BUF_SIZE];
+        buf[0] = key.len() as u8;
+        buf[1] = flags;
+        buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let data_end = RECORD_HEADER_SIZE + key.len() + value.len();
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key.len()].copy_from_slice(key);
+        buf[RECORD_HEADER_SIZE + key.len()..data_end].copy_from_slice(value);
+        let crc = crc32(&[&buf[..4], &buf[RECORD_HEADER_SIZE..data_end]]);
+        buf[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        //second attempt is after compacting away whatever broke the first one
+        for _ in 0..2 {
+            if self.dirty || self.write_pos + len > self.bank_size {
+                self.compact()?;
+            }
+            if self.write_pos + len > self.bank_size {
+                return Err(Error::Full);
+            }
+            let addr = self.bank_addr(self.active) + self.write_pos;
+            self.flash
+                .write(addr, &buf[..len as usize])
+                .map_err(Error::Flash)?;
+            //read it back, programming over bits that weren't erased leaves a bad record
+            let mut check = [0u8; RECORD_BUF_SIZE];
+            match self.read_record(self.active, self.write_pos, &mut check)? {
+                Slot::Record(record) if record.len == len => {
+                    self.write_pos += len;
+                    return Ok(());
+                }
+                _ => self.dirty = true,
+            }
+        }
+        Err(Error::Full)
+    }
+
+    /// Copy the live records into the other bank and make it the active one
+    fn compact(&mut self) -> Result<(), Error<F::Error>> {
+        let from = self.active;
+        let to = 1 - from;
+        self.erase_bank(to)?;
+
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        let mut scratch = [0u8; RECORD_BUF_SIZE];
+        let mut src = Self::records_start();
+        let mut dst = Self::records_start();
+        while let Slot::Record(record) = self.read_record(from, src, &mut buf)? {
+            if !record.tombstone && self.is_latest(from, src, &record, &buf, &mut scratch)? {
+                let addr = self.bank_addr(to) + dst;
+                self.flash
+                    .write(addr, &buf[..record.len as usize])
+                    .map_err(Error::Flash)?;
+                dst += record.len;
+            }
+            src += record.len;
+        }
+
+        //the header makes the new bank valid, so it goes last
+        let seq = self.seq.wrapping_add(1);
+        self.write_bank_header(to, seq)?;
+        self.erase_bank(from)?;
+        self.active = to;
+        self.seq = seq;
+        self.write_pos = dst;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// True if no record after `pos` has the same key
+    fn is_latest(
+        &mut self,
+        bank: u32,
+        pos: u32,
+        record: &Record,
+        buf: &RecordBuf,
+        scratch: &mut RecordBuf,
+    ) -> Result<bool, Error<F::Error>> {
+        let key = record.key(buf);
+        let mut next = pos + record.len;
+        while let Slot::Record(later) = self.read_record(bank, next, scratch)? {
+            if later.key(scratch) == key {
+                return Ok(false);
+            }
+            next += later.len;
+        }
+        Ok(true)
+    }
+
+    fn mount(&mut self) -> Result<(), Error<F::Error>> {
+        let banks = (self.read_bank_header(0)?, self.read_bank_header(1)?);
+        let (active, seq) = match banks {
+            (None, None) => {
+                self.erase_bank(0)?;
+                self.write_bank_header(0, 1)?;
+                (0, 1)
+            }
+            (Some(seq), None) => (0, seq),
+            (None, Some(seq)) => (1, seq),
+            //power was lost between finishing a compaction and erasing the old bank
+            (Some(a), Some(b)) => {
+                let (active, seq) = if b > a { (1, b) } else { (0, a) };
+                self.erase_bank(1 - active)?;
+                (active, seq)
+            }
+        };
+        self.active = active;
+        self.seq = seq;
+
+        let mut buf = [0u8; RECORD_BUF_SIZE];
+        let mut pos = Self::records_start();
+        loop {
+            match self.read_record(active, pos, &mut buf)? {
+                Slot::Record(record) => pos += record.len,
+                Slot::End => break,
+                Slot::Corrupt => {
+                    self.dirty = true;
+                    break;
+                }
+            }
+        }
+        self.write_pos = pos;
+        Ok(())
+    }
+
+    fn read_record(
+        &mut self,
+        bank: u32,
+        pos: u32,
+        buf: &mut RecordBuf,
+    ) -> Result<Slot, Error<F::Error>> {
+        let header_len = Self::round_up(RECORD_HEADER_SIZE as u32);
+        if pos + header_len > self.bank_size {
+            return Ok(Slot::End);
+        }
+        let addr = self.bank_addr(bank) + pos;
+        self.flash
+            .read(addr, &mut buf[..header_len as usize])
+            .map_err(Error::Flash)?;
+        if buf[..RECORD_HEADER_SIZE].iter().all(|b| *b == 0xFF) {
+            return Ok(Slot::End);
+        }
+
+        let key_len = buf[0] as usize;
+        let value_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if key_len == 0 || key_len > MAX_KEY_SIZE || value_len > MAX_VALUE_SIZE {
+            return Ok(Slot::Corrupt);
+        }
+        let data_end = RECORD_HEADER_SIZE + key_len + value_len;
+        let len = Self::round_up(data_end as u32);
+        if pos + len > self.bank_size {
+            return Ok(Slot::Corrupt);
+        }
+        self.flash
+            .read(addr, &mut buf[..len as usize])
+            .map_err(Error::Flash)?;
+        let crc = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if crc != crc32(&[&buf[..4], &buf[RECORD_HEADER_SIZE..data_end]]) {
+            return Ok(Slot::Corrupt);
+        }
+        Ok(Slot::Record(Record {
+            key_len,
+            value_len,
+            tombstone: buf[1] & FLAG_TOMBSTONE != 0,
+            len,
+        }))
+    }
+
+    /// Sequence number of a bank, None if it doesn't hold a valid store
+    fn read_bank_header(&mut self, bank: u32) -> Result<Option<u32>, Error<F::Error>> {
+        let mut buf = [0u8; MAX_ALIGN];
+        let len = Self::round_up(BANK_HEADER_SIZE as u32) as usize;
+        self.flash
+            .read(self.bank_addr(bank), &mut buf[..len])
+            .map_err(Error::Flash)?;
+        let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let seq = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let crc = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        if magic == MAGIC && crc == crc32(&[&buf[..8]]) {
+            Ok(Some(seq))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_bank_header(&mut self, bank: u32, seq: u32) -> Result<(), Error<F::Error>> {
+        let mut buf = [0xFFu8; MAX_ALIGN];
+        buf[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&seq.to_le_bytes());
+        let crc = crc32(&[&buf[..8]]);
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+        let len = Self::round_up(BANK_HEADER_SIZE as u32) as usize;
+        self.flash
+            .write(self.bank_addr(bank), &buf[..len])
+            .map_err(Error::Flash)
+    }
+
+    fn erase_bank(&mut self, bank: u32) -> Result<(), Error<F::Error>> {
+        let addr = self.bank_addr(bank);
+        self.flash
+            .erase(addr, addr + self.bank_size)
+            .map_err(Error::Flash)
+    }
+
+    fn bank_addr(&self, bank: u32) -> u32 {
+        self.base + bank * self.bank_size
+    }
+
+    fn align() -> u32 {
+        F::READ_SIZE.max(F::WRITE_SIZE).max(1) as u32
+    }
+
+    fn round_up(len: u32) -> u32 {
+        let align = Self::align();
+        len.div_ceil(align) * align
+    }
+
+    fn records_start() -> u32 {
+        Self::round_up(BANK_HEADER_SIZE as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{MemFlash, SIM_ERASE_SIZE};
+
+    const BANK: u32 = SIM_ERASE_SIZE as u32;
+
+    fn mount(flash: MemFlash) -> Settings<MemFlash> {
+        Settings::new(flash, 0, BANK).unwrap()
+    }
+
+    fn get(settings: &mut Settings<MemFlash>, key: &str) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        let len = settings.get(key, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn test_set_get() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        assert_eq!(get(&mut settings, "TZ"), None);
+        settings.set("TZ", b"-5").unwrap();
+        settings.set("ZIP", b"12345").unwrap();
+        settings.set("TZ", b"+1").unwrap();
+        assert_eq!(get(&mut settings, "TZ").unwrap(), b"+1");
+        assert_eq!(get(&mut settings, "ZIP").unwrap(), b"12345");
+
+        let mut small = [0u8; 2];
+        assert_eq!(settings.get("ZIP", &mut small), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        assert_eq!(
+            settings.set("", b"1"),
+            Err(Error::Param(db_link::params::Error::InvalidKey))
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("A", b"1").unwrap();
+        settings.remove("A").unwrap();
+        assert_eq!(get(&mut settings, "A"), None);
+        let used = settings.used();
+        //removing again doesn't write
+        settings.remove("A").unwrap();
+        assert_eq!(settings.used(), used);
+    }
+
+    #[test]
+    fn test_persists_across_mount() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("A", b"1").unwrap();
+        settings.set("B", b"2").unwrap();
+        settings.remove("B").unwrap();
+        let mut settings = mount(settings.into_inner());
+        assert_eq!(get(&mut settings, "A").unwrap(), b"1");
+        assert_eq!(get(&mut settings, "B"), None);
+        settings.set("C", b"3").unwrap();
+        assert_eq!(get(&mut settings, "C").unwrap(), b"3");
+    }
+
+    #[test]
+    fn test_unchanged_value_not_written() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("A", b"same").unwrap();
+        let used = settings.used();
+        settings.set("A", b"same").unwrap();
+        assert_eq!(settings.used(), used);
+    }
+
+    #[test]
+    fn test_compaction_alternates_banks() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        for i in 0..2000u32 {
+            settings.set("COUNTER", &i.to_le_bytes()).unwrap();
+            settings.set("FIXED", b"x").unwrap();
+        }
+        assert_eq!(
+            get(&mut settings, "COUNTER").unwrap(),
+            1999u32.to_le_bytes()
+        );
+        assert_eq!(get(&mut settings, "FIXED").unwrap(), b"x");
+        let flash = settings.into_inner();
+        let counts = flash.erase_counts();
+        assert!(counts[0] > 1);
+        assert!(counts[0].abs_diff(counts[1]) <= 1, "uneven wear {counts:?}");
+    }
+
+    #[test]
+    fn test_full() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        let value = [7u8; MAX_VALUE_SIZE];
+        let mut result = Ok(());
+        for i in 0..100 {
+            let key = std::format!("K{i}");
+            result = settings.set(&key, &value);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(Error::Full));
+        //existing values survive
+        assert_eq!(get(&mut settings, "K0").unwrap(), value);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("A", b"1").unwrap();
+        settings.set("B", b"2").unwrap();
+        settings.set("A", b"3").unwrap();
+        settings.set("C", b"4").unwrap();
+        settings.remove("C").unwrap();
+        let mut keys = Vec::new();
+        settings
+            .for_each_key(|key| keys.push(std::string::String::from(key)))
+            .unwrap();
+        assert_eq!(keys, ["B", "A"]);
+    }
+
+    #[test]
+    fn test_power_loss_during_write() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("A", b"old").unwrap();
+        let flash = settings.into_inner();
+
+        //cut power at every byte of the next record
+        for cut in 0..24 {
+            let mut flash = MemFlash::from_image(flash.image().to_vec());
+            flash.cut_power_after(cut);
+            let mut settings = mount(flash);
+            let result = settings.set("A", b"new value");
+            let mut flash = settings.into_inner();
+            flash.restore_power();
+
+            let mut settings = mount(flash);
+            let value = get(&mut settings, "A").unwrap();
+            if result.is_ok() {
+                assert_eq!(value, b"new value");
+            } else {
+                assert!(
+                    value == b"old" || value == b"new value",
+                    "cut {cut}: {value:?}"
+                );
+            }
+            //store is still usable
+            settings.set("A", b"after").unwrap();
+            assert_eq!(get(&mut settings, "A").unwrap(), b"after");
+        }
+    }
+
+    #[test]
+    fn test_power_loss_during_compaction() {
+        let mut settings = mount(MemFlash::new(2 * SIM_ERASE_SIZE));
+        settings.set("KEEP", b"kept").unwrap();
+        let mut i = 0u32;
+        //fill until the next write will need a compaction
+        while settings.used() + 64 < settings.bank_size() {
+            settings.set("N", &i.to_le_bytes()).unwrap();
+            i += 1;
+        }
+        let last = (i - 1).to_le_bytes();
+        let flash = settings.into_inner();
+
+        for cut in (0..2 * SIM_ERASE_SIZE + 256).step_by(61) {
+            let mut flash = MemFlash::from_image(flash.image().to_vec());
+            flash.cut_power_after(cut);
+            let mut settings = mount(flash);
+            let mut result = Ok(());
+            for _ in 0..4 {
+                result = settings.set("N", &[0xAA; 40]);
+                if result.is_err() {
+                    break;
+                }
+            }
+            let mut flash = settings.into_inner();
+            flash.restore_power();
+
+            let mut settings = mount(flash);
+            assert_eq!(get(&mut settings, "KEEP").unwrap(), b"kept", "cut {cut}");
+            let value = get(&mut settings, "N").unwrap();
+            assert!(value == last || value == [0xAA; 40], "cut {cut}: {value:?}");
+            if result.is_ok() {
+                assert_eq!(value, [0xAA; 40]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bad_geometry() {
+        let flash = MemFlash::new(2 * SIM_ERASE_SIZE);
+        assert!(matches!(
+            Settings::new(flash, 0, BANK + 1),
+            Err(Error::Geometry)
+        ));
+        let flash = MemFlash::new(SIM_ERASE_SIZE);
+        assert!(matches!(
+            Settings::new(flash, 0, BANK),
+            Err(Error::Geometry)
+        ));
+    }
+}
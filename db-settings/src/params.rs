@@ -0,0 +1,108 @@
+//! Serves the param commands from the store
+
+use db_link::{
+    commands::{ErrorCode, ErrorPayload, Packet, PayloadBuf},
+    params::{self, ParamListBuilder, SetParam, MAX_VALUE_SIZE},
+};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{Error, Settings};
+
+impl<F: NorFlash> Settings<F> {
+    /// Answer GetParam, SetParam and GetParamList, returns None for any other packet
+    /// SetParam is acknowledged with an empty Response
+    pub fn handle(&mut self, packet: &Packet) -> Option<Packet> {
+        let reply = match packet {
+            Packet::GetParam(payload) => self.handle_get(payload),
+            Packet::SetParam(payload) => self.handle_set(payload),
+            Packet::GetParamList => {
+                let mut list = ParamListBuilder::new();
+                self.list_keys(&mut list).map(|_| list.finish())
+            }
+            _ => return None,
+        };
+        Some(reply.unwrap_or_else(|e| Packet::Error(error_payload(e))))
+    }
+
+    /// Add every stored key to a GetParamList response
+    /// Lets firmware put its built in params in the same list
+    pub fn list_keys(&mut self, list: &mut ParamListBuilder) -> Result<(), Error<F::Error>> {
+        self.for_each_key(|key| {
+            list.push(key);
+        })
+    }
+
+    fn handle_get(&mut self, payload: &[u8]) -> Result<Packet, Error<F::Error>> {
+        let key = params::decode_get(payload).map_err(Error::Param)?;
+        let mut value = [0u8; MAX_VALUE_SIZE];
+        match self.get(key, &mut value)? {
+            //values are capped below the payload size
+            Some(len) => Ok(Packet::Response(
+                PayloadBuf::from_slice(&value[..len]).unwrap(),
+            )),
+            None => Ok(Packet::Error(ErrorCode::UnknownParam.into())),
+        }
+    }
+
+    fn handle_set(&mut self, payload: &[u8]) -> Result<Packet, Error<F::Error>> {
+        let param = SetParam::decode(payload).map_err(Error::Param)?;
+        self.set(param.key, param.value)?;
+        Ok(Packet::Response(PayloadBuf::new()))
+    }
+}
+
+fn error_payload<E>(error: Error<E>) -> ErrorPayload {
+    match error {
+        Error::Param(_) => ErrorCode::InvalidValue.into(),
+        Error::Full => ErrorCode::OutOfMemory.into(),
+        Error::BufferTooSmall => ErrorCode::OutOfMemory.into(),
+        Error::Flash(_) => ErrorPayload::with_detail(ErrorCode::Unknown, "flash error"),
+        Error::Geometry => ErrorCode::NotSupported.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db_link::params::param_list;
+
+    use super::*;
+    use crate::sim::{MemFlash, SIM_ERASE_SIZE};
+
+    fn settings() -> Settings<MemFlash> {
+        Settings::new(MemFlash::new(2 * SIM_ERASE_SIZE), 0, SIM_ERASE_SIZE as u32).unwrap()
+    }
+
+    #[test]
+    fn test_set_then_get() {
+        let mut settings = settings();
+        let reply = settings.handle(&Packet::set_param("ZIP", b"12345").unwrap());
+        assert_eq!(reply, Some(Packet::Response(PayloadBuf::new())));
+        let reply = settings.handle(&Packet::get_param("ZIP").unwrap());
+        assert_eq!(
+            reply,
+            Some(Packet::Response(PayloadBuf::from_slice(b"12345").unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut settings = settings();
+        let reply = settings.handle(&Packet::get_param("NOPE").unwrap());
+        assert_eq!(reply, Some(Packet::Error(ErrorCode::UnknownParam.into())));
+        let reply = settings.handle(&Packet::SetParam(PayloadBuf::from_slice(&[9]).unwrap()));
+        assert_eq!(reply, Some(Packet::Error(ErrorCode::InvalidValue.into())));
+        assert_eq!(settings.handle(&Packet::Echo(PayloadBuf::new())), None);
+    }
+
+    #[test]
+    fn test_list() {
+        let mut settings = settings();
+        settings.set("A", b"1").unwrap();
+        settings.set("B", b"2").unwrap();
+        let Some(Packet::Response(payload)) = settings.handle(&Packet::GetParamList) else {
+            panic!("expected Response");
+        };
+        let keys: std::vec::Vec<_> = param_list(&payload).collect();
+        assert_eq!(keys, ["A", "B"]);
+    }
+}
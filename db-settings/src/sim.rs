@@ -0,0 +1,263 @@
+//! Flash simulators for running the store on a host
+//! Both behave like NOR flash: erase sets bytes to 0xFF and writes can only clear bits
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    vec,
+    vec::Vec,
+};
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+pub const SIM_ERASE_SIZE: usize = 4096;
+/// Matches the ESP32's word sized flash access
+pub const SIM_WRITE_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SimError {
+    NotAligned,
+    OutOfBounds,
+    /// `MemFlash::cut_power_after` ran out
+    PowerLoss,
+    Io(ErrorKind),
+}
+
+impl NorFlashError for SimError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            SimError::NotAligned => NorFlashErrorKind::NotAligned,
+            SimError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            SimError::PowerLoss | SimError::Io(_) => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for SimError {
+    fn from(value: NorFlashErrorKind) -> Self {
+        match value {
+            NorFlashErrorKind::NotAligned => SimError::NotAligned,
+            _ => SimError::OutOfBounds,
+        }
+    }
+}
+
+/// Flash backed by memory
+#[derive(Debug, Clone)]
+pub struct MemFlash {
+    data: Vec<u8>,
+    erase_counts: Vec<u32>,
+    /// bytes left to program or erase before the simulated power cut
+    power_budget: Option<usize>,
+}
+
+impl MemFlash {
+    /// Erased flash of `size` bytes, rounded up to a whole sector
+    pub fn new(size: usize) -> Self {
+        Self::from_image(vec![0xFF; size])
+    }
+
+    /// Flash holding a previously taken `image`
+    pub fn from_image(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().div_ceil(SIM_ERASE_SIZE) * SIM_ERASE_SIZE, 0xFF);
+        Self {
+            erase_counts: vec![0; data.len() / SIM_ERASE_SIZE],
+            data,
+            power_budget: None,
+        }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Times each sector has been erased
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// Simulate losing power after `bytes` more bytes are programmed or erased
+    /// the operation that runs out stops part way and every one after it fails
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_budget = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power_budget = None;
+    }
+
+    fn spend_power(&mut self) -> Result<(), SimError> {
+        match &mut self.power_budget {
+            Some(0) => Err(SimError::PowerLoss),
+            Some(budget) => {
+                *budget -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = SimError;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = SIM_WRITE_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = SIM_WRITE_SIZE;
+    const ERASE_SIZE: usize = SIM_ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        for sector in from as usize / SIM_ERASE_SIZE..to as usize / SIM_ERASE_SIZE {
+            self.erase_counts[sector] += 1;
+        }
+        for i in from as usize..to as usize {
+            self.spend_power()?;
+            self.data[i] = 0xFF;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (i, byte) in bytes.iter().enumerate() {
+            self.spend_power()?;
+            self.data[offset as usize + i] &= byte;
+        }
+        Ok(())
+    }
+}
+
+/// Flash backed by an image file, every change is written straight through
+/// Lets a simulated device keep its settings between runs
+#[derive(Debug)]
+pub struct FileFlash {
+    path: PathBuf,
+    mem: MemFlash,
+}
+
+impl FileFlash {
+    /// Opens the image at `path`, creating an erased one of `size` bytes if it doesn't exist
+    pub fn open(path: impl AsRef<Path>, size: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mem = match fs::read(&path) {
+            Ok(data) => MemFlash::from_image(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mem = MemFlash::new(size);
+                fs::write(&path, mem.image())?;
+                mem
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, mem })
+    }
+
+    fn persist(&self) -> Result<(), SimError> {
+        fs::write(&self.path, self.mem.image()).map_err(|e| SimError::Io(e.kind()))
+    }
+}
+
+impl ErrorType for FileFlash {
+    type Error = SimError;
+}
+
+impl ReadNorFlash for FileFlash {
+    const READ_SIZE: usize = MemFlash::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.mem.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.capacity()
+    }
+}
+
+impl NorFlash for FileFlash {
+    const WRITE_SIZE: usize = MemFlash::WRITE_SIZE;
+    const ERASE_SIZE: usize = MemFlash::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let result = self.mem.erase(from, to);
+        self.persist()?;
+        result
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let result = self.mem.write(offset, bytes);
+        self.persist()?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nor_semantics() {
+        let mut flash = MemFlash::new(SIM_ERASE_SIZE);
+        flash.write(0, &[0xF0, 0x0F, 0xFF, 0x00]).unwrap();
+        flash.write(0, &[0x3C, 0x3C, 0x3C, 0x3C]).unwrap();
+        assert_eq!(&flash.image()[..4], &[0x30, 0x0C, 0x3C, 0x00]);
+        flash.erase(0, SIM_ERASE_SIZE as u32).unwrap();
+        assert_eq!(&flash.image()[..4], &[0xFF; 4]);
+        assert_eq!(flash.erase_counts(), &[1]);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let mut flash = MemFlash::new(SIM_ERASE_SIZE);
+        assert_eq!(flash.write(1, &[0; 4]), Err(SimError::NotAligned));
+        assert_eq!(flash.erase(0, 100), Err(SimError::NotAligned));
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            flash.read(SIM_ERASE_SIZE as u32, &mut buf),
+            Err(SimError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_power_cut() {
+        let mut flash = MemFlash::new(SIM_ERASE_SIZE);
+        flash.cut_power_after(2);
+        assert_eq!(flash.write(0, &[0; 4]), Err(SimError::PowerLoss));
+        assert_eq!(&flash.image()[..4], &[0, 0, 0xFF, 0xFF]);
+        flash.restore_power();
+        flash.write(4, &[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn test_file_flash() {
+        let path =
+            std::env::temp_dir().join(std::format!("db-settings-test-{}.bin", std::process::id()));
+        _ = fs::remove_file(&path);
+        let mut flash = FileFlash::open(&path, SIM_ERASE_SIZE).unwrap();
+        flash.write(0, b"abcd").unwrap();
+        drop(flash);
+        let mut flash = FileFlash::open(&path, SIM_ERASE_SIZE).unwrap();
+        let mut buf = [0u8; 4];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 use rust_embed::RustEmbed;
 
 pub mod pages;
+
+/// Lives in db-link so the no_std firmware can use it, db-ui needs std and its assets
+pub use db_link::pages::PageRegistry;
+
 #[derive(RustEmbed)]
 #[folder = "images/bmp/40/"]
 struct Icons40;
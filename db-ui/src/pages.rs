@@ -1 +1,30 @@
+use embedded_graphics::mono_font::MonoFont;
+
+pub mod notifications;
+pub mod system;
 pub mod weather;
+
+/// The start of `text` that fits in `width` pixels of `font`
+fn fit<'a>(text: &'a str, font: &MonoFont, width: u32) -> &'a str {
+    let advance = font.character_size.width + font.character_spacing;
+    let columns = (width / advance.max(1)) as usize;
+    match text.char_indices().nth(columns) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use profont::PROFONT_9_POINT;
+
+    use super::*;
+
+    #[test]
+    pub fn test_fit() {
+        let advance = PROFONT_9_POINT.character_size.width + PROFONT_9_POINT.character_spacing;
+        assert_eq!(fit("hello", &PROFONT_9_POINT, advance * 3), "hel");
+        assert_eq!(fit("hello", &PROFONT_9_POINT, advance * 10), "hello");
+        assert_eq!(fit("héllo", &PROFONT_9_POINT, advance * 2), "hé");
+    }
+}
@@ -0,0 +1,69 @@
+//! Recent desktop notifications, newest at the top
+
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle},
+    text::{Baseline, Text},
+};
+use profont::{PROFONT_14_POINT, PROFONT_9_POINT};
+
+use super::fit;
+
+/// Space between one notification and the line under it
+const GAP: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub app: String,
+    pub summary: String,
+    pub body: String,
+}
+
+/// Draws `notifications` from the top, as many as fit. Each gets its summary, then the app
+/// name and the start of the body on one line
+pub fn draw<D>(display: &mut D, notifications: &[Notification]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = display.bounding_box();
+    display.fill_solid(&area, BinaryColor::On)?;
+    let width = area.size.width;
+    let bottom = area.size.height as i32;
+    let title = MonoTextStyle::new(&PROFONT_14_POINT, BinaryColor::Off);
+    let small = MonoTextStyle::new(&PROFONT_9_POINT, BinaryColor::Off);
+
+    if notifications.is_empty() {
+        Text::with_baseline("No notifications", Point::zero(), small, Baseline::Top)
+            .draw(display)?;
+        return Ok(());
+    }
+    let height =
+        (PROFONT_14_POINT.character_size.height + PROFONT_9_POINT.character_size.height) as i32;
+    let mut y = 0;
+    for notification in notifications {
+        if y + height > bottom {
+            break;
+        }
+        let summary = fit(&notification.summary, &PROFONT_14_POINT, width);
+        Text::with_baseline(summary, Point::new(0, y), title, Baseline::Top).draw(display)?;
+        y += PROFONT_14_POINT.character_size.height as i32;
+
+        //bodies can be several lines, only the first one fits here
+        let body = notification.body.lines().next().unwrap_or_default();
+        let detail = match body.is_empty() {
+            true => notification.app.clone(),
+            false => format!("{}: {body}", notification.app),
+        };
+        let detail = fit(&detail, &PROFONT_9_POINT, width);
+        Text::with_baseline(detail, Point::new(0, y), small, Baseline::Top).draw(display)?;
+        y += PROFONT_9_POINT.character_size.height as i32 + GAP;
+
+        Line::new(Point::new(0, y), Point::new(width as i32 - 1, y))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::Off, 1))
+            .draw(display)?;
+        y += 1 + GAP;
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! Host metrics, usage as bars with the other readings as text under them
+
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use profont::PROFONT_9_POINT;
+
+use super::fit;
+
+/// Space between one row and the next
+const GAP: i32 = 2;
+/// Columns left of a bar for its label, and right of it for the percentage
+const LABEL_COLUMNS: u32 = 5;
+const PERCENT_COLUMNS: u32 = 5;
+
+/// What the page shows, anything the host couldn't read is None or empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct System {
+    pub cpu_percent: Option<f32>,
+    pub memory_percent: Option<f32>,
+    /// One minute load average
+    pub load: Option<f32>,
+    /// Hottest sensor
+    pub celsius: Option<f32>,
+    pub network: Vec<Interface>,
+    pub disks: Vec<Disk>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disk {
+    pub mount: String,
+    pub used_percent: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Row<'a> {
+    Bar(&'a str, f32),
+    Text(String),
+}
+
+/// Draws cpu and memory bars, load and temperature, disk bars and then network rates, as
+/// many rows as fit
+pub fn draw<D>(display: &mut D, system: &System) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = display.bounding_box();
+    display.fill_solid(&area, BinaryColor::On)?;
+    let width = area.size.width;
+    let bottom = area.size.height as i32;
+    let small = MonoTextStyle::new(&PROFONT_9_POINT, BinaryColor::Off);
+    let height = PROFONT_9_POINT.character_size.height as i32;
+
+    let rows = rows(system);
+    if rows.is_empty() {
+        Text::with_baseline("No metrics", Point::zero(), small, Baseline::Top).draw(display)?;
+        return Ok(());
+    }
+    let mut y = 0;
+    for row in rows {
+        if y + height > bottom {
+            break;
+        }
+        match row {
+            Row::Bar(label, percent) => bar(display, label, percent, y)?,
+            Row::Text(text) => {
+                let text = fit(&text, &PROFONT_9_POINT, width);
+                Text::with_baseline(text, Point::new(0, y), small, Baseline::Top).draw(display)?;
+            }
+        }
+        y += height + GAP;
+    }
+    Ok(())
+}
+
+fn rows(system: &System) -> Vec<Row<'_>> {
+    let mut rows = Vec::new();
+    if let Some(cpu) = system.cpu_percent {
+        rows.push(Row::Bar("cpu", cpu));
+    }
+    if let Some(memory) = system.memory_percent {
+        rows.push(Row::Bar("mem", memory));
+    }
+    let load = system.load.map(|load| format!("load {load:.2}"));
+    let celsius = system.celsius.map(|celsius| format!("{celsius:.0}C"));
+    match (load, celsius) {
+        (Some(load), Some(celsius)) => rows.push(Row::Text(format!("{load}  {celsius}"))),
+        (Some(text), None) | (None, Some(text)) => rows.push(Row::Text(text)),
+        (None, None) => {}
+    }
+    for disk in &system.disks {
+        rows.push(Row::Bar(&disk.mount, disk.used_percent));
+    }
+    for interface in &system.network {
+        rows.push(Row::Text(format!(
+            "{} rx {} tx {}",
+            interface.name,
+            rate(interface.rx_bytes_per_sec),
+            rate(interface.tx_bytes_per_sec)
+        )));
+    }
+    rows
+}
+
+/// `label`, a bar filled to `percent` and the percentage, in one row at `y`
+fn bar<D>(display: &mut D, label: &str, percent: f32, y: i32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let width = display.bounding_box().size.width;
+    let small = MonoTextStyle::new(&PROFONT_9_POINT, BinaryColor::Off);
+    let advance = PROFONT_9_POINT.character_size.width + PROFONT_9_POINT.character_spacing;
+    let height = PROFONT_9_POINT.character_size.height;
+
+    let label = fit(label, &PROFONT_9_POINT, advance * (LABEL_COLUMNS - 1));
+    Text::with_baseline(label, Point::new(0, y), small, Baseline::Top).draw(display)?;
+
+    let left = advance * LABEL_COLUMNS;
+    let length = width.saturating_sub(left + advance * PERCENT_COLUMNS);
+    let outline = Rectangle::new(Point::new(left as i32, y), Size::new(length, height));
+    outline
+        .into_styled(PrimitiveStyle::with_stroke(BinaryColor::Off, 1))
+        .draw(display)?;
+    let filled = Size::new(filled(percent, length), height);
+    Rectangle::new(outline.top_left, filled)
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+        .draw(display)?;
+
+    let text = format!("{percent:.0}%");
+    let x = (left + length + advance) as i32;
+    Text::with_baseline(&text, Point::new(x, y), small, Baseline::Top).draw(display)?;
+    Ok(())
+}
+
+/// Pixels of a `length` long bar filled at `percent`
+fn filled(percent: f32, length: u32) -> u32 {
+    (percent.clamp(0.0, 100.0) * length as f32 / 100.0).round() as u32
+}
+
+/// Bytes per second in at most four digits and a unit
+fn rate(bytes_per_sec: f64) -> String {
+    match bytes_per_sec {
+        rate if rate < 1024.0 => format!("{rate:.0}B"),
+        rate if rate < 1024.0 * 1024.0 => format!("{:.1}K", rate / 1024.0),
+        rate => format!("{:.1}M", rate / (1024.0 * 1024.0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_rows() {
+        assert_eq!(rows(&System::default()), []);
+        let system = System {
+            cpu_percent: Some(12.0),
+            load: Some(0.5),
+            disks: vec![Disk {
+                mount: "/".into(),
+                used_percent: 40.0,
+            }],
+            network: vec![Interface {
+                name: "eth0".into(),
+                rx_bytes_per_sec: 2048.0,
+                tx_bytes_per_sec: 10.0,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            rows(&system),
+            [
+                Row::Bar("cpu", 12.0),
+                Row::Text("load 0.50".into()),
+                Row::Bar("/", 40.0),
+                Row::Text("eth0 rx 2.0K tx 10B".into()),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_filled() {
+        assert_eq!(filled(0.0, 100), 0);
+        assert_eq!(filled(42.4, 100), 42);
+        assert_eq!(filled(50.0, 7), 4);
+        assert_eq!(filled(150.0, 100), 100);
+        assert_eq!(filled(-3.0, 100), 0);
+    }
+
+    #[test]
+    pub fn test_rate() {
+        assert_eq!(rate(512.0), "512B");
+        assert_eq!(rate(1536.0), "1.5K");
+        assert_eq!(rate(3.0 * 1024.0 * 1024.0), "3.0M");
+    }
+}
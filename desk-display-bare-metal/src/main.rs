@@ -1,17 +1,23 @@
 #![no_std]
 #![no_main]
 
-use core::fmt::Write;
-use core::ptr::addr_of_mut;
-
-use db_link::commands::{Command, MAX_PACKET_SIZE, MAX_PAYLOAD_SIZE};
+use core::fmt::Write as _;
+use db_link::commands::{ErrorCode, ErrorPayload, MAX_PACKET_SIZE};
 use db_link::{
-    commands::{Packet, PayloadBuf, ResponsePayload},
+    commands::{Frame, Packet, PayloadBuf},
+    flow::CreditReceiver,
+    mux::Mux,
+    pages::{PageId, PageRegistry},
+    params::{self, ParamListBuilder, SetParam},
     parser::Parser,
+    region::{DirtyRegion, RefreshKind, RefreshPolicy, RegionUpdate},
 };
+use db_settings::Settings;
 use embassy_executor::Spawner;
-use embassy_sync::signal::Signal;
-use embassy_time::{Delay, Timer};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Delay, Instant, Timer};
 use embedded_graphics::geometry::Point;
 use embedded_graphics::mono_font::iso_8859_5::FONT_6X9;
 use embedded_graphics::mono_font::MonoTextStyle;
@@ -21,6 +27,7 @@ use embedded_graphics::text::Text;
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_io::Write as EIOWrite;
 use esp_backtrace as _;
+use esp_hal::dma_descriptors;
 use esp_hal::gpio::NO_PIN;
 use esp_hal::{
     clock::ClockControl,
@@ -38,96 +45,290 @@ use esp_hal::{
     usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagRx, UsbSerialJtagTx},
     Async,
 };
-use esp_hal::{dma_descriptors, spi, FlashSafeDma};
 use esp_hal_smartled::{smartLedBuffer, SmartLedsAdapter};
 use esp_println::println;
-use heapless::spsc::{Consumer, Producer, Queue};
+use esp_storage::FlashStorage;
+use fifo::{Consumer, Producer, StaticFifo, Stats};
 use log::info;
 use smart_leds::hsv::Hsv;
 use smart_leds::{brightness, gamma, hsv::hsv2rgb, SmartLedsWrite};
-use ssd1680::async_driver::Ssd1680Async;
-use ssd1680::driver::Ssd1680;
 use ssd1680::graphics::{Display, Display2in13, DisplayRotation};
+use ssd1680_refresh::{Epd, Window};
+use static_cell::ConstStaticCell;
 
 const QUEUE_SIZE: usize = 4096;
+/// fifos hold one less than their size
+const QUEUE_CAPACITY: usize = QUEUE_SIZE - 1;
+/// Hand credit back to the host once a full packet worth of bytes has been consumed
+const CREDIT_THRESHOLD: u16 = MAX_PACKET_SIZE as u16;
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Settings use the two sectors of the default nvs partition, nothing else on this firmware
+/// touches it
+const SETTINGS_BASE: u32 = 0x9000;
+const SETTINGS_BANK_SIZE: u32 = 0x1000;
+
+/// Frames per channel waiting to go back to the host
+const OUTBOX_DEPTH: usize = 4;
+/// Partial refreshes between full ones, a full refresh clears the ghosting partials leave
+const FULL_REFRESH_EVERY: u16 = 10;
+
+const MAX_PAGES: usize = 2;
+/// Whatever the host drew with region updates, shown from boot so streaming just works
+const HOST_PAGE: PageId = 0;
+/// Boot screen drawn by the firmware itself
+const HELLO_PAGE: PageId = 1;
+/// How often an idle writer checks whether the playlist moves on
+const PAGE_TICK_MS: u64 = 500;
+
+/// Read only diagnostic params for the receive queue
+const RX_STATS: [&str; 5] = [
+    "RX_PEAK",
+    "RX_WRITES",
+    "RX_READS",
+    "RX_REJECTED",
+    "RX_WRAPS",
+];
+/// Setting this param to anything clears the receive queue counters
+const RX_RESET: &str = "RX_RESET";
+
+/// Region updates and refreshes handed from the writer to the task that owns the display
+static DISPLAY: Channel<CriticalSectionRawMutex, Packet, 4> = Channel::new();
 
-/// Handle packets, return vector should be sent back
-fn handle_packet(packet: Packet) -> heapless::Vec<u8, MAX_PACKET_SIZE> {
+fn rx_stat(stats: &Stats, key: &str) -> Option<usize> {
+    match key {
+        "RX_PEAK" => Some(stats.peak),
+        "RX_WRITES" => Some(stats.written),
+        "RX_READS" => Some(stats.read),
+        "RX_REJECTED" => Some(stats.rejected),
+        "RX_WRAPS" => Some(stats.wraps),
+        _ => None,
+    }
+}
+
+/// Receive queue counters as params, returns None for other packets
+fn handle_rx_stats(packet: &Packet, fifo: &Consumer<'static, u8>) -> Option<Packet> {
     match packet {
-        Packet::Echo(_) => packet.serialize(),
-        Packet::GetParam(param) => match param.as_slice() {
-            b"VERSION" => {
-                Packet::Response(PayloadBuf::from_slice(VERSION.as_bytes()).unwrap()).serialize()
+        Packet::GetParam(param) => {
+            let key = params::decode_get(param).ok()?;
+            let value = rx_stat(&fifo.stats(), key)?;
+            let mut text = heapless::String::<20>::new();
+            //a usize is at most 20 digits
+            _ = write!(text, "{}", value);
+            Some(Packet::Response(
+                PayloadBuf::from_slice(text.as_bytes()).unwrap(),
+            ))
+        }
+        Packet::SetParam(param) => {
+            let param = SetParam::decode(param).ok()?;
+            if param.key == RX_RESET {
+                fifo.reset_stats();
+                Some(Packet::Response(PayloadBuf::new()))
+            } else if RX_STATS.contains(&param.key) {
+                Some(Packet::Error(ErrorPayload::with_detail(
+                    ErrorCode::InvalidValue,
+                    "read only",
+                )))
+            } else {
+                None
             }
-            _ => {
-                let mut buf = heapless::Vec::<u8, MAX_PAYLOAD_SIZE>::new();
-                _ = buf.write_str("unknown param");
-                Packet::Error(buf).serialize()
+        }
+        _ => None,
+    }
+}
+
+/// Handle packets, returned packet should be sent back
+fn handle_packet(packet: Packet, settings: &mut Settings<FlashStorage>) -> Packet {
+    match &packet {
+        Packet::Echo(_) => packet,
+        Packet::GetParam(param) if param.as_slice() == b"VERSION" => {
+            Packet::Response(PayloadBuf::from_slice(VERSION.as_bytes()).unwrap())
+        }
+        Packet::SetParam(param)
+            if SetParam::decode(param).is_ok_and(|param| param.key == "VERSION") =>
+        {
+            Packet::Error(ErrorPayload::with_detail(
+                ErrorCode::InvalidValue,
+                "read only",
+            ))
+        }
+        Packet::GetParamList => {
+            let mut list = ParamListBuilder::new();
+            list.push("VERSION");
+            for key in RX_STATS.iter().chain([&RX_RESET]) {
+                list.push(key);
+            }
+            if let Err(e) = settings.list_keys(&mut list) {
+                log::error!("P Listing settings failed: {:?}", e);
+            }
+            list.finish()
+        }
+        _ => settings
+            .handle(&packet)
+            .unwrap_or_else(|| Packet::Error(ErrorCode::UnknownCommand.into())),
+    }
+}
+
+/// Queue a reply, if its channel is backed up everything waiting is sent first
+fn queue_frame(
+    tx: &mut UsbSerialJtagTx<'static, Async>,
+    outbox: &mut Mux<OUTBOX_DEPTH>,
+    frame: Frame,
+) {
+    if let Err(frame) = outbox.push(frame) {
+        send_all(tx, outbox);
+        //outbox was just emptied
+        _ = outbox.push(frame);
+    }
+}
+
+/// Send queued replies, highest priority channel first
+fn send_all(tx: &mut UsbSerialJtagTx<'static, Async>, outbox: &mut Mux<OUTBOX_DEPTH>) {
+    while let Some(frame) = outbox.pop() {
+        //TODO: I asumme this should only write the len of the vec but should check
+        //this
+        tx.write_all(&frame.serialize()).unwrap();
+    }
+}
+
+fn uptime() -> core::time::Duration {
+    core::time::Duration::from_millis(Instant::now().as_millis())
+}
+
+/// Page commands are answered here and the switch drawn by main, returns None for other packets
+async fn handle_pages(packet: &Packet, pages: &mut PageRegistry<MAX_PAGES>) -> Option<Packet> {
+    let shown = pages.current();
+    let reply = pages.handle(packet, uptime())?;
+    if let Some(page) = pages.current().filter(|page| Some(*page) != shown) {
+        DISPLAY.send(Packet::ShowPage(page)).await;
+    }
+    Some(reply)
+}
+
+/// Moves the playlist on if the current page's time is up
+async fn tick_pages(pages: &mut PageRegistry<MAX_PAGES>) {
+    if let Some(page) = pages.tick(uptime()) {
+        DISPLAY.send(Packet::ShowPage(page)).await;
+    }
+}
+
+/// Display commands are acked here and drawn by main, returns None for other packets
+async fn handle_display(packet: &Packet, policy: &mut RefreshPolicy) -> Option<Packet> {
+    match packet {
+        Packet::UpdateRegion(payload) => {
+            if let Err(e) = RegionUpdate::decode(payload) {
+                log::error!("P Bad region update: {:?}", e);
+                return Some(Packet::Error(ErrorCode::InvalidValue.into()));
             }
-        },
-        _ => {
-            let mut buf = heapless::Vec::<u8, MAX_PAYLOAD_SIZE>::new();
-            _ = buf.write_str("unknown command");
-            Packet::Error(buf).serialize()
+            DISPLAY.send(packet.clone()).await;
+            Some(Packet::Response(PayloadBuf::new()))
+        }
+        Packet::Refresh(requested) => {
+            let kind = policy.next(*requested);
+            DISPLAY.send(Packet::Refresh(kind)).await;
+            Some(Packet::refresh_done(kind))
         }
+        _ => None,
     }
 }
 
+/// Boot screen, also what the host page shows until the host draws over it
+fn draw_hello(display: &mut Display2in13) {
+    display.set_rotation(DisplayRotation::Rotate90);
+    // background fill
+    display
+        .fill_solid(&display.bounding_box(), BinaryColor::On)
+        .unwrap();
+
+    Text::new(
+        "hello",
+        Point::new(10, 10),
+        MonoTextStyle::new(&FONT_6X9, BinaryColor::Off),
+    )
+    .draw(display)
+    .unwrap();
+}
+
 #[embassy_executor::task]
 async fn writer(
     mut tx: UsbSerialJtagTx<'static, Async>,
-    signal: &'static Signal<embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex, usize>,
-    mut fifo: Consumer<'static, u8, QUEUE_SIZE>,
+    mut fifo: Consumer<'static, u8>,
+    mut settings: Settings<FlashStorage>,
 ) {
     let mut parser = Parser::new();
+    let mut credits = CreditReceiver::new(QUEUE_CAPACITY as u16, CREDIT_THRESHOLD);
+    let mut outbox = Mux::<OUTBOX_DEPTH>::new();
+    let mut policy = RefreshPolicy::new(FULL_REFRESH_EVERY);
+    let mut pages = PageRegistry::<MAX_PAGES>::new();
+    //ids are handed out in order, these match HOST_PAGE and HELLO_PAGE
+    pages.register("host").unwrap();
+    pages.register("hello").unwrap();
 
     loop {
-        // info!("P Waiting");
-        let _len = signal.wait().await;
-        signal.reset();
-        // info!("P Got {len}bytes");
-        while let Some(byte) = fifo.dequeue() {
-            match parser.parse(&[byte]) {
-                Ok(packet) => {
-                    //TODO: I asumme this should only write the len of the vec but should check
-                    //this
-                    let buf = handle_packet(packet);
-                    tx.write_all(&buf).unwrap();
-                    //embedded_io_async::Write::flush(&mut tx).await.unwrap();
-                    // info!("P Wrote Packet");
-                }
-                Err(db_link::parser::Error::InvalidVersion) => {
-                    log::error!("P Received invalid version");
+        let byte = match select(fifo.read_async(), Timer::after_millis(PAGE_TICK_MS)).await {
+            Either::First(byte) => byte,
+            Either::Second(_) => {
+                tick_pages(&mut pages).await;
+                continue;
+            }
+        };
+        match parser.parse_frame(&[byte]) {
+            Ok(Frame {
+                packet: Packet::CreditRequest,
+                ..
+            }) => {
+                let free = QUEUE_CAPACITY - fifo.len();
+                tx.write_all(&credits.advertise(free).serialize()).unwrap();
+            }
+            Ok(frame) => {
+                //credit skips the outbox, the host may be blocked on it
+                if let Some(credit) = credits.received(&frame.packet) {
+                    tx.write_all(&credit.serialize()).unwrap();
                 }
-                Err(_) => {}
+                let reply = match handle_display(&frame.packet, &mut policy).await {
+                    Some(reply) => reply,
+                    None => match handle_pages(&frame.packet, &mut pages).await {
+                        Some(reply) => reply,
+                        None => handle_rx_stats(&frame.packet, &fifo)
+                            .unwrap_or_else(|| handle_packet(frame.packet, &mut settings)),
+                    },
+                };
+                //reply on the channel the request came in on
+                queue_frame(&mut tx, &mut outbox, Frame::new(frame.channel, reply));
+            }
+            Err(db_link::parser::Error::InvalidCommand) => {
+                let reply = Packet::Error(ErrorCode::UnknownCommand.into());
+                queue_frame(&mut tx, &mut outbox, reply.into());
             }
+            Err(db_link::parser::Error::InvalidVersion) => {
+                log::error!("P Received invalid version");
+            }
+            Err(_) => {}
+        }
+        if !fifo.is_empty() {
+            continue;
+        }
+        send_all(&mut tx, &mut outbox);
+        //a host that never stops sending would otherwise hold up the playlist
+        tick_pages(&mut pages).await;
+        //embedded_io_async::Write::flush(&mut tx).await.unwrap();
+        // info!("P Wrote Packet");
+        //queue is drained, don't leave the host waiting on credit below the threshold
+        if let Some(credit) = credits.flush() {
+            tx.write_all(&credit.serialize()).unwrap();
         }
     }
 }
 
 #[embassy_executor::task]
-async fn reader(
-    mut rx: UsbSerialJtagRx<'static, Async>,
-    signal: &'static Signal<embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex, usize>,
-    mut fifo: Producer<'static, u8, QUEUE_SIZE>,
-) {
+async fn reader(mut rx: UsbSerialJtagRx<'static, Async>, mut fifo: Producer<'static, u8>) {
     let mut rbuf = [0u8; 512];
     loop {
         // info!("R reading");
         let r = embedded_io_async::Read::read(&mut rx, &mut rbuf).await;
         match r {
-            Ok(len) => {
-                for b in &rbuf[..len] {
-                    while let Err(_) = fifo.enqueue(*b) {
-                        //fifo full, try to give parser time to process
-                        // info!("R fifo full");
-                        Timer::after_millis(20).await;
-                    }
-                }
-                // info!("R signaling");
-                signal.signal(len);
-            }
+            //the host stays inside the credit window so this should only wait on hosts that
+            //don't do flow control
+            Ok(len) => fifo.write_async(&rbuf[..len]).await,
             Err(e) => esp_println::println!("RX Error: {:?}", e),
         }
     }
@@ -178,41 +379,29 @@ async fn main(spawner: Spawner) {
     let spi_device = ExclusiveDevice::new(spi, cs, Delay).unwrap();
     let disp_interface = display_interface_spi::SPIInterface::new(spi_device, dc);
     let mut delay = Delay;
-    let mut ssd1680 = Ssd1680::new(disp_interface, busy, rst, &mut delay).unwrap();
-    ssd1680.clear_bw_frame().unwrap();
+    let mut epd = Epd::new(disp_interface, busy, rst, &mut delay).unwrap();
+    //host page, region updates are drawn here
     let mut display_bw = Display2in13::bw();
-    display_bw.set_rotation(DisplayRotation::Rotate90);
+    let mut hello_bw = Display2in13::bw();
     println!("drawing display");
-    // background fill
-    display_bw
-        .fill_solid(&display_bw.bounding_box(), BinaryColor::On)
-        .unwrap();
-
-    Text::new(
-        "hello",
-        Point::new(10, 10),
-        MonoTextStyle::new(&FONT_6X9, BinaryColor::Off),
-    )
-    .draw(&mut display_bw)
-    .unwrap();
+    draw_hello(&mut display_bw);
+    draw_hello(&mut hello_bw);
     println!("updating display");
-    ssd1680.update_bw_frame(display_bw.buffer()).unwrap();
-    ssd1680.display_frame(&mut delay).unwrap();
+    epd.full_refresh(display_bw.buffer(), &mut delay).unwrap();
 
     let (tx, rx) = UsbSerialJtag::new_async(peripherals.USB_DEVICE).split();
     esp_println::logger::init_logger_from_env();
 
-    let fifo: &'static mut Queue<u8, QUEUE_SIZE> = {
-        static mut Q: Queue<u8, QUEUE_SIZE> = Queue::new();
-        unsafe { &mut *addr_of_mut!(Q) }
-    };
-    static DATA_SIGNAL: Signal<embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex, usize> =
-        Signal::new();
+    static FIFO: ConstStaticCell<StaticFifo<u8, QUEUE_SIZE>> =
+        ConstStaticCell::new(StaticFifo::new());
 
-    let (producer, consumer) = fifo.split();
+    let (producer, consumer) = FIFO.take().split();
 
-    spawner.spawn(reader(rx, &DATA_SIGNAL, producer)).unwrap();
-    spawner.spawn(writer(tx, &DATA_SIGNAL, consumer)).unwrap();
+    let settings = Settings::new(FlashStorage::new(), SETTINGS_BASE, SETTINGS_BANK_SIZE)
+        .expect("mounting settings");
+
+    spawner.spawn(reader(rx, producer)).unwrap();
+    spawner.spawn(writer(tx, consumer, settings)).unwrap();
 
     let rmt = Rmt::new(peripherals.RMT, 80.MHz(), &clocks, None).unwrap();
     let rmt_buffer = smartLedBuffer!(1);
@@ -224,6 +413,8 @@ async fn main(spawner: Spawner) {
         val: 255,
     };
     let mut data;
+    let mut dirty = DirtyRegion::<8>::new();
+    let mut showing = HOST_PAGE;
     loop {
         // Iterate over the rainbow!
         for hue in 0..=255 {
@@ -236,7 +427,51 @@ async fn main(spawner: Spawner) {
             // that the output it's not too bright.
             led.write(brightness(gamma(data.iter().cloned()), 10))
                 .unwrap();
-            Timer::after_millis(20).await;
+            let command = match select(DISPLAY.receive(), Timer::after_millis(20)).await {
+                Either::First(command) => command,
+                Either::Second(_) => continue,
+            };
+            match command {
+                Packet::UpdateRegion(payload) => {
+                    //checked by the writer before it was queued
+                    let update = RegionUpdate::decode(&payload).unwrap();
+                    let pixels = update.iter().map(|(x, y, on)| {
+                        Pixel(Point::new(x as i32, y as i32), BinaryColor::from(on))
+                    });
+                    display_bw.draw_iter(pixels).unwrap();
+                    dirty.add(update.rect);
+                }
+                //the host's regions are kept for when its page is shown again
+                Packet::Refresh(_) if showing != HOST_PAGE => {}
+                Packet::Refresh(kind) => {
+                    info!("{:?} refresh of {:?}", kind, dirty.bounding_box());
+                    let window = dirty.bounding_box().and_then(|rect| {
+                        Window::rotated_90(rect.x, rect.y, rect.width, rect.height)
+                    });
+                    match (kind, window) {
+                        (RefreshKind::Partial, Some(window)) => epd
+                            .partial_refresh(display_bw.buffer(), window, &mut delay)
+                            .unwrap(),
+                        //nothing drawn since the last refresh
+                        (RefreshKind::Partial, None) => {}
+                        (RefreshKind::Full, _) => {
+                            epd.full_refresh(display_bw.buffer(), &mut delay).unwrap()
+                        }
+                    }
+                    dirty.clear();
+                }
+                Packet::ShowPage(page) => {
+                    info!("Showing page {}", page);
+                    showing = page;
+                    let frame = match page {
+                        HELLO_PAGE => hello_bw.buffer(),
+                        _ => display_bw.buffer(),
+                    };
+                    epd.full_refresh(frame, &mut delay).unwrap();
+                    dirty.clear();
+                }
+                _ => {}
+            }
         }
     }
 }
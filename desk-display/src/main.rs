@@ -1,3 +1,4 @@
+use db_link::region::{RefreshKind, RefreshPolicy};
 use db_weather_openweather::OpenWeather;
 use embassy_time::Timer;
 use esp_idf_svc::hal::delay::FreeRtos;
@@ -11,9 +12,13 @@ use esp_idf_svc::{hal::delay::Ets, log::EspLogger};
 use log::info;
 use serde::Deserialize;
 use ssd1680::prelude::*;
+use ssd1680_refresh::{Epd, Window};
 use std::thread::sleep;
 use std::time::Duration;
 
+/// Partial refreshes between full ones, a full refresh clears the ghosting partials leave
+const FULL_REFRESH_EVERY: u16 = 10;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     wifi: ConfigWifi,
@@ -73,17 +78,30 @@ pub fn main() -> anyhow::Result<()> {
 
     let spi = SpiDeviceDriver::new(spi, Some(cs), &esp_idf_svc::hal::spi::config::Config::new())?;
     let di = display_interface_spi::SPIInterface::new(spi, dc);
-    let mut ssd1680 = Ssd1680::new(di, busy, rst, &mut delay).unwrap();
-    ssd1680.clear_bw_frame().unwrap();
+    let mut epd = Epd::new(di, busy, rst, &mut delay).unwrap();
     let mut display_bw = Display2in13::bw();
     display_bw.set_rotation(DisplayRotation::Rotate90);
+    let mut policy = RefreshPolicy::new(FULL_REFRESH_EVERY);
+    //what the panel shows, None until the first refresh
+    let mut shown: Option<Vec<u8>> = None;
 
     let _ = std::thread::spawn(usb_task);
 
     loop {
         db_ui::pages::weather::draw(&mut display_bw, &weather_api)?;
-        ssd1680.update_bw_frame(display_bw.buffer()).unwrap();
-        ssd1680.display_frame(&mut FreeRtos).unwrap();
+        let frame = display_bw.buffer();
+        let (requested, changed) = match &shown {
+            Some(shown) => (RefreshKind::Partial, Window::diff(shown, frame)),
+            None => (RefreshKind::Full, Some(Window::full())),
+        };
+        if let Some(window) = changed {
+            match policy.next(requested) {
+                RefreshKind::Partial => epd.partial_refresh(frame, window, &mut FreeRtos),
+                RefreshKind::Full => epd.full_refresh(frame, &mut FreeRtos),
+            }
+            .unwrap();
+            shown = Some(frame.to_vec());
+        }
         sleep(Duration::from_secs(60 * 10)); //10min
     }
 }
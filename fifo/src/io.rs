@@ -0,0 +1,186 @@
+//! `embedded_io` support for byte fifos
+//!
+//! A fifo never ends, so reads return what is there and wait for at least one byte instead of
+//! returning 0, which only an empty `buf` gets. The wait spins, see the `Read` impls for when
+//! that's safe. Writes take as many bytes as fit and fail with `Error::OutOfSpace` only when
+//! nothing fits.
+
+use embedded_io::{ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+
+use crate::{Consumer, Error, Fifo, Producer};
+
+impl embedded_io::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::OutOfSpace => ErrorKind::OutOfMemory,
+            Error::RecordTooLarge | Error::BufferTooSmall => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl ErrorType for Fifo<'_, u8> {
+    type Error = Error;
+}
+
+impl Read for Fifo<'_, u8> {
+    /// Nothing else can write to a `Fifo` while it's borrowed for the read, so reading an empty
+    /// one never returns. Check `read_ready` first, or split it and read the `Consumer`
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.is_empty() {
+            core::hint::spin_loop();
+        }
+        Ok(self.read_to_buffer(buf))
+    }
+}
+
+impl ReadReady for Fifo<'_, u8> {
+    fn read_ready(&mut self) -> Result<bool, Error> {
+        Ok(!self.is_empty())
+    }
+}
+
+impl Write for Fifo<'_, u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.write_partial(buf) {
+            0 if !buf.is_empty() => Err(Error::OutOfSpace),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl WriteReady for Fifo<'_, u8> {
+    fn write_ready(&mut self) -> Result<bool, Error> {
+        Ok(self.remaining() > 0)
+    }
+}
+
+impl ErrorType for Consumer<'_, u8> {
+    type Error = Error;
+}
+
+impl Read for Consumer<'_, u8> {
+    /// Spins until the producer writes at least one byte. Only for a producer in an interrupt,
+    /// another thread or the other core: on a cooperative executor such as embassy's a producer
+    /// task on the same executor never gets to run and this hangs, use the async `read`
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.is_empty() {
+            core::hint::spin_loop();
+        }
+        Ok(self.read_to_buffer(buf))
+    }
+}
+
+impl embedded_io_async::Read for Consumer<'_, u8> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.read_to_buffer_async(buf).await)
+    }
+}
+
+impl ReadReady for Consumer<'_, u8> {
+    fn read_ready(&mut self) -> Result<bool, Error> {
+        Ok(!self.is_empty())
+    }
+}
+
+impl ErrorType for Producer<'_, u8> {
+    type Error = Error;
+}
+
+impl Write for Producer<'_, u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = buf.len().min(self.remaining());
+        if n == 0 && !buf.is_empty() {
+            return Err(Error::OutOfSpace);
+        }
+        //n fits, checked above
+        Producer::write(self, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl WriteReady for Producer<'_, u8> {
+    fn write_ready(&mut self) -> Result<bool, Error> {
+        Ok(!self.is_full())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        assert_eq!(Write::write(&mut fifo, b"hello world"), Ok(7));
+        assert_eq!(Write::write(&mut fifo, b"!"), Err(Error::OutOfSpace));
+        assert_eq!(fifo.write_ready(), Ok(false));
+        let mut out = [0u8; 5];
+        fifo.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"hello");
+        fifo.write_all(b"abc").unwrap();
+        let mut out = [0u8; 8];
+        //wrapped, both halves come back in one read
+        assert_eq!(Read::read(&mut fifo, &mut out), Ok(5));
+        assert_eq!(&out[..5], b" wabc");
+        assert_eq!(fifo.read_ready(), Ok(false));
+        assert_eq!(Read::read(&mut fifo, &mut []), Ok(0));
+    }
+
+    #[test]
+    fn test_split_read_write() {
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        assert_eq!(Write::write(&mut producer, b"abcd"), Ok(3));
+        assert_eq!(producer.write_ready(), Ok(false));
+        let mut out = [0u8; 4];
+        assert_eq!(Read::read(&mut consumer, &mut out), Ok(3));
+        assert_eq!(&out[..3], b"abc");
+        assert_eq!(consumer.read_ready(), Ok(false));
+        assert_eq!(Read::read(&mut consumer, &mut []), Ok(0));
+    }
+
+    #[test]
+    fn test_read_waits_for_data() {
+        extern crate std;
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                producer.write_all(b"ab").unwrap();
+            });
+            let mut out = [0u8; 4];
+            consumer.read_exact(&mut out[..2]).unwrap();
+            assert_eq!(&out[..2], b"ab");
+        });
+    }
+
+    #[test]
+    fn test_async_read() {
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        producer.write_all(b"xy").unwrap();
+        let mut out = [0u8; 4];
+        let n = pollster::block_on(embedded_io_async::Read::read(&mut consumer, &mut out));
+        assert_eq!(n, Ok(2));
+        assert_eq!(&out[..2], b"xy");
+    }
+}
@@ -1,16 +1,55 @@
 #![no_std]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(loom)]
+extern crate std;
+
+mod io;
+pub mod record;
+pub mod ring;
+mod spsc;
+mod static_fifo;
+mod stats;
+mod sync;
+
+use atomic_waker::AtomicWaker;
+use core::{marker::PhantomData, ptr::NonNull};
+
+pub use record::RecordQueue;
+pub use ring::RingBuffer;
+use spsc::Shared;
+pub use spsc::{Consumer, Producer};
+pub use static_fifo::StaticFifo;
+use stats::Counters;
+pub use stats::Stats;
+use sync::{AtomicUsize, Ordering, Slots};
 
 pub struct Fifo<'a, T> {
     head: AtomicUsize,
     tail: AtomicUsize,
-    buffer: &'a mut [T],
+    /// Storage is kept as a pointer so the producer and consumer halves can write and read
+    /// different slots through shared references
+    buffer: NonNull<T>,
+    buffer_len: usize,
+    slots: Slots,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+    counters: Counters,
+    _storage: PhantomData<&'a mut [T]>,
 }
 
+// SAFETY: the fifo holds the only borrow of its storage, same as the `&mut [T]` it's made from
+unsafe impl<T: Send> Send for Fifo<'_, T> {}
+// SAFETY: everything that writes to the storage takes `&mut self`, shared references only read
+// items and the atomics
+unsafe impl<T: Sync> Sync for Fifo<'_, T> {}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     OutOfSpace,
+    /// Record is bigger than the queue could ever hold
+    RecordTooLarge,
+    /// Out buffer can't hold the next record
+    BufferTooSmall,
 }
 
 impl<'a, T> Fifo<'a, T>
@@ -22,44 +61,126 @@ where
     /// tail, a more complex empty system could fix this but this should not need locks
     pub fn new(buffer: &'a mut [T]) -> Self {
         Self {
-            head: 0.into(),
-            tail: 0.into(),
-            buffer,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer_len: buffer.len(),
+            slots: Slots::new(buffer.len()),
+            read_waker: AtomicWaker::new(),
+            write_waker: AtomicWaker::new(),
+            counters: Counters::new(),
+            buffer: NonNull::from(buffer).cast(),
+            _storage: PhantomData,
+        }
+    }
+
+    /// Splits into a producer and consumer that can be used from different contexts, such as
+    /// an interrupt and a task
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        (Producer::new(self.shared()), Consumer::new(self.shared()))
+    }
+
+    fn shared(&self) -> Shared<'_, T> {
+        Shared {
+            head: &self.head,
+            tail: &self.tail,
+            buffer: self.buffer,
+            buffer_len: self.buffer_len,
+            slots: &self.slots,
+            read_waker: &self.read_waker,
+            write_waker: &self.write_waker,
+            counters: &self.counters,
+            _storage: PhantomData,
         }
     }
 
-    /// FIXME: do we want to return the slice of data left so we keep retrying ?
+    /// Writes all items or none of them
     pub fn write(&mut self, write_buf: &[T]) -> Result<(), Error> {
-        //make sure write wont hit head
-        if self.head.load(Ordering::SeqCst) + write_buf.len() % self.buffer.len()
-            != self.tail.load(Ordering::SeqCst)
-        {
-            //we have enough room, write each byte wrapping if needed
-            for i in 0..write_buf.len() {
-                let head = self.head.load(Ordering::SeqCst);
-                self.buffer[head] = write_buf[i];
-                if head + 1 == self.buffer.len() {
-                    self.head.store(0, Ordering::SeqCst);
-                } else {
-                    self.head.fetch_add(1, Ordering::SeqCst);
-                }
-            }
-            Ok(())
-        } else {
-            Err(Error::OutOfSpace)
+        if write_buf.len() > self.remaining() {
+            self.counters.rejected();
+            return Err(Error::OutOfSpace);
+        }
+        self.write_partial(write_buf);
+        Ok(())
+    }
+
+    /// Writes as many items as fit, returns how many were written
+    pub fn write_partial(&mut self, write_buf: &[T]) -> usize {
+        let (first, second) = self.write_slices();
+        let n = first.len().min(write_buf.len());
+        first[..n].copy_from_slice(&write_buf[..n]);
+        let rest = &write_buf[n..];
+        let m = second.len().min(rest.len());
+        second[..m].copy_from_slice(&rest[..m]);
+        self.commit(n + m);
+        n + m
+    }
+
+    /// Free space as up to two slices, fill them in order then `commit` how many items were
+    /// written. The second slice is empty unless the free space wraps around
+    pub fn write_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let head = self.head.load(Ordering::SeqCst);
+        let free = self.remaining();
+        let first = free.min(self.buffer_len - head);
+        let ptr = self.buffer.as_ptr();
+        //SAFETY: both ranges are in bounds, don't overlap each other or any unread items and
+        //&mut self keeps anyone else from touching the storage while they're alive
+        unsafe {
+            (
+                core::slice::from_raw_parts_mut(ptr.add(head), first),
+                core::slice::from_raw_parts_mut(ptr, free - first),
+            )
+        }
+    }
+
+    /// Makes `count` items written through `write_slices` readable
+    /// Panics if `count` is more than the free space
+    pub fn commit(&mut self, count: usize) {
+        assert!(count <= self.remaining(), "commit past free space");
+        let head = self.head.load(Ordering::SeqCst);
+        self.head
+            .store((head + count) % self.buffer_len, Ordering::SeqCst);
+        self.counters
+            .wrote(count, self.len(), head + count >= self.buffer_len);
+    }
+
+    /// Unread items as up to two slices in order, `consume` them once they're handled
+    pub fn readable_slices(&self) -> (&[T], &[T]) {
+        let tail = self.tail.load(Ordering::SeqCst);
+        let len = self.len();
+        let first = len.min(self.buffer_len - tail);
+        let ptr = self.buffer.as_ptr();
+        //SAFETY: both ranges are in bounds and hold written items, nothing can write until
+        //the shared borrow ends
+        unsafe {
+            (
+                core::slice::from_raw_parts(ptr.add(tail), first),
+                core::slice::from_raw_parts(ptr, len - first),
+            )
         }
     }
 
+    /// Drops `count` items from the front
+    /// Panics if `count` is more than what's in the fifo
+    pub fn consume(&mut self, count: usize) {
+        assert!(count <= self.len(), "consume past written items");
+        let tail = self.tail.load(Ordering::SeqCst);
+        self.tail
+            .store((tail + count) % self.buffer_len, Ordering::SeqCst);
+        self.counters.read(count);
+    }
+
     /// Read next item from fifo and advance tail
     pub fn read(&mut self) -> Option<T> {
         let tail = self.tail.load(Ordering::SeqCst);
         if self.head.load(Ordering::SeqCst) != tail {
-            let item = self.buffer[tail];
-            if tail + 1 == self.buffer.len() {
+            //SAFETY: tail is behind head so the slot holds an item
+            let item = unsafe { self.shared().read_slot(tail) };
+            if tail + 1 == self.buffer_len {
                 self.tail.store(0, Ordering::SeqCst);
             } else {
                 self.tail.fetch_add(1, Ordering::SeqCst);
             }
+            self.counters.read(1);
             Some(item)
         } else {
             None
@@ -70,7 +191,8 @@ where
     pub fn peek(&self) -> Option<T> {
         let tail = self.tail.load(Ordering::SeqCst);
         if self.head.load(Ordering::SeqCst) != tail {
-            Some(self.buffer[tail])
+            //SAFETY: tail is behind head so the slot holds an item
+            Some(unsafe { self.shared().read_slot(tail) })
         } else {
             None
         }
@@ -80,43 +202,67 @@ where
     /// returns how many items were read
     /// This could read nothing
     pub fn read_to_buffer(&mut self, out_buffer: &mut [T]) -> usize {
-        for i in 0..out_buffer.len() {
+        for (i, slot) in out_buffer.iter_mut().enumerate() {
             if let Some(item) = self.read() {
-                out_buffer[i] = item;
+                *slot = item;
             } else {
                 return i;
             }
         }
-        return out_buffer.len();
+        out_buffer.len()
     }
 
     /// returns how many items are currently in fifo
     pub fn len(&self) -> usize {
         let tail = self.tail.load(Ordering::SeqCst);
         let head = self.head.load(Ordering::SeqCst);
-        if head == tail {
-            0
-        } else if head > tail {
-            head - tail
-        } else {
-            let to_end = self.buffer.len() - tail;
-            let to_head = head;
-            to_end + to_head
-        }
+        self.shared().used(head, tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Returns max count of items fifo can hold
     pub fn size(&self) -> usize {
-        self.buffer.len() - 1
+        self.buffer_len - 1
     }
 
-    /// Returns how many slots remain
+    /// Returns how many more items can be written
     pub fn remaining(&self) -> usize {
-        self.buffer.len() - self.len()
+        self.size() - self.len()
+    }
+
+    /// Counters since creation or the last `reset_stats`
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.counters.reset()
     }
 }
 
-#[cfg(test)]
+impl Fifo<'_, u8> {
+    /// Offset from the front of the first `byte`, searches both halves of wrapped contents
+    pub fn find(&self, byte: u8) -> Option<usize> {
+        let (first, second) = self.readable_slices();
+        memchr::memchr(byte, first)
+            .or_else(|| memchr::memchr(byte, second).map(|i| first.len() + i))
+    }
+
+    /// Drops everything before the first `byte` so it's the next item read, empties the fifo
+    /// if there isn't one. Returns how many bytes were dropped
+    pub fn skip_until(&mut self, byte: u8) -> usize {
+        let count = self.find(byte).unwrap_or_else(|| self.len());
+        self.consume(count);
+        count
+    }
+}
+
+#[cfg(all(test, not(loom)))]
 mod tests {
     use super::*;
 
@@ -171,4 +317,174 @@ mod tests {
         assert_eq!(fifo.read_to_buffer(&mut read_buffer), 19);
         assert_eq!(read_buffer, [2; 19]);
     }
+
+    #[test]
+    fn test_split() {
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        assert_eq!(producer.write(&[1, 2, 3]), Ok(()));
+        assert!(producer.is_full());
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.peek(), Some(1));
+        assert_eq!(consumer.read(), Some(1));
+        assert_eq!(producer.push(4), Ok(()));
+        let mut out = [0u8; 4];
+        assert_eq!(consumer.read_to_buffer(&mut out), 3);
+        assert_eq!(out[..3], [2, 3, 4]);
+        assert!(consumer.is_empty());
+        assert_eq!(producer.write(&[0; 4]), Err(Error::OutOfSpace));
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Fifo<'static, u32>>();
+    }
+
+    #[test]
+    fn test_split_threads() {
+        extern crate std;
+        let mut buffer = [0u32; 16];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..10_000 {
+                    while producer.push(i).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < 10_000 {
+                if let Some(item) = consumer.read() {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+            }
+        });
+    }
+
+    /// writes used to only check that head wouldn't land exactly on tail
+    #[test]
+    fn test_write_checks_remaining() {
+        let mut buffer = [0u8; 20];
+        let mut fifo = Fifo::new(&mut buffer);
+        fifo.write(&[1; 5]).unwrap();
+        assert_eq!(fifo.remaining(), 14);
+        assert_eq!(fifo.write(&[2; 15]), Err(Error::OutOfSpace));
+        assert_eq!(fifo.len(), 5);
+        assert_eq!(fifo.remaining() + fifo.len(), fifo.size());
+    }
+
+    #[test]
+    fn test_write_partial() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        assert_eq!(fifo.write_partial(&[1, 2, 3, 4, 5]), 5);
+        assert_eq!(fifo.read(), Some(1));
+        assert_eq!(fifo.read(), Some(2));
+        //wraps around the end of the storage
+        assert_eq!(fifo.write_partial(&[6, 7, 8, 9, 10]), 4);
+        let mut out = [0u8; 7];
+        assert_eq!(fifo.read_to_buffer(&mut out), 7);
+        assert_eq!(out, [3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(fifo.write_partial(&[]), 0);
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        fifo.write(&[0; 6]).unwrap();
+        fifo.consume(6);
+        let (first, second) = fifo.write_slices();
+        assert_eq!((first.len(), second.len()), (2, 5));
+        first.copy_from_slice(&[1, 2]);
+        second[..2].copy_from_slice(&[3, 4]);
+        fifo.commit(4);
+        assert_eq!(fifo.readable_slices(), (&[1, 2][..], &[3, 4][..]));
+        fifo.consume(3);
+        assert_eq!(fifo.readable_slices(), (&[4][..], &[][..]));
+        assert_eq!(fifo.peek(), Some(4));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_commit_past_free() {
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        fifo.commit(4);
+    }
+
+    #[test]
+    fn test_find_wrapped() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        fifo.write(&[0; 5]).unwrap();
+        fifo.consume(5);
+        fifo.write(&[1, 2, 3, 4, 5, 3]).unwrap();
+        assert_eq!(fifo.find(3), Some(2));
+        //only in the half after the wrap
+        assert_eq!(fifo.find(5), Some(4));
+        assert_eq!(fifo.find(9), None);
+        assert_eq!(fifo.skip_until(4), 3);
+        assert_eq!(fifo.read(), Some(4));
+        assert_eq!(fifo.skip_until(9), 2);
+        assert!(fifo.is_empty());
+        assert_eq!(fifo.skip_until(9), 0);
+    }
+
+    #[test]
+    fn test_consumer_find_wrapped() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        producer.write(&[0; 5]).unwrap();
+        assert_eq!(consumer.skip_until(1), 5);
+        producer.write(&[1, 2, 3, 4, 5, 3]).unwrap();
+        assert_eq!(consumer.find(3), Some(2));
+        assert_eq!(consumer.find(5), Some(4));
+        assert_eq!(consumer.find(9), None);
+        assert_eq!(consumer.skip_until(4), 3);
+        assert_eq!(consumer.read(), Some(4));
+        assert_eq!(consumer.skip_until(9), 2);
+        assert!(consumer.is_empty());
+        assert_eq!(consumer.skip_until(9), 0);
+        //the freed space is usable again
+        producer.write(&[7; 7]).unwrap();
+    }
+
+    #[test]
+    fn test_async_wakes_other_side() {
+        extern crate std;
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                pollster::block_on(producer.write_async(&[1, 2, 3, 4, 5, 6, 7, 8]));
+            });
+            let mut out = [0u8; 8];
+            let mut read = 0;
+            while read < out.len() {
+                read += pollster::block_on(consumer.read_to_buffer_async(&mut out[read..]));
+            }
+            assert_eq!(out, [1, 2, 3, 4, 5, 6, 7, 8]);
+        });
+    }
+
+    #[test]
+    fn test_read_async_ready() {
+        let mut buffer = [0u8; 4];
+        let mut fifo = Fifo::new(&mut buffer);
+        let (mut producer, mut consumer) = fifo.split();
+        producer.push(9).unwrap();
+        assert_eq!(pollster::block_on(consumer.read_async()), 9);
+        assert_eq!(
+            pollster::block_on(consumer.read_to_buffer_async(&mut [])),
+            0
+        );
+    }
 }
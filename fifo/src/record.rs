@@ -0,0 +1,216 @@
+//! Queue of variable length records in one byte ring, each record is stored behind a u16 LE
+//! length so whole packets or log lines can be passed between tasks
+
+use crate::{Error, Fifo};
+
+pub const RECORD_HEADER_SIZE: usize = 2;
+
+pub struct RecordQueue<'a> {
+    fifo: Fifo<'a, u8>,
+    records: usize,
+}
+
+/// A record as stored, split in two when it wraps around the end of the ring
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Record<'r> {
+    pub first: &'r [u8],
+    pub second: &'r [u8],
+}
+
+impl Record<'_> {
+    pub fn len(&self) -> usize {
+        self.first.len() + self.second.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the record to the start of `out`, which must be at least `len` long
+    pub fn copy_to(&self, out: &mut [u8]) {
+        let (first, second) = out[..self.len()].split_at_mut(self.first.len());
+        first.copy_from_slice(self.first);
+        second.copy_from_slice(self.second);
+    }
+
+    /// Copies as much of the record as fits in `out`
+    fn copy_prefix(&self, out: &mut [u8]) {
+        for (slot, byte) in out.iter_mut().zip(self.first.iter().chain(self.second)) {
+            *slot = *byte;
+        }
+    }
+}
+
+impl<'a> RecordQueue<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            fifo: Fifo::new(buffer),
+            records: 0,
+        }
+    }
+
+    /// Largest record that could fit in an empty queue
+    pub fn max_record_size(&self) -> usize {
+        self.fifo
+            .size()
+            .saturating_sub(RECORD_HEADER_SIZE)
+            .min(u16::MAX as usize)
+    }
+
+    /// Adds a record, all of it or none
+    pub fn push(&mut self, record: &[u8]) -> Result<(), Error> {
+        if record.len() > self.max_record_size() {
+            return Err(Error::RecordTooLarge);
+        }
+        if RECORD_HEADER_SIZE + record.len() > self.fifo.remaining() {
+            return Err(Error::OutOfSpace);
+        }
+        //room was checked above so both writes land
+        self.fifo
+            .write_partial(&(record.len() as u16).to_le_bytes());
+        self.fifo.write_partial(record);
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Oldest record without removing it
+    pub fn peek(&self) -> Option<Record<'_>> {
+        if self.records == 0 {
+            return None;
+        }
+        let (first, second) = self.fifo.readable_slices();
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        Record { first, second }.copy_prefix(&mut header);
+        let len = u16::from_le_bytes(header) as usize;
+        let (first, second) = skip(first, second, RECORD_HEADER_SIZE);
+        let first = &first[..len.min(first.len())];
+        let second = &second[..len - first.len()];
+        Some(Record { first, second })
+    }
+
+    /// Copies the oldest record into `out` and removes it, returns its length or None if the
+    /// queue is empty. The record is kept if `out` is too small for it
+    pub fn pop(&mut self, out: &mut [u8]) -> Result<Option<usize>, Error> {
+        let Some(record) = self.peek() else {
+            return Ok(None);
+        };
+        let len = record.len();
+        if out.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        record.copy_to(out);
+        self.discard();
+        Ok(Some(len))
+    }
+
+    /// Drops the oldest record, returns false if there wasn't one
+    pub fn discard(&mut self) -> bool {
+        let Some(record) = self.peek() else {
+            return false;
+        };
+        self.fifo.consume(RECORD_HEADER_SIZE + record.len());
+        self.records -= 1;
+        true
+    }
+
+    /// Records in the queue
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Bytes in use, including record headers
+    pub fn bytes_used(&self) -> usize {
+        self.fifo.len()
+    }
+
+    /// Bytes that can still be pushed, including record headers
+    pub fn bytes_remaining(&self) -> usize {
+        self.fifo.remaining()
+    }
+}
+
+/// Drops `count` bytes off the front of a pair of slices
+fn skip<'r>(first: &'r [u8], second: &'r [u8], count: usize) -> (&'r [u8], &'r [u8]) {
+    if count <= first.len() {
+        (&first[count..], second)
+    } else {
+        (&second[count - first.len()..], &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let mut buffer = [0u8; 32];
+        let mut queue = RecordQueue::new(&mut buffer);
+        queue.push(b"hello").unwrap();
+        queue.push(b"").unwrap();
+        queue.push(b"world!").unwrap();
+        assert_eq!(queue.len(), 3);
+        let mut out = [0u8; 8];
+        assert_eq!(queue.pop(&mut out), Ok(Some(5)));
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(queue.pop(&mut out), Ok(Some(0)));
+        assert_eq!(queue.pop(&mut out), Ok(Some(6)));
+        assert_eq!(&out[..6], b"world!");
+        assert_eq!(queue.pop(&mut out), Ok(None));
+        assert_eq!(queue.bytes_used(), 0);
+    }
+
+    #[test]
+    fn test_full() {
+        let mut buffer = [0u8; 10];
+        let mut queue = RecordQueue::new(&mut buffer);
+        assert_eq!(queue.max_record_size(), 7);
+        assert_eq!(queue.push(&[0; 8]), Err(Error::RecordTooLarge));
+        queue.push(&[1; 4]).unwrap();
+        assert_eq!(queue.push(&[2; 2]), Err(Error::OutOfSpace));
+        assert_eq!(queue.len(), 1);
+        queue.push(&[2; 1]).unwrap();
+        assert_eq!(queue.bytes_remaining(), 0);
+    }
+
+    #[test]
+    fn test_wrapped_record() {
+        let mut buffer = [0u8; 12];
+        let mut queue = RecordQueue::new(&mut buffer);
+        queue.push(&[0; 6]).unwrap();
+        assert!(queue.discard());
+        //header fits before the end, the data wraps
+        queue.push(b"abcdefg").unwrap();
+        let record = queue.peek().unwrap();
+        assert_eq!(record.len(), 7);
+        assert!(!record.second.is_empty());
+        let mut out = [0u8; 7];
+        assert_eq!(queue.pop(&mut out), Ok(Some(7)));
+        assert_eq!(&out, b"abcdefg");
+    }
+
+    #[test]
+    fn test_wrapped_header() {
+        let mut buffer = [0u8; 12];
+        let mut queue = RecordQueue::new(&mut buffer);
+        queue.push(&[0; 9]).unwrap();
+        assert!(queue.discard());
+        //one header byte before the end, one after
+        queue.push(b"xyz").unwrap();
+        assert_eq!(queue.peek().unwrap().first, b"xyz");
+    }
+
+    #[test]
+    fn test_pop_small_buffer() {
+        let mut buffer = [0u8; 16];
+        let mut queue = RecordQueue::new(&mut buffer);
+        queue.push(b"long record").unwrap();
+        let mut out = [0u8; 4];
+        assert_eq!(queue.pop(&mut out), Err(Error::BufferTooSmall));
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+//! Ring buffer that overwrites the oldest items when full, for logs and sensor history where
+//! the newest data matters most
+
+pub struct RingBuffer<'a, T> {
+    buffer: &'a mut [T],
+    /// Index of the oldest item
+    start: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl<'a, T> RingBuffer<'a, T>
+where
+    T: Copy,
+{
+    /// Unlike `Fifo` every slot of `buffer` is usable
+    pub fn new(buffer: &'a mut [T]) -> Self {
+        Self {
+            buffer,
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Adds an item, returns the oldest item if it had to be dropped to make room
+    pub fn push(&mut self, item: T) -> Option<T> {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            self.dropped += 1;
+            return Some(item);
+        }
+        let end = (self.start + self.len) % capacity;
+        if self.len == capacity {
+            let old = core::mem::replace(&mut self.buffer[end], item);
+            self.start = (self.start + 1) % capacity;
+            self.dropped += 1;
+            Some(old)
+        } else {
+            self.buffer[end] = item;
+            self.len += 1;
+            None
+        }
+    }
+
+    /// Adds every item, dropping as many of the oldest as needed
+    pub fn write(&mut self, items: &[T]) {
+        for item in items {
+            self.push(*item);
+        }
+    }
+
+    /// Removes and returns the oldest item
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.buffer[self.start];
+        self.start = (self.start + 1) % self.capacity();
+        self.len -= 1;
+        Some(item)
+    }
+
+    /// Oldest item
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    /// Newest item
+    pub fn last(&self) -> Option<&T> {
+        self.len.checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    /// Item `index` places after the oldest
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        Some(&self.buffer[(self.start + index) % self.capacity()])
+    }
+
+    /// Contents oldest first as up to two slices
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let first = self.len.min(self.capacity() - self.start);
+        (
+            &self.buffer[self.start..self.start + first],
+            &self.buffer[..self.len - first],
+        )
+    }
+
+    /// Contents oldest first
+    pub fn iter(&self) -> Iter<'_, 'a, T> {
+        Iter {
+            ring: self,
+            front: 0,
+            back: self.len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Items overwritten since creation or the last `take_dropped`
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Returns the dropped count and resets it
+    pub fn take_dropped(&mut self) -> usize {
+        core::mem::take(&mut self.dropped)
+    }
+
+    /// Removes all items, the dropped count is kept
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+pub struct Iter<'r, 'a, T> {
+    ring: &'r RingBuffer<'a, T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'r, T: Copy> Iterator for Iter<'r, '_, T> {
+    type Item = &'r T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let item = self.ring.get(self.front);
+        self.front += 1;
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+        (len, Some(len))
+    }
+}
+
+impl<T: Copy> DoubleEndedIterator for Iter<'_, '_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        self.ring.get(self.back)
+    }
+}
+
+impl<T: Copy> ExactSizeIterator for Iter<'_, '_, T> {}
+
+impl<'r, 'a, T: Copy> IntoIterator for &'r RingBuffer<'a, T> {
+    type Item = &'r T;
+    type IntoIter = Iter<'r, 'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let mut buffer = [0u8; 3];
+        let mut ring = RingBuffer::new(&mut buffer);
+        assert_eq!(ring.push(1), None);
+        assert_eq!(ring.push(2), None);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn test_overwrite_oldest() {
+        let mut buffer = [0u8; 3];
+        let mut ring = RingBuffer::new(&mut buffer);
+        ring.write(&[1, 2, 3]);
+        assert!(ring.is_full());
+        assert_eq!(ring.push(4), Some(1));
+        ring.write(&[5, 6]);
+        assert_eq!(ring.dropped(), 3);
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.first(), Some(&4));
+        assert_eq!(ring.last(), Some(&6));
+        assert_eq!(ring.take_dropped(), 3);
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn test_iter_in_order() {
+        let mut buffer = [0u8; 4];
+        let mut ring = RingBuffer::new(&mut buffer);
+        ring.write(&[1, 2, 3, 4, 5, 6]);
+        let mut iter = ring.iter();
+        assert_eq!(iter.len(), 4);
+        assert_eq!(iter.next(), Some(&3));
+        assert_eq!(iter.next_back(), Some(&6));
+        assert_eq!(iter.next(), Some(&4));
+        assert_eq!(iter.next(), Some(&5));
+        assert_eq!(iter.next(), None);
+        assert_eq!(ring.as_slices(), (&[3, 4][..], &[5, 6][..]));
+        let mut sum = 0;
+        for item in &ring {
+            sum += item;
+        }
+        assert_eq!(sum, 18);
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        let mut buffer: [u8; 0] = [];
+        let mut ring = RingBuffer::new(&mut buffer);
+        assert_eq!(ring.push(1), Some(1));
+        assert_eq!(ring.dropped(), 1);
+        assert!(ring.iter().next().is_none());
+    }
+}
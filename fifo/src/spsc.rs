@@ -0,0 +1,334 @@
+//! Producer and consumer halves of a split `Fifo` or `StaticFifo`
+//!
+//! Only the producer moves head and only the consumer moves tail. Each side publishes its index
+//! with `Release` after touching a slot and reads the other side's index with `Acquire`, so a
+//! slot is never read before it's written or written before it's read.
+//!
+//! The async methods park the task on a waker until the other side publishes data or space,
+//! every push and read wakes the opposite side so sync and async halves can be mixed.
+
+use core::{future::poll_fn, marker::PhantomData, ptr::NonNull, task::Poll};
+
+use atomic_waker::AtomicWaker;
+
+use crate::{
+    stats::Counters,
+    sync::{AtomicUsize, Ordering, Slots},
+    Error,
+};
+
+/// Indexes and storage of a fifo, borrowed for as long as the fifo is split
+pub(crate) struct Shared<'b, T> {
+    pub head: &'b AtomicUsize,
+    pub tail: &'b AtomicUsize,
+    pub buffer: NonNull<T>,
+    pub buffer_len: usize,
+    pub slots: &'b Slots,
+    /// Woken when items are published
+    pub read_waker: &'b AtomicWaker,
+    /// Woken when space is freed
+    pub write_waker: &'b AtomicWaker,
+    pub counters: &'b Counters,
+    pub _storage: PhantomData<&'b mut [T]>,
+}
+
+impl<T> Shared<'_, T> {
+    /// Index after `index`, wrapping at the end of the storage
+    pub fn next_index(&self, index: usize) -> usize {
+        if index + 1 == self.buffer_len {
+            0
+        } else {
+            index + 1
+        }
+    }
+
+    /// Items between tail and head
+    pub fn used(&self, head: usize, tail: usize) -> usize {
+        if head >= tail {
+            head - tail
+        } else {
+            self.buffer_len - tail + head
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer_len - 1
+    }
+
+    /// # Safety
+    /// `index` must be in bounds and not readable by the consumer until head moves past it
+    pub unsafe fn write_slot(&self, index: usize, item: T) {
+        self.slots
+            .write(index, || self.buffer.as_ptr().add(index).write(item))
+    }
+
+    /// Items from `tail` up to `head` as up to two slices in order
+    /// # Safety
+    /// `head` must have been loaded with `Acquire` and the slices dropped before tail moves
+    /// past them, the producer leaves those slots alone until then
+    pub unsafe fn published(&self, head: usize, tail: usize) -> (&[T], &[T]) {
+        let len = self.used(head, tail);
+        let first = len.min(self.buffer_len - tail);
+        let ptr = self.buffer.as_ptr();
+        (
+            core::slice::from_raw_parts(ptr.add(tail), first),
+            core::slice::from_raw_parts(ptr, len - first),
+        )
+    }
+
+    /// # Safety
+    /// `index` must be in bounds and hold an item the producer won't touch until tail moves
+    /// past it
+    pub unsafe fn read_slot(&self, index: usize) -> T {
+        self.slots
+            .read(index, || self.buffer.as_ptr().add(index).read())
+    }
+}
+
+pub struct Producer<'b, T> {
+    fifo: Shared<'b, T>,
+}
+
+// SAFETY: the producer is the only writer of head and of the free slots
+unsafe impl<T: Send> Send for Producer<'_, T> {}
+
+impl<'b, T> Producer<'b, T>
+where
+    T: Copy,
+{
+    pub(crate) fn new(fifo: Shared<'b, T>) -> Self {
+        Self { fifo }
+    }
+
+    /// Adds one item, hands it back if the fifo is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        self.try_push(item)
+            .inspect_err(|_| self.fifo.counters.rejected())
+    }
+
+    /// `push` without counting a full fifo as a rejected write, for callers that wait for space
+    fn try_push(&mut self, item: T) -> Result<(), T> {
+        let head = self.fifo.head.load(Ordering::Relaxed);
+        let next = self.fifo.next_index(head);
+        if next == self.fifo.tail.load(Ordering::Acquire) {
+            return Err(item);
+        }
+        //SAFETY: the slot at head is free until we publish the new head
+        unsafe { self.fifo.write_slot(head, item) };
+        self.fifo.head.store(next, Ordering::Release);
+        self.fifo.counters.wrote(1, self.len(), next == 0);
+        self.fifo.read_waker.wake();
+        Ok(())
+    }
+
+    /// Writes all items or none of them
+    pub fn write(&mut self, items: &[T]) -> Result<(), Error> {
+        if items.len() > self.remaining() {
+            self.fifo.counters.rejected();
+            return Err(Error::OutOfSpace);
+        }
+        let start = self.fifo.head.load(Ordering::Relaxed);
+        let mut head = start;
+        for item in items {
+            //SAFETY: checked above that every slot up to the new head is free
+            unsafe { self.fifo.write_slot(head, *item) };
+            head = self.fifo.next_index(head);
+        }
+        self.fifo.head.store(head, Ordering::Release);
+        let wrapped = start + items.len() >= self.fifo.buffer_len;
+        self.fifo.counters.wrote(items.len(), self.len(), wrapped);
+        self.fifo.read_waker.wake();
+        Ok(())
+    }
+
+    /// Adds one item, waiting for space if the fifo is full
+    pub async fn push_async(&mut self, item: T) {
+        poll_fn(|cx| {
+            if self.try_push(item).is_ok() {
+                return Poll::Ready(());
+            }
+            self.fifo.write_waker.register(cx.waker());
+            //the consumer may have made room before the waker was registered
+            match self.try_push(item) {
+                Ok(()) => Poll::Ready(()),
+                Err(_) => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Writes every item, waiting for space as often as needed. Items are published as they
+    /// fit so the consumer can drain the fifo while the rest waits
+    pub async fn write_async(&mut self, items: &[T]) {
+        for item in items {
+            self.push_async(*item).await;
+        }
+    }
+
+    /// Items waiting to be read, the consumer may have read more since
+    pub fn len(&self) -> usize {
+        used(&self.fifo)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Items that can be written without failing
+    pub fn remaining(&self) -> usize {
+        self.fifo.size() - self.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Counters of the whole fifo, shared with the consumer
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::Stats {
+        self.fifo.counters.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.fifo.counters.reset()
+    }
+}
+
+pub struct Consumer<'b, T> {
+    fifo: Shared<'b, T>,
+}
+
+// SAFETY: the consumer is the only writer of tail and the only reader of filled slots
+unsafe impl<T: Send> Send for Consumer<'_, T> {}
+
+impl<'b, T> Consumer<'b, T>
+where
+    T: Copy,
+{
+    pub(crate) fn new(fifo: Shared<'b, T>) -> Self {
+        Self { fifo }
+    }
+
+    /// Read next item and advance tail
+    pub fn read(&mut self) -> Option<T> {
+        let tail = self.fifo.tail.load(Ordering::Relaxed);
+        let item = self.peek()?;
+        self.fifo
+            .tail
+            .store(self.fifo.next_index(tail), Ordering::Release);
+        self.fifo.counters.read(1);
+        self.fifo.write_waker.wake();
+        Some(item)
+    }
+
+    /// Reads the next item, waiting for one if the fifo is empty
+    pub async fn read_async(&mut self) -> T {
+        poll_fn(|cx| {
+            if let Some(item) = self.read() {
+                return Poll::Ready(item);
+            }
+            self.fifo.read_waker.register(cx.waker());
+            //the producer may have published before the waker was registered
+            match self.read() {
+                Some(item) => Poll::Ready(item),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Waits for at least one item then reads as many as are ready into `out_buffer`,
+    /// returns how many were read. Returns 0 right away for an empty `out_buffer`
+    pub async fn read_to_buffer_async(&mut self, out_buffer: &mut [T]) -> usize {
+        let Some((first, rest)) = out_buffer.split_first_mut() else {
+            return 0;
+        };
+        *first = self.read_async().await;
+        1 + self.read_to_buffer(rest)
+    }
+
+    /// Read the next item but don't advance tail
+    pub fn peek(&self) -> Option<T> {
+        let tail = self.fifo.tail.load(Ordering::Relaxed);
+        if self.fifo.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        //SAFETY: the producer published this slot and won't touch it until tail moves past it
+        Some(unsafe { self.fifo.read_slot(tail) })
+    }
+
+    /// Reads until `out_buffer` is full or the fifo is empty, returns how many items were read
+    pub fn read_to_buffer(&mut self, out_buffer: &mut [T]) -> usize {
+        for (i, slot) in out_buffer.iter_mut().enumerate() {
+            match self.read() {
+                Some(item) => *slot = item,
+                None => return i,
+            }
+        }
+        out_buffer.len()
+    }
+
+    /// Items ready to read, the producer may have written more since
+    pub fn len(&self) -> usize {
+        used(&self.fifo)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counters of the whole fifo, shared with the producer
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::Stats {
+        self.fifo.counters.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.fifo.counters.reset()
+    }
+}
+
+impl Consumer<'_, u8> {
+    /// Offset from the front of the first `byte` among the items ready to read
+    pub fn find(&self, byte: u8) -> Option<usize> {
+        self.scan(byte).ok()
+    }
+
+    /// Drops everything before the first `byte` so it's the next item read, empties the fifo
+    /// if there isn't one. Returns how many bytes were dropped
+    pub fn skip_until(&mut self, byte: u8) -> usize {
+        //on a miss only drop what was scanned, the producer may have published more since
+        let count = self.scan(byte).unwrap_or_else(|scanned| scanned);
+        if count == 0 {
+            return 0;
+        }
+        let tail = self.fifo.tail.load(Ordering::Relaxed);
+        self.fifo
+            .tail
+            .store((tail + count) % self.fifo.buffer_len, Ordering::Release);
+        self.fifo.counters.read(count);
+        self.fifo.write_waker.wake();
+        count
+    }
+
+    /// Offset of the first `byte`, or how many items were scanned without finding it
+    /// Searches both halves of wrapped contents, same as `Fifo::find`
+    fn scan(&self, byte: u8) -> Result<usize, usize> {
+        let head = self.fifo.head.load(Ordering::Acquire);
+        let tail = self.fifo.tail.load(Ordering::Relaxed);
+        //SAFETY: head was just acquired and only this consumer moves tail, after the slices
+        //are gone
+        let (first, second) = unsafe { self.fifo.published(head, tail) };
+        memchr::memchr(byte, first)
+            .or_else(|| memchr::memchr(byte, second).map(|i| first.len() + i))
+            .ok_or(first.len() + second.len())
+    }
+}
+
+fn used<T>(fifo: &Shared<'_, T>) -> usize {
+    let head = fifo.head.load(Ordering::Acquire);
+    let tail = fifo.tail.load(Ordering::Acquire);
+    fifo.used(head, tail)
+}
@@ -0,0 +1,169 @@
+//! Fifo that owns its storage, so it can live in a `static` without a borrowed buffer
+
+use atomic_waker::AtomicWaker;
+use core::{cell::UnsafeCell, marker::PhantomData, mem::MaybeUninit, ptr::NonNull};
+
+use crate::{
+    spsc::Shared,
+    stats::Counters,
+    sync::{AtomicUsize, Ordering, Slots},
+    Consumer, Producer,
+};
+
+/// Fifo holding up to `N - 1` items, only usable through `split`
+///
+/// ```
+/// use fifo::StaticFifo;
+/// use static_cell::ConstStaticCell;
+///
+/// static FIFO: ConstStaticCell<StaticFifo<u8, 64>> = ConstStaticCell::new(StaticFifo::new());
+///
+/// let (mut producer, mut consumer) = FIFO.take().split();
+/// producer.push(1).unwrap();
+/// assert_eq!(consumer.read(), Some(1));
+/// ```
+pub struct StaticFifo<T, const N: usize> {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+    slots: Slots,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+    counters: Counters,
+}
+
+// SAFETY: storage is only touched through the split halves, which need `&mut self` to create
+unsafe impl<T: Send, const N: usize> Sync for StaticFifo<T, N> {}
+
+/// Body of `new`, which is only const without loom
+macro_rules! new_static_fifo {
+    () => {{
+        const { assert!(N > 0, "StaticFifo needs at least one slot") };
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            slots: Slots::new(N),
+            read_waker: AtomicWaker::new(),
+            write_waker: AtomicWaker::new(),
+            counters: Counters::new(),
+        }
+    }};
+}
+
+impl<T, const N: usize> StaticFifo<T, N>
+where
+    T: Copy,
+{
+    /// Fails to compile for `N = 0`
+    #[cfg(not(loom))]
+    pub const fn new() -> Self {
+        new_static_fifo!()
+    }
+
+    /// loom's atomics can't be built in a const context
+    #[cfg(loom)]
+    pub fn new() -> Self {
+        new_static_fifo!()
+    }
+
+    /// Splits into a producer and consumer, with a `&'static mut` they can be handed to
+    /// different tasks
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        (Producer::new(self.shared()), Consumer::new(self.shared()))
+    }
+
+    fn shared(&self) -> Shared<'_, T> {
+        Shared {
+            head: &self.head,
+            tail: &self.tail,
+            //uninit slots are never read, the consumer stays behind the producer
+            buffer: NonNull::new(self.buffer.get().cast::<T>()).unwrap(),
+            buffer_len: N,
+            slots: &self.slots,
+            read_waker: &self.read_waker,
+            write_waker: &self.write_waker,
+            counters: &self.counters,
+            _storage: PhantomData,
+        }
+    }
+
+    /// returns how many items are currently in fifo
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        self.shared().used(head, tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns max count of items fifo can hold
+    pub const fn size(&self) -> usize {
+        N - 1
+    }
+
+    /// Counters since creation or the last `reset_stats`
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::Stats {
+        self.counters.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.counters.reset()
+    }
+}
+
+impl<T, const N: usize> Default for StaticFifo<T, N>
+where
+    T: Copy,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let mut fifo = StaticFifo::<u8, 4>::new();
+        assert_eq!(fifo.size(), 3);
+        let (mut producer, mut consumer) = fifo.split();
+        assert_eq!(producer.write(&[1, 2, 3]), Ok(()));
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.read(), Some(1));
+        assert_eq!(producer.push(4), Ok(()));
+        let mut out = [0u8; 3];
+        assert_eq!(consumer.read_to_buffer(&mut out), 3);
+        assert_eq!(out, [2, 3, 4]);
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    fn test_static() {
+        extern crate std;
+        static FIFO: static_cell::ConstStaticCell<StaticFifo<u32, 8>> =
+            static_cell::ConstStaticCell::new(StaticFifo::new());
+        let (mut producer, mut consumer) = FIFO.take().split();
+        let writer = std::thread::spawn(move || {
+            for i in 0..1000 {
+                while producer.push(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 1000 {
+            if let Some(item) = consumer.read() {
+                assert_eq!(item, expected);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Occupancy and traffic counters, only kept with the `stats` feature
+//!
+//! Counters are diagnostics so they use relaxed core atomics even under loom, a snapshot taken
+//! while both sides are busy may be a little out of date.
+
+/// Counters at the time of a snapshot, all counts are in items
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Most items the fifo held at once
+    pub peak: usize,
+    pub written: usize,
+    pub read: usize,
+    /// Writes that failed for lack of space
+    pub rejected: usize,
+    /// Times head wrapped around the end of the storage
+    pub wraps: usize,
+}
+
+#[cfg(feature = "stats")]
+mod counters {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Stats;
+
+    pub(crate) struct Counters {
+        peak: AtomicUsize,
+        written: AtomicUsize,
+        read: AtomicUsize,
+        rejected: AtomicUsize,
+        wraps: AtomicUsize,
+    }
+
+    impl Counters {
+        pub const fn new() -> Self {
+            Self {
+                peak: AtomicUsize::new(0),
+                written: AtomicUsize::new(0),
+                read: AtomicUsize::new(0),
+                rejected: AtomicUsize::new(0),
+                wraps: AtomicUsize::new(0),
+            }
+        }
+
+        /// `len` is the fifo length right after the write
+        pub fn wrote(&self, count: usize, len: usize, wrapped: bool) {
+            self.written.fetch_add(count, Ordering::Relaxed);
+            self.peak.fetch_max(len, Ordering::Relaxed);
+            if wrapped {
+                self.wraps.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        pub fn read(&self, count: usize) {
+            self.read.fetch_add(count, Ordering::Relaxed);
+        }
+
+        pub fn rejected(&self) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn snapshot(&self) -> Stats {
+            Stats {
+                peak: self.peak.load(Ordering::Relaxed),
+                written: self.written.load(Ordering::Relaxed),
+                read: self.read.load(Ordering::Relaxed),
+                rejected: self.rejected.load(Ordering::Relaxed),
+                wraps: self.wraps.load(Ordering::Relaxed),
+            }
+        }
+
+        pub fn reset(&self) {
+            for counter in [
+                &self.peak,
+                &self.written,
+                &self.read,
+                &self.rejected,
+                &self.wraps,
+            ] {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+mod counters {
+    pub(crate) struct Counters;
+
+    impl Counters {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        #[inline(always)]
+        pub fn wrote(&self, _count: usize, _len: usize, _wrapped: bool) {}
+
+        #[inline(always)]
+        pub fn read(&self, _count: usize) {}
+
+        #[inline(always)]
+        pub fn rejected(&self) {}
+    }
+}
+
+pub(crate) use counters::Counters;
+
+#[cfg(all(test, feature = "stats", not(loom)))]
+mod tests {
+    use super::*;
+    use crate::{Fifo, StaticFifo};
+
+    #[test]
+    fn test_fifo_stats() {
+        let mut buffer = [0u8; 8];
+        let mut fifo = Fifo::new(&mut buffer);
+        fifo.write(&[1; 5]).unwrap();
+        assert_eq!(fifo.write(&[2; 3]), Err(crate::Error::OutOfSpace));
+        fifo.consume(4);
+        assert_eq!(fifo.read(), Some(1));
+        //head goes past the end of the storage
+        fifo.write(&[3; 6]).unwrap();
+        assert_eq!(
+            fifo.stats(),
+            Stats {
+                peak: 6,
+                written: 11,
+                read: 5,
+                rejected: 1,
+                wraps: 1,
+            }
+        );
+        fifo.reset_stats();
+        assert_eq!(fifo.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_split_stats() {
+        let mut fifo = StaticFifo::<u8, 4>::new();
+        let (mut producer, mut consumer) = fifo.split();
+        producer.write(&[1, 2, 3]).unwrap();
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.read(), Some(1));
+        producer.push(4).unwrap();
+        assert_eq!(consumer.read_to_buffer(&mut [0; 3]), 3);
+        //waiting for space isn't a rejected write
+        pollster::block_on(producer.push_async(5));
+        let stats = consumer.stats();
+        assert_eq!(stats, producer.stats());
+        assert_eq!(
+            stats,
+            Stats {
+                peak: 3,
+                written: 5,
+                read: 4,
+                rejected: 1,
+                wraps: 1,
+            }
+        );
+        consumer.reset_stats();
+        assert_eq!(fifo.stats().written, 0);
+    }
+}
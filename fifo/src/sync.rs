@@ -0,0 +1,47 @@
+//! Atomics and slot access, swapped for loom's versions when model checking with `--cfg loom`
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks access to each slot of the storage so loom can catch the producer and consumer
+/// touching the same slot at the same time
+#[cfg(loom)]
+pub(crate) struct Slots(std::vec::Vec<loom::cell::UnsafeCell<()>>);
+
+#[cfg(loom)]
+impl Slots {
+    pub fn new(len: usize) -> Self {
+        Self((0..len).map(|_| loom::cell::UnsafeCell::new(())).collect())
+    }
+
+    pub fn write<R>(&self, index: usize, f: impl FnOnce() -> R) -> R {
+        self.0[index].with_mut(|_| f())
+    }
+
+    pub fn read<R>(&self, index: usize, f: impl FnOnce() -> R) -> R {
+        self.0[index].with(|_| f())
+    }
+}
+
+#[cfg(not(loom))]
+pub(crate) struct Slots;
+
+#[cfg(not(loom))]
+impl Slots {
+    pub const fn new(_len: usize) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub fn write<R>(&self, _index: usize, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    #[inline(always)]
+    pub fn read<R>(&self, _index: usize, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
@@ -0,0 +1,106 @@
+//! Model checks the split fifo, run with
+//! `RUSTFLAGS="--cfg loom" cargo test --release --test loom`
+#![cfg(loom)]
+
+use fifo::Fifo;
+use loom::thread;
+
+/// Storage has to outlive the handles that loom moves to other threads
+fn leak_fifo(len: usize) -> &'static mut Fifo<'static, u8> {
+    let buffer = Box::leak(vec![0u8; len].into_boxed_slice());
+    Box::leak(Box::new(Fifo::new(buffer)))
+}
+
+#[test]
+fn push_read_in_order() {
+    loom::model(|| {
+        let fifo = leak_fifo(3);
+        let (mut producer, mut consumer) = fifo.split();
+        let writer = thread::spawn(move || {
+            for i in 0..3 {
+                while producer.push(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 3 {
+            match consumer.read() {
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+        assert!(consumer.is_empty());
+    });
+}
+
+#[test]
+fn bulk_write_is_all_or_nothing() {
+    loom::model(|| {
+        let fifo = leak_fifo(4);
+        let (mut producer, mut consumer) = fifo.split();
+        let writer = thread::spawn(move || {
+            while producer.write(&[1, 2]).is_err() {
+                thread::yield_now();
+            }
+            while producer.write(&[3, 4]).is_err() {
+                thread::yield_now();
+            }
+        });
+        let mut out = Vec::new();
+        while out.len() < 4 {
+            match consumer.read() {
+                Some(item) => out.push(item),
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(out, [1, 2, 3, 4]);
+    });
+}
+
+#[test]
+fn len_never_exceeds_size() {
+    loom::model(|| {
+        let fifo = leak_fifo(2);
+        let size = fifo.size();
+        let (mut producer, consumer) = fifo.split();
+        let writer = thread::spawn(move || {
+            _ = producer.push(1);
+            _ = producer.push(2);
+        });
+        assert!(consumer.len() <= size);
+        writer.join().unwrap();
+        assert_eq!(consumer.len(), 1);
+    });
+}
+
+#[test]
+fn static_fifo_push_read() {
+    loom::model(|| {
+        let fifo = Box::leak(Box::new(fifo::StaticFifo::<u8, 2>::new()));
+        let (mut producer, mut consumer) = fifo.split();
+        let writer = thread::spawn(move || {
+            for i in 0..2 {
+                while producer.push(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 2 {
+            match consumer.read() {
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+    });
+}
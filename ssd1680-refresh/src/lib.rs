@@ -0,0 +1,453 @@
+#![no_std]
+//! Full and partial refreshes for the 2.13" SSD1680 panel
+//!
+//! A full refresh writes the frame into both of the controller's RAMs and runs the display
+//! mode 1 waveform, which flashes the panel to clear any ghosting. A partial refresh writes
+//! only a window of the frame into the new image RAM and runs display mode 2, which just
+//! drives the pixels that differ from the old image RAM. The window is copied into the old
+//! image RAM afterwards so the next partial refresh compares against what's on screen.
+
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{InputPin, OutputPin},
+};
+
+/// Panel size in its native portrait orientation
+pub const WIDTH: u16 = 122;
+pub const HEIGHT: u16 = 250;
+/// Rows are padded to whole bytes, MSB first
+pub const ROW_BYTES: usize = (WIDTH as usize).div_ceil(8);
+pub const FRAME_SIZE: usize = ROW_BYTES * HEIGHT as usize;
+
+mod cmd {
+    pub const DRIVER_OUTPUT: u8 = 0x01;
+    pub const DATA_ENTRY_MODE: u8 = 0x11;
+    pub const SW_RESET: u8 = 0x12;
+    pub const TEMP_SENSOR: u8 = 0x18;
+    pub const MASTER_ACTIVATION: u8 = 0x20;
+    pub const UPDATE_CONTROL_1: u8 = 0x21;
+    pub const UPDATE_CONTROL_2: u8 = 0x22;
+    pub const WRITE_NEW_RAM: u8 = 0x24;
+    pub const WRITE_OLD_RAM: u8 = 0x26;
+    pub const BORDER_WAVEFORM: u8 = 0x3C;
+    pub const RAM_X_RANGE: u8 = 0x44;
+    pub const RAM_Y_RANGE: u8 = 0x45;
+    pub const RAM_X_COUNTER: u8 = 0x4E;
+    pub const RAM_Y_COUNTER: u8 = 0x4F;
+}
+
+/// Load the LUT from OTP and display with mode 1
+const UPDATE_FULL: u8 = 0xF7;
+/// Same as full but display with mode 2, only pixels that changed are driven
+const UPDATE_PARTIAL: u8 = 0xFF;
+/// Border follows the LUT on a full refresh and is left alone on a partial one
+const BORDER_FULL: u8 = 0x05;
+const BORDER_PARTIAL: u8 = 0x80;
+
+#[derive(Debug)]
+pub enum Error {
+    Interface(DisplayError),
+    /// Reading busy or driving reset failed
+    Pin,
+    /// Frame isn't `FRAME_SIZE` bytes
+    FrameSize,
+}
+
+impl From<DisplayError> for Error {
+    fn from(e: DisplayError) -> Self {
+        Error::Interface(e)
+    }
+}
+
+/// Part of the controller's RAM in native coordinates, columns are whole bytes and both ends
+/// are inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub first_byte: u8,
+    pub last_byte: u8,
+    pub first_row: u16,
+    pub last_row: u16,
+}
+
+impl Window {
+    pub const fn full() -> Self {
+        Self {
+            first_byte: 0,
+            last_byte: ROW_BYTES as u8 - 1,
+            first_row: 0,
+            last_row: HEIGHT - 1,
+        }
+    }
+
+    /// Window holding a rect drawn with the display rotated 90 degrees, landscape x maps to
+    /// native rows and landscape y to native columns counted from the right edge. Clipped to
+    /// the panel, None if nothing is left
+    pub fn rotated_90(x: u16, y: u16, width: u16, height: u16) -> Option<Self> {
+        let right = x.saturating_add(width).min(HEIGHT);
+        let bottom = y.saturating_add(height).min(WIDTH);
+        if x >= right || y >= bottom {
+            return None;
+        }
+        let first_column = WIDTH - bottom;
+        let last_column = WIDTH - 1 - y;
+        Some(Self {
+            first_byte: (first_column / 8) as u8,
+            last_byte: (last_column / 8) as u8,
+            first_row: x,
+            last_row: right - 1,
+        })
+    }
+
+    /// Smallest window holding every byte that differs between two frames, None if they're
+    /// the same
+    pub fn diff(old: &[u8], new: &[u8]) -> Option<Self> {
+        let mut window: Option<Self> = None;
+        let rows = old.chunks(ROW_BYTES).zip(new.chunks(ROW_BYTES));
+        for (row, (old, new)) in rows.enumerate() {
+            let columns = old.len().min(new.len());
+            let Some(first) = (0..columns).find(|&i| old[i] != new[i]) else {
+                continue;
+            };
+            //there is at least one, `first`
+            let last = (first..columns).rev().find(|&i| old[i] != new[i]).unwrap();
+            let row = row as u16;
+            let window = window.get_or_insert(Self {
+                first_byte: first as u8,
+                last_byte: last as u8,
+                first_row: row,
+                last_row: row,
+            });
+            window.first_byte = window.first_byte.min(first as u8);
+            window.last_byte = window.last_byte.max(last as u8);
+            window.last_row = row;
+        }
+        window
+    }
+
+    /// Bytes of each row of `frame` inside the window
+    fn rows<'a>(&self, frame: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let columns = self.first_byte as usize..=self.last_byte as usize;
+        frame
+            .chunks(ROW_BYTES)
+            .skip(self.first_row as usize)
+            .take((self.last_row - self.first_row + 1) as usize)
+            .map(move |row| &row[columns.clone()])
+    }
+}
+
+/// SSD1680 over any `display-interface`, takes the place of the `ssd1680` driver
+pub struct Epd<DI, BUSY, RST> {
+    interface: DI,
+    busy: BUSY,
+    rst: RST,
+}
+
+impl<DI, BUSY, RST> Epd<DI, BUSY, RST>
+where
+    DI: WriteOnlyDataCommand,
+    BUSY: InputPin,
+    RST: OutputPin,
+{
+    /// Resets and sets up the controller, RAM isn't cleared so start with a full refresh
+    pub fn new(
+        interface: DI,
+        busy: BUSY,
+        rst: RST,
+        delay: &mut impl DelayNs,
+    ) -> Result<Self, Error> {
+        let mut epd = Self {
+            interface,
+            busy,
+            rst,
+        };
+        epd.rst.set_low().map_err(|_| Error::Pin)?;
+        delay.delay_ms(10);
+        epd.rst.set_high().map_err(|_| Error::Pin)?;
+        delay.delay_ms(10);
+        epd.wait_idle(delay)?;
+        epd.command(cmd::SW_RESET, &[])?;
+        epd.wait_idle(delay)?;
+
+        let [last_lo, last_hi] = (HEIGHT - 1).to_le_bytes();
+        epd.command(cmd::DRIVER_OUTPUT, &[last_lo, last_hi, 0x00])?;
+        //x then y increasing, matches the frame layout
+        epd.command(cmd::DATA_ENTRY_MODE, &[0x03])?;
+        epd.command(cmd::BORDER_WAVEFORM, &[BORDER_FULL])?;
+        epd.command(cmd::UPDATE_CONTROL_1, &[0x00, 0x80])?;
+        epd.command(cmd::TEMP_SENSOR, &[0x80])?;
+        epd.wait_idle(delay)?;
+        Ok(epd)
+    }
+
+    /// Shows the whole frame with the flashing waveform, clears ghosting
+    pub fn full_refresh(&mut self, frame: &[u8], delay: &mut impl DelayNs) -> Result<(), Error> {
+        check_size(frame)?;
+        self.command(cmd::BORDER_WAVEFORM, &[BORDER_FULL])?;
+        self.write_window(cmd::WRITE_NEW_RAM, frame, Window::full())?;
+        self.write_window(cmd::WRITE_OLD_RAM, frame, Window::full())?;
+        self.update(UPDATE_FULL, delay)
+    }
+
+    /// Shows `window` of the frame without flashing, the rest of the panel is left as it is
+    pub fn partial_refresh(
+        &mut self,
+        frame: &[u8],
+        window: Window,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), Error> {
+        check_size(frame)?;
+        self.command(cmd::BORDER_WAVEFORM, &[BORDER_PARTIAL])?;
+        self.write_window(cmd::WRITE_NEW_RAM, frame, window)?;
+        self.update(UPDATE_PARTIAL, delay)?;
+        //the next partial refresh diffs against this
+        self.write_window(cmd::WRITE_OLD_RAM, frame, window)
+    }
+
+    fn update(&mut self, mode: u8, delay: &mut impl DelayNs) -> Result<(), Error> {
+        self.command(cmd::UPDATE_CONTROL_2, &[mode])?;
+        self.command(cmd::MASTER_ACTIVATION, &[])?;
+        self.wait_idle(delay)
+    }
+
+    fn write_window(&mut self, ram: u8, frame: &[u8], window: Window) -> Result<(), Error> {
+        let [first_lo, first_hi] = window.first_row.to_le_bytes();
+        let [last_lo, last_hi] = window.last_row.to_le_bytes();
+        self.command(cmd::RAM_X_RANGE, &[window.first_byte, window.last_byte])?;
+        self.command(cmd::RAM_Y_RANGE, &[first_lo, first_hi, last_lo, last_hi])?;
+        self.command(cmd::RAM_X_COUNTER, &[window.first_byte])?;
+        self.command(cmd::RAM_Y_COUNTER, &[first_lo, first_hi])?;
+        self.interface.send_commands(DataFormat::U8(&[ram]))?;
+        for row in window.rows(frame) {
+            self.interface.send_data(DataFormat::U8(row))?;
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), Error> {
+        self.interface.send_commands(DataFormat::U8(&[command]))?;
+        if !data.is_empty() {
+            self.interface.send_data(DataFormat::U8(data))?;
+        }
+        Ok(())
+    }
+
+    /// Busy is high while the controller works
+    fn wait_idle(&mut self, delay: &mut impl DelayNs) -> Result<(), Error> {
+        while self.busy.is_high().map_err(|_| Error::Pin)? {
+            delay.delay_ms(1);
+        }
+        Ok(())
+    }
+}
+
+fn check_size(frame: &[u8]) -> Result<(), Error> {
+    if frame.len() == FRAME_SIZE {
+        Ok(())
+    } else {
+        Err(Error::FrameSize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::{vec, vec::Vec};
+
+    use core::convert::Infallible;
+
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Op {
+        Command(u8),
+        Data(Vec<u8>),
+    }
+
+    /// Records what would go over the wire
+    #[derive(Default)]
+    struct Recorder(Vec<Op>);
+
+    impl WriteOnlyDataCommand for Recorder {
+        fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), DisplayError> {
+            let DataFormat::U8(cmd) = cmd else {
+                return Err(DisplayError::DataFormatNotImplemented);
+            };
+            self.0.extend(cmd.iter().map(|c| Op::Command(*c)));
+            Ok(())
+        }
+
+        fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+            let DataFormat::U8(buf) = buf else {
+                return Err(DisplayError::DataFormatNotImplemented);
+            };
+            self.0.push(Op::Data(buf.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Never busy, ignores writes
+    struct Pin;
+
+    impl ErrorType for Pin {
+        type Error = Infallible;
+    }
+
+    impl InputPin for Pin {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            Ok(false)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(true)
+        }
+    }
+
+    impl OutputPin for Pin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn epd() -> Epd<Recorder, Pin, Pin> {
+        let mut epd = Epd::new(Recorder::default(), Pin, Pin, &mut NoDelay).unwrap();
+        epd.interface.0.clear();
+        epd
+    }
+
+    /// Writes of `window` into `ram` from `frame`
+    fn window_ops(ram: u8, frame: &[u8], window: Window) -> Vec<Op> {
+        let [first_lo, first_hi] = window.first_row.to_le_bytes();
+        let [last_lo, last_hi] = window.last_row.to_le_bytes();
+        let mut ops = vec![
+            Op::Command(cmd::RAM_X_RANGE),
+            Op::Data(vec![window.first_byte, window.last_byte]),
+            Op::Command(cmd::RAM_Y_RANGE),
+            Op::Data(vec![first_lo, first_hi, last_lo, last_hi]),
+            Op::Command(cmd::RAM_X_COUNTER),
+            Op::Data(vec![window.first_byte]),
+            Op::Command(cmd::RAM_Y_COUNTER),
+            Op::Data(vec![first_lo, first_hi]),
+            Op::Command(ram),
+        ];
+        ops.extend(window.rows(frame).map(|row| Op::Data(row.to_vec())));
+        ops
+    }
+
+    #[test]
+    fn test_rotated_window() {
+        //top left corner of the landscape screen is the top right of the native one
+        assert_eq!(
+            Window::rotated_90(0, 0, 8, 2),
+            Some(Window {
+                first_byte: 15,
+                last_byte: 15,
+                first_row: 0,
+                last_row: 7,
+            })
+        );
+        assert_eq!(
+            Window::rotated_90(200, 100, 100, 50),
+            Some(Window {
+                first_byte: 0,
+                last_byte: 2,
+                first_row: 200,
+                last_row: HEIGHT - 1,
+            })
+        );
+        assert_eq!(
+            Window::rotated_90(0, 0, HEIGHT, WIDTH),
+            Some(Window::full())
+        );
+        assert_eq!(Window::rotated_90(10, 10, 0, 5), None);
+        assert_eq!(Window::rotated_90(HEIGHT, 0, 5, 5), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = [0xffu8; FRAME_SIZE];
+        assert_eq!(Window::diff(&old, &old), None);
+        let mut new = old;
+        new[3 * ROW_BYTES + 4] = 0;
+        new[9 * ROW_BYTES + 2] = 0;
+        new[9 * ROW_BYTES + 5] = 0;
+        assert_eq!(
+            Window::diff(&old, &new),
+            Some(Window {
+                first_byte: 2,
+                last_byte: 5,
+                first_row: 3,
+                last_row: 9,
+            })
+        );
+    }
+
+    #[test]
+    fn test_full_refresh() {
+        let mut epd = epd();
+        let frame = [0xaa; FRAME_SIZE];
+        epd.full_refresh(&frame, &mut NoDelay).unwrap();
+        let mut expected = vec![
+            Op::Command(cmd::BORDER_WAVEFORM),
+            Op::Data(vec![BORDER_FULL]),
+        ];
+        expected.extend(window_ops(cmd::WRITE_NEW_RAM, &frame, Window::full()));
+        expected.extend(window_ops(cmd::WRITE_OLD_RAM, &frame, Window::full()));
+        expected.extend([
+            Op::Command(cmd::UPDATE_CONTROL_2),
+            Op::Data(vec![UPDATE_FULL]),
+            Op::Command(cmd::MASTER_ACTIVATION),
+        ]);
+        assert_eq!(epd.interface.0, expected);
+    }
+
+    #[test]
+    fn test_partial_refresh_writes_window() {
+        let mut epd = epd();
+        let mut frame = [0u8; FRAME_SIZE];
+        for (i, byte) in frame.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let window = Window::rotated_90(16, 0, 4, 8).unwrap();
+        epd.partial_refresh(&frame, window, &mut NoDelay).unwrap();
+
+        let mut expected = vec![
+            Op::Command(cmd::BORDER_WAVEFORM),
+            Op::Data(vec![BORDER_PARTIAL]),
+        ];
+        expected.extend(window_ops(cmd::WRITE_NEW_RAM, &frame, window));
+        expected.extend([
+            Op::Command(cmd::UPDATE_CONTROL_2),
+            Op::Data(vec![UPDATE_PARTIAL]),
+            Op::Command(cmd::MASTER_ACTIVATION),
+        ]);
+        expected.extend(window_ops(cmd::WRITE_OLD_RAM, &frame, window));
+        assert_eq!(epd.interface.0, expected);
+        //columns 114 to 121, the panel isn't a whole number of bytes wide
+        let rows: Vec<_> = window.rows(&frame).collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], &frame[16 * ROW_BYTES + 14..17 * ROW_BYTES]);
+    }
+
+    #[test]
+    fn test_frame_size_checked() {
+        let mut epd = epd();
+        assert!(matches!(
+            epd.full_refresh(&[0; 10], &mut NoDelay),
+            Err(Error::FrameSize)
+        ));
+        assert!(epd.interface.0.is_empty());
+    }
+}